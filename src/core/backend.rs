@@ -0,0 +1,243 @@
+use futures::channel::mpsc::unbounded;
+use futures::stream::BoxStream;
+use futures::{FutureExt, StreamExt};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::*;
+
+pub type BackendFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A script that the robot accepted. The feedback lines come in while it runs and the
+/// result tells how it ended, with a message for the log either way.
+pub struct ScriptExecution {
+    pub feedback: BoxStream<'static, String>,
+    pub result: BackendFuture<'static, Result<String, String>>,
+}
+
+/// How the rendered scripts reach the robot. The request state machine only talks to this,
+/// so it behaves the same no matter the transport.
+pub trait RobotBackend: Send + Sync {
+    fn send_script(&self, script: String) -> BackendFuture<'_, Result<ScriptExecution, String>>;
+
+    // Stops the script that is running, if any.
+    fn cancel(&self) -> BackendFuture<'_, Result<(), String>>;
+}
+
+/// Runs the script to the end. Feedback is handed to on_feedback in the order it was sent,
+/// and all feedback sent before the result is handled before this returns.
+pub async fn execute_script(
+    backend: &dyn RobotBackend,
+    script: String,
+    mut on_feedback: impl FnMut(String) + Send,
+) -> Result<String, String> {
+    let ScriptExecution {
+        mut feedback,
+        mut result,
+    } = backend.send_script(script).await?;
+    let mut feedback_open = true;
+    loop {
+        tokio::select! {
+            biased;
+            line = feedback.next(), if feedback_open => match line {
+                Some(line) => on_feedback(line),
+                None => feedback_open = false,
+            },
+            result = &mut result => {
+                while let Some(Some(line)) = feedback.next().now_or_never() {
+                    on_feedback(line);
+                }
+                return result;
+            }
+        }
+    }
+}
+
+/// How a script that ran to its end counts, the same on every backend. A script returns
+/// false when it notices by itself that it didn't do what it was asked, so the request fails.
+pub fn script_returned(returned: bool) -> Result<String, String> {
+    match returned {
+        true => Ok("Script returned true.".to_string()),
+        false => Err("Script returned false.".to_string()),
+    }
+}
+
+/// What a request ends in, given how the script ended and whether the robot is in a
+/// protective stop that is being taken care of.
+pub fn request_state_after(result: &Result<String, String>, protective_stop: bool) -> String {
+    match (result, protective_stop) {
+        (Ok(_), _) => ActionRequestState::Succeeded.to_string(),
+        (Err(_), true) => FAILED_PROTECTIVE_STOP.to_string(),
+        (Err(_), false) => ActionRequestState::Failed.to_string(),
+    }
+}
+
+/// What the mock robot does with the next script it gets.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockOutcome {
+    pub feedback: Vec<String>,
+    // None never completes, until cancelled
+    pub result: Option<Result<String, String>>,
+    pub delay: Duration,
+}
+
+impl MockOutcome {
+    pub fn succeed() -> MockOutcome {
+        MockOutcome {
+            feedback: vec![],
+            result: Some(Ok("Script succeeded.".to_string())),
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn fail(message: &str) -> MockOutcome {
+        MockOutcome {
+            feedback: vec![],
+            result: Some(Err(message.to_string())),
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn never_complete() -> MockOutcome {
+        MockOutcome {
+            feedback: vec![],
+            result: None,
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn with_feedback(mut self, feedback: &[&str]) -> MockOutcome {
+        self.feedback = feedback.iter().map(|line| line.to_string()).collect();
+        self
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> MockOutcome {
+        self.delay = delay;
+        self
+    }
+}
+
+/// An in-process robot that records the scripts it gets and plays back the queued outcomes,
+/// or succeeds when there are none left.
+#[derive(Debug, Clone, Default)]
+pub struct MockBackend {
+    pub scripts: Arc<Mutex<Vec<String>>>,
+    outcomes: Arc<Mutex<VecDeque<MockOutcome>>>,
+    cancelled: Arc<tokio::sync::Notify>,
+    // Refuse the scripts instead of running them
+    pub unavailable: bool,
+}
+
+impl MockBackend {
+    pub fn push_outcome(&self, outcome: MockOutcome) {
+        self.outcomes.lock().unwrap().push_back(outcome);
+    }
+
+    pub fn scripts(&self) -> Vec<String> {
+        self.scripts.lock().unwrap().clone()
+    }
+}
+
+impl RobotBackend for MockBackend {
+    fn send_script(&self, script: String) -> BackendFuture<'_, Result<ScriptExecution, String>> {
+        Box::pin(async move {
+            if self.unavailable {
+                return Err("Mock robot is unavailable.".to_string());
+            }
+            self.scripts.lock().unwrap().push(script);
+            let outcome = self
+                .outcomes
+                .lock()
+                .unwrap()
+                .pop_front()
+                .unwrap_or_else(MockOutcome::succeed);
+            let (sender, feedback) = unbounded();
+            for line in outcome.feedback {
+                let _ = sender.unbounded_send(line);
+            }
+            let cancelled = self.cancelled.clone();
+            let result: BackendFuture<'static, Result<String, String>> = Box::pin(async move {
+                // Hold on to the sender, so the feedback stays open while the script runs
+                let _sender = sender;
+                let finished = async {
+                    tokio::time::sleep(outcome.delay).await;
+                    match outcome.result {
+                        Some(result) => result,
+                        None => std::future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    result = finished => result,
+                    _ = cancelled.notified() => Err("Script cancelled.".to_string()),
+                }
+            });
+            Ok(ScriptExecution {
+                feedback: feedback.boxed(),
+                result,
+            })
+        })
+    }
+
+    fn cancel(&self) -> BackendFuture<'_, Result<(), String>> {
+        Box::pin(async move {
+            self.cancelled.notify_waiters();
+            Ok(())
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_execute_script() {
+    let backend = MockBackend::default();
+    backend.push_outcome(MockOutcome::succeed().with_feedback(&["FORCE: 1.0", "FORCE: 2.0"]));
+    backend.push_outcome(MockOutcome::fail("Goal aborted, result is false."));
+
+    let mut feedback = vec![];
+    let result = execute_script(&backend, "script_1".to_string(), |line| feedback.push(line)).await;
+    assert_eq!(result, Ok("Script succeeded.".to_string()));
+    assert_eq!(feedback, vec!["FORCE: 1.0", "FORCE: 2.0"]);
+    assert_eq!(request_state_after(&result, false), ActionRequestState::Succeeded.to_string());
+
+    let result = execute_script(&backend, "script_2".to_string(), |_| ()).await;
+    assert!(result.is_err());
+    assert_eq!(request_state_after(&result, false), ActionRequestState::Failed.to_string());
+    assert_eq!(request_state_after(&result, true), FAILED_PROTECTIVE_STOP);
+    assert!(script_returned(true).is_ok());
+    assert_eq!(
+        request_state_after(&script_returned(false), false),
+        ActionRequestState::Failed.to_string()
+    );
+
+    // Nothing queued, so it succeeds
+    let result = execute_script(&backend, "script_3".to_string(), |_| ()).await;
+    assert!(result.is_ok());
+    assert_eq!(backend.scripts(), vec!["script_1", "script_2", "script_3"]);
+
+    let unavailable = MockBackend {
+        unavailable: true,
+        ..Default::default()
+    };
+    assert!(execute_script(&unavailable, "script".to_string(), |_| ()).await.is_err());
+    assert!(unavailable.scripts().is_empty());
+}
+
+#[tokio::test]
+async fn test_cancel_script() {
+    let backend = MockBackend::default();
+    backend.push_outcome(MockOutcome::never_complete());
+    let execution = execute_script(&backend, "script".to_string(), |_| ());
+    let cancel = async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        backend.cancel().await
+    };
+    let (result, cancelled) = tokio::join!(execution, cancel);
+    assert_eq!(result, Err("Script cancelled.".to_string()));
+    assert!(cancelled.is_ok());
+
+    backend.push_outcome(MockOutcome::succeed().with_delay(Duration::from_secs(10)));
+    let execution = execute_script(&backend, "script".to_string(), |_| ());
+    assert!(tokio::time::timeout(Duration::from_millis(50), execution).await.is_err());
+}
@@ -0,0 +1,102 @@
+use k::nalgebra::Isometry3;
+use k::prelude::InverseKinematicsSolver;
+
+/// The commands that go through get_inverse_kin on the controller when they are
+/// not given joint positions.
+pub static INVERSE_KINEMATICS_COMMANDS: [&str; 2] = ["safe_move_j", "unsafe_move_j"];
+
+/// Solves for the joint positions of the arm that put the tcp at the target, with the
+/// jacobian solver of k. The solver ends up close to where it starts, so the seeds
+/// are tried in order: the preferred joint configuration first, like the qnear of
+/// get_inverse_kin, then the current joint states.
+pub fn solve_inverse_kinematics(
+    arm: &k::SerialChain<f64>,
+    base_link: &str,
+    target_in_base: &Isometry3<f64>,
+    tcp_in_faceplate: &Isometry3<f64>,
+    seeds: &[Vec<f64>],
+) -> Result<Vec<f64>, String> {
+    arm.update_transforms();
+    // The chain starts at the root of the urdf, which is not always the base
+    let base_in_root = arm
+        .find_link(base_link)
+        .and_then(|base| base.world_transform())
+        .ok_or(format!("there is no link {} in the arm", base_link))?;
+    let target = base_in_root * target_in_base * tcp_in_faceplate.inverse();
+
+    let solver = k::JacobianIkSolver::default();
+    let mut last_error = "there are no joint positions to start from".to_string();
+    for seed in seeds {
+        if let Err(e) = arm.set_joint_positions(seed) {
+            last_error = e.to_string();
+            continue;
+        }
+        match solver.solve(arm, &target) {
+            Ok(()) => return Ok(arm.joint_positions()),
+            Err(e) => last_error = e.to_string(),
+        }
+    }
+    Err(format!("the target is out of reach, {}", last_error))
+}
+
+#[test]
+fn test_solve_inverse_kinematics() {
+    let joint = |name: &str, parent: &str, child: &str, axis: &str, xyz: &str| {
+        format!(
+            r#"<joint name="{name}" type="revolute">
+                <parent link="{parent}"/>
+                <child link="{child}"/>
+                <origin xyz="{xyz}" rpy="0 0 0"/>
+                <axis xyz="{axis}"/>
+                <limit lower="-6.28" upper="6.28" effort="1.0" velocity="3.0"/>
+            </joint>"#
+        )
+    };
+    let links = ["world", "base_link", "l1", "l2", "l3", "l4", "l5", "l6", "tool0"];
+    let urdf = format!(
+        r#"<robot name="arm">
+            {}
+            <joint name="base_joint" type="fixed">
+                <parent link="world"/>
+                <child link="base_link"/>
+                <origin xyz="0 0 0.5" rpy="0 0 0"/>
+            </joint>
+            {}{}{}{}{}{}
+            <joint name="flange" type="fixed">
+                <parent link="l6"/>
+                <child link="tool0"/>
+                <origin xyz="0 0.1 0" rpy="0 0 0"/>
+            </joint>
+        </robot>"#,
+        links.iter().map(|link| format!(r#"<link name="{link}"/>"#)).collect::<String>(),
+        joint("j1", "base_link", "l1", "0 0 1", "0 0 0.1"),
+        joint("j2", "l1", "l2", "0 1 0", "0 0.1 0"),
+        joint("j3", "l2", "l3", "0 1 0", "0 0 0.4"),
+        joint("j4", "l3", "l4", "0 1 0", "0 0 0.4"),
+        joint("j5", "l4", "l5", "0 0 1", "0 0.1 0"),
+        joint("j6", "l5", "l6", "0 1 0", "0 0 0.1"),
+    );
+    let arm = crate::arm_from_urdf(&urdf, "tool0").unwrap();
+
+    // A target that the arm can reach, from forward kinematics
+    let joint_positions = vec![0.3, -0.8, 1.2, -0.4, 0.5, 0.2];
+    arm.set_joint_positions(&joint_positions).unwrap();
+    arm.update_transforms();
+    let base_in_root = arm.find_link("base_link").unwrap().world_transform().unwrap();
+    let target_in_base = base_in_root.inverse() * arm.end_transform();
+
+    let seed = joint_positions.iter().map(|q| q + 0.1).collect::<Vec<f64>>();
+    let solution =
+        solve_inverse_kinematics(&arm, "base_link", &target_in_base, &Isometry3::identity(), &[seed.clone()])
+            .unwrap();
+    arm.set_joint_positions(&solution).unwrap();
+    arm.update_transforms();
+    let reached = base_in_root.inverse() * arm.end_transform();
+    assert!((reached.translation.vector - target_in_base.translation.vector).norm() < 0.01);
+
+    let out_of_reach = Isometry3::translation(5.0, 0.0, 0.0);
+    assert!(
+        solve_inverse_kinematics(&arm, "base_link", &out_of_reach, &Isometry3::identity(), &[seed])
+            .is_err()
+    );
+}
@@ -0,0 +1,96 @@
+use crate::*;
+
+// The joints of a UR arm in the order used by the joint vectors, without the tf_prefix.
+pub static UR_JOINT_NAMES: [&str; 6] = [
+    "shoulder_pan_joint",
+    "shoulder_lift_joint",
+    "elbow_joint",
+    "wrist_1_joint",
+    "wrist_2_joint",
+    "wrist_3_joint",
+];
+
+// Both jogs are resolved to absolute joint positions first, so their scripts
+// only include the shared joint_jog.script.
+pub static JOINT_JOG_COMMANDS: [&str; 2] = ["jog_joint", "move_j_relative"];
+
+/// Rotates a single joint by delta [rad], the others stay where they are.
+pub fn jog_joint(current: &[f64], joint_index: i64, delta: f64) -> Result<Vec<f64>, String> {
+    if joint_index < 0 || joint_index as usize >= current.len() {
+        return Err(format!(
+            "joint index {} is out of range for {} joints",
+            joint_index,
+            current.len()
+        ));
+    }
+    let mut target = current.to_vec();
+    target[joint_index as usize] += delta;
+    Ok(target)
+}
+
+/// Adds a delta [rad] to every joint.
+pub fn move_joints_relative(current: &[f64], deltas: &[f64]) -> Result<Vec<f64>, String> {
+    if current.len() != deltas.len() {
+        return Err(format!(
+            "expected {} joint deltas, got {}",
+            current.len(),
+            deltas.len()
+        ));
+    }
+    Ok(current.iter().zip(deltas).map(|(q, d)| q + d).collect())
+}
+
+pub fn check_joint_limits(target: &[f64], joint_limits: &[JointLimit]) -> Result<(), String> {
+    if target.iter().any(|q| !q.is_finite()) {
+        return Err(format!("joint target {:?} is not finite", target));
+    }
+    if target.len() != joint_limits.len() {
+        return Err(format!(
+            "expected {} joint values, got {}",
+            joint_limits.len(),
+            target.len()
+        ));
+    }
+    for (q, limit) in target.iter().zip(joint_limits) {
+        if *q < limit.lower || *q > limit.upper {
+            return Err(format!(
+                "{} would move to {:.4}, outside of [{:.4}, {:.4}]",
+                limit.name, q, limit.lower, limit.upper
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_jog_joint() {
+    let current = SAFE_HOME_JOINT_STATE.to_vec();
+    let target = jog_joint(&current, 5, std::f64::consts::FRAC_PI_2).unwrap();
+    assert_eq!(target[5], std::f64::consts::FRAC_PI_2);
+    assert_eq!(target[..5], current[..5]);
+    assert!(jog_joint(&current, 6, 0.1).is_err());
+    assert!(jog_joint(&current, -1, 0.1).is_err());
+
+    let target = move_joints_relative(&current, &[0.1, 0.0, 0.0, 0.0, 0.0, -0.1]).unwrap();
+    assert_eq!(target[0], 0.1);
+    assert_eq!(target[5], -0.1);
+    assert!(move_joints_relative(&current, &[0.1]).is_err());
+}
+
+#[test]
+fn test_check_joint_limits() {
+    let joint_limits = UR_JOINT_NAMES
+        .iter()
+        .map(|name| JointLimit {
+            name: name.to_string(),
+            lower: -6.0,
+            upper: 6.0,
+            velocity: 2.09,
+        })
+        .collect::<Vec<JointLimit>>();
+    let current = SAFE_HOME_JOINT_STATE.to_vec();
+    assert!(check_joint_limits(&current, &joint_limits).is_ok());
+    let target = jog_joint(&current, 5, 7.0).unwrap();
+    assert!(check_joint_limits(&target, &joint_limits).is_err());
+    assert!(check_joint_limits(&current[..5], &joint_limits).is_err());
+}
@@ -0,0 +1,109 @@
+/// The latest joint states in a fixed joint order. Drivers publish the joints in any order
+/// and may split them over several messages, e.g. one for the arm and one for a gripper.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JointStates {
+    pub names: Vec<String>,
+    pub positions: Vec<Option<f64>>,
+    pub velocities: Vec<Option<f64>>,
+    pub efforts: Vec<Option<f64>>,
+}
+
+impl JointStates {
+    pub fn new(names: Vec<String>) -> JointStates {
+        JointStates {
+            positions: vec![None; names.len()],
+            velocities: vec![None; names.len()],
+            efforts: vec![None; names.len()],
+            names,
+        }
+    }
+
+    /// Takes in a JointState message. Joints that are not ours are left out, and so are
+    /// velocities and efforts when the driver doesn't fill them in. Returns how many of
+    /// our joints were in the message.
+    pub fn update(
+        &mut self,
+        names: &[String],
+        positions: &[f64],
+        velocities: &[f64],
+        efforts: &[f64],
+    ) -> usize {
+        let mut updated = 0;
+        for (i, name) in names.iter().enumerate() {
+            let index = match self.names.iter().position(|n| n == name) {
+                Some(index) => index,
+                None => continue,
+            };
+            updated += 1;
+            if let Some(position) = positions.get(i) {
+                self.positions[index] = Some(*position);
+            }
+            if let Some(velocity) = velocities.get(i) {
+                self.velocities[index] = Some(*velocity);
+            }
+            if let Some(effort) = efforts.get(i) {
+                self.efforts[index] = Some(*effort);
+            }
+        }
+        updated
+    }
+
+    // All or nothing, a partial array can't be indexed by joint.
+    pub fn complete(values: &[Option<f64>]) -> Option<Vec<f64>> {
+        values.iter().copied().collect()
+    }
+}
+
+/// The arm joints in the order of UR_JOINT_NAMES, looked up by name in the mirrored
+/// joint names, so nothing depends on where the arm is among the joint states.
+pub fn arm_joint_positions(
+    joint_names: &[String],
+    joint_states: &[f64],
+    tf_prefix: &str,
+) -> Option<Vec<f64>> {
+    if joint_names.len() != joint_states.len() {
+        return None;
+    }
+    crate::UR_JOINT_NAMES
+        .iter()
+        .map(|name| {
+            let name = format!("{}{}", tf_prefix, name);
+            joint_names.iter().position(|n| *n == name).map(|i| joint_states[i])
+        })
+        .collect()
+}
+
+#[test]
+fn test_joint_states() {
+    let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<String>>();
+    let mut joint_states = JointStates::new(names(&["shoulder_pan_joint", "elbow_joint", "finger_joint"]));
+
+    // The ur driver sorts the joints by name
+    let updated = joint_states.update(
+        &names(&["elbow_joint", "shoulder_pan_joint", "wrist_1_joint"]),
+        &[1.0, 2.0, 3.0],
+        &[0.1, 0.2, 0.3],
+        &[],
+    );
+    assert_eq!(updated, 2);
+    assert_eq!(joint_states.positions, vec![Some(2.0), Some(1.0), None]);
+    assert_eq!(JointStates::complete(&joint_states.positions), None);
+
+    joint_states.update(&names(&["finger_joint"]), &[0.5], &[0.0], &[]);
+    assert_eq!(JointStates::complete(&joint_states.positions), Some(vec![2.0, 1.0, 0.5]));
+    assert_eq!(JointStates::complete(&joint_states.velocities), Some(vec![0.2, 0.1, 0.0]));
+    assert_eq!(JointStates::complete(&joint_states.efforts), None);
+}
+
+#[test]
+fn test_arm_joint_positions() {
+    let mut joint_names = vec!["r1_finger_joint".to_string()];
+    joint_names.extend(crate::UR_JOINT_NAMES.iter().rev().map(|name| format!("r1_{}", name)));
+    let joint_states = [0.5, 6.0, 5.0, 4.0, 3.0, 2.0, 1.0];
+    assert_eq!(
+        arm_joint_positions(&joint_names, &joint_states, "r1_"),
+        Some(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0])
+    );
+    assert_eq!(arm_joint_positions(&joint_names, &joint_states, "r2_"), None);
+    assert_eq!(arm_joint_positions(&joint_names, &joint_states[1..], "r1_"), None);
+}
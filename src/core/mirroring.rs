@@ -0,0 +1,109 @@
+use k::nalgebra::Isometry3;
+use micro_sp::*;
+use std::time::Duration;
+
+pub const DEFAULT_JOINT_STATE_RATE: f64 = 50.0;
+pub const DEFAULT_TF_RATE: f64 = 50.0;
+pub const DEFAULT_JOINT_DEADBAND: f64 = 0.0001;
+pub const DEFAULT_TRANSLATION_DEADBAND: f64 = 0.0001;
+pub const DEFAULT_ROTATION_DEADBAND: f64 = 0.0001;
+
+/// How often the joint states and transforms are written to Redis, and how much
+/// they have to change to be written at all. The drivers publish at up to 500 Hz.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MirrorConfig {
+    // In [Hz]
+    pub joint_state_rate: f64,
+    pub tf_rate: f64,
+    // In [rad] for the positions and [rad/s] for the velocities
+    pub joint_deadband: f64,
+    // In [m]
+    pub translation_deadband: f64,
+    // In [rad]
+    pub rotation_deadband: f64,
+}
+
+impl Default for MirrorConfig {
+    fn default() -> Self {
+        MirrorConfig {
+            joint_state_rate: DEFAULT_JOINT_STATE_RATE,
+            tf_rate: DEFAULT_TF_RATE,
+            joint_deadband: DEFAULT_JOINT_DEADBAND,
+            translation_deadband: DEFAULT_TRANSLATION_DEADBAND,
+            rotation_deadband: DEFAULT_ROTATION_DEADBAND,
+        }
+    }
+}
+
+// A rate of zero or below writes as fast as a ms allows.
+pub fn mirror_period(rate: f64) -> Duration {
+    match rate > 0.0 {
+        true => Duration::from_secs_f64(1.0 / rate).max(Duration::from_millis(1)),
+        false => Duration::from_millis(1),
+    }
+}
+
+// Nothing written yet, or a different number of values, is always a change.
+pub fn outside_deadband(previous: Option<&[f64]>, current: &[f64], deadband: f64) -> bool {
+    match previous {
+        Some(previous) if previous.len() == current.len() => previous
+            .iter()
+            .zip(current)
+            .any(|(previous, current)| (previous - current).abs() > deadband),
+        _ => true,
+    }
+}
+
+pub fn transform_outside_deadband(
+    previous: Option<&Isometry3<f64>>,
+    current: &Isometry3<f64>,
+    translation_deadband: f64,
+    rotation_deadband: f64,
+) -> bool {
+    match previous {
+        Some(previous) => {
+            (previous.translation.vector - current.translation.vector).norm() > translation_deadband
+                || previous.rotation.angle_to(&current.rotation) > rotation_deadband
+        }
+        None => true,
+    }
+}
+
+/// The values as one State, so that StateManager::set_state writes them in a single
+/// round trip instead of one set_sp_value per key.
+pub fn state_from_values(values: Vec<(String, SPValue)>) -> State {
+    values.into_iter().fold(State::new(), |state, (key, value)| {
+        let variable = match &value {
+            SPValue::Bool(_) => bv!(&&key),
+            SPValue::Int64(_) => iv!(&&key),
+            SPValue::Float64(_) => fv!(&&key),
+            SPValue::Array(_) => av!(&&key),
+            _ => v!(&&key),
+        };
+        state.add(assign!(variable, value))
+    })
+}
+
+#[test]
+fn test_deadband() {
+    use k::nalgebra::{Translation3, UnitQuaternion};
+
+    assert!(outside_deadband(None, &[0.0], 0.1));
+    assert!(!outside_deadband(Some(&[0.0, 1.0]), &[0.05, 0.95], 0.1));
+    assert!(outside_deadband(Some(&[0.0, 1.0]), &[0.0, 1.2], 0.1));
+    assert!(outside_deadband(Some(&[0.0]), &[0.0, 1.0], 0.1));
+
+    let previous = Isometry3::identity();
+    let moved = Isometry3::from_parts(Translation3::new(0.0, 0.0, 0.01), UnitQuaternion::identity());
+    let turned = Isometry3::from_parts(
+        Translation3::identity(),
+        UnitQuaternion::from_euler_angles(0.0, 0.0, 0.01),
+    );
+    assert!(transform_outside_deadband(None, &previous, 0.1, 0.1));
+    assert!(!transform_outside_deadband(Some(&previous), &moved, 0.1, 0.1));
+    assert!(transform_outside_deadband(Some(&previous), &moved, 0.001, 0.1));
+    assert!(transform_outside_deadband(Some(&previous), &turned, 0.1, 0.001));
+
+    assert_eq!(mirror_period(50.0), Duration::from_millis(20));
+    assert_eq!(mirror_period(0.0), Duration::from_millis(1));
+}
@@ -1,2 +1,13 @@
 pub mod structs;
-pub mod state;
\ No newline at end of file
+pub mod state;
+pub mod payload_identification;
+pub mod speed_scaling;
+pub mod relative_motion;
+pub mod joint_jog;
+pub mod backend;
+pub mod state_age;
+pub mod joint_states;
+pub mod mirroring;
+pub mod motion;
+pub mod inverse_kinematics;
+pub mod ur_kinematics;
\ No newline at end of file
@@ -0,0 +1,158 @@
+use k::nalgebra::DVector;
+use micro_sp::*;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+pub const DEFAULT_SETTLED_VELOCITY: f64 = 0.01;
+pub const DEFAULT_SETTLE_TIME: u64 = 250;
+pub const DEFAULT_SETTLE_TIMEOUT: u64 = 5000;
+pub const SETTLE_TICKER_RATE: u64 = 20;
+
+/// When the arm counts as standing still, and whether the action client waits for
+/// that before it reports a motion as succeeded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SettleParameters {
+    // Every joint below this, in [rad/s]
+    pub settled_velocity: f64,
+    // For at least this long, in ms
+    pub settle_time: u64,
+    pub wait_for_settled: bool,
+    // How long the action client waits, in ms
+    pub settle_timeout: u64,
+}
+
+impl Default for SettleParameters {
+    fn default() -> Self {
+        SettleParameters {
+            settled_velocity: DEFAULT_SETTLED_VELOCITY,
+            settle_time: DEFAULT_SETTLE_TIME,
+            wait_for_settled: false,
+            settle_timeout: DEFAULT_SETTLE_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MotionStatus {
+    pub is_moving: bool,
+    pub is_settled: bool,
+}
+
+/// Tells from the joint stream whether the arm is moving, and whether it has been
+/// still long enough to be settled. Drivers that leave out the velocities get them
+/// from the difference between two messages.
+#[derive(Debug, Clone)]
+pub struct MotionDetector {
+    settled_velocity: f64,
+    settle_time: Duration,
+    previous: Option<(Vec<f64>, SystemTime)>,
+    still_since: Option<SystemTime>,
+}
+
+impl MotionDetector {
+    pub fn new(settle: &SettleParameters) -> MotionDetector {
+        MotionDetector {
+            settled_velocity: settle.settled_velocity,
+            settle_time: Duration::from_millis(settle.settle_time),
+            previous: None,
+            still_since: None,
+        }
+    }
+
+    pub fn update(
+        &mut self,
+        positions: &[f64],
+        velocities: Option<&[f64]>,
+        time: SystemTime,
+    ) -> MotionStatus {
+        let is_moving = match velocities {
+            Some(velocities) => velocities.iter().any(|v| v.abs() > self.settled_velocity),
+            None => match &self.previous {
+                Some((previous, previous_time)) if previous.len() == positions.len() => {
+                    match time.duration_since(*previous_time) {
+                        Ok(dt) if !dt.is_zero() => previous.iter().zip(positions).any(|(p0, p1)| {
+                            ((p1 - p0) / dt.as_secs_f64()).abs() > self.settled_velocity
+                        }),
+                        // The same stamp twice says nothing new
+                        _ => self.still_since.is_none(),
+                    }
+                }
+                _ => true,
+            },
+        };
+        self.previous = Some((positions.to_vec(), time));
+        self.still_since = match is_moving {
+            true => None,
+            false => self.still_since.or(Some(time)),
+        };
+        let is_settled = self.still_since.is_some_and(|still_since| {
+            time.duration_since(still_since).unwrap_or_default() >= self.settle_time
+        });
+        MotionStatus {
+            is_moving,
+            is_settled,
+        }
+    }
+}
+
+/// The linear speed of the end of the arm in [m/s], from the jacobian at the
+/// current joint positions. The joints are given in the order of the arm.
+pub fn tcp_speed(arm: &k::SerialChain<f64>, positions: &[f64], velocities: &[f64]) -> Option<f64> {
+    if positions.len() != arm.dof() || velocities.len() != arm.dof() {
+        return None;
+    }
+    arm.set_joint_positions_clamped(positions);
+    arm.update_transforms();
+    let twist = k::jacobian(arm) * DVector::from_column_slice(velocities);
+    Some(twist.fixed_rows::<3>(0).norm())
+}
+
+/// Polls {robot}_is_settled until it is true, or fails after the timeout.
+pub async fn wait_until_settled(
+    robot_name: &str,
+    connection_manager: &Arc<ConnectionManager>,
+    timeout: u64,
+) -> Result<(), String> {
+    let log_target = &format!("{robot_name}_settle");
+    let keys = vec![format!("{robot_name}_is_settled")];
+    let mut con = connection_manager.get_connection().await;
+    let started = SystemTime::now();
+    let mut interval = tokio::time::interval(Duration::from_millis(SETTLE_TICKER_RATE));
+    loop {
+        interval.tick().await;
+        if let Some(state) = StateManager::get_state_for_keys(&mut con, &keys).await {
+            if state.get_bool_or_default_to_false(&keys[0], log_target) {
+                return Ok(());
+            }
+        }
+        if started.elapsed().unwrap_or_default() >= Duration::from_millis(timeout) {
+            return Err(format!("The arm did not settle within {} ms.", timeout));
+        }
+    }
+}
+
+#[test]
+fn test_motion_detector() {
+    let settle = SettleParameters {
+        settled_velocity: 0.01,
+        settle_time: 100,
+        ..SettleParameters::default()
+    };
+    let at = |ms: u64| SystemTime::UNIX_EPOCH + Duration::from_millis(1700000000000 + ms);
+    let mut detector = MotionDetector::new(&settle);
+
+    let status = detector.update(&[0.0, 0.0], Some(&[0.5, 0.0]), at(0));
+    assert_eq!(status, MotionStatus { is_moving: true, is_settled: false });
+    let status = detector.update(&[0.1, 0.0], Some(&[0.001, 0.0]), at(50));
+    assert_eq!(status, MotionStatus { is_moving: false, is_settled: false });
+    let status = detector.update(&[0.1, 0.0], Some(&[0.0, 0.002]), at(150));
+    assert_eq!(status, MotionStatus { is_moving: false, is_settled: true });
+
+    // Without velocities, from the positions
+    let mut detector = MotionDetector::new(&settle);
+    assert!(detector.update(&[0.0], None, at(0)).is_moving);
+    assert!(detector.update(&[0.01], None, at(100)).is_moving);
+    assert!(!detector.update(&[0.01], None, at(200)).is_moving);
+    assert!(!detector.update(&[0.01], None, at(200)).is_moving);
+    assert!(detector.update(&[0.01], None, at(300)).is_settled);
+}
@@ -0,0 +1,159 @@
+use k::nalgebra::{DMatrix, DVector, Matrix3, Rotation3, Vector3};
+
+use crate::*;
+
+// Gravity expressed in the robot base frame, UR bases have z pointing up.
+pub static GRAVITY_IN_BASE: [f64; 3] = [0.0, 0.0, -9.81];
+
+// Below this ratio between the smallest and largest singular value, the
+// measured orientations don't excite the payload enough to trust the estimate.
+pub const PAYLOAD_IDENTIFICATION_MIN_CONDITION: f64 = 1e-3;
+pub const PAYLOAD_IDENTIFICATION_MIN_SAMPLES: usize = 3;
+
+/// One reading sent by identify_payload.script. The wrench is the raw reading of the
+/// sensor in the flange frame, so it doesn't depend on the payload or the TCP that
+/// are set. The flange pose is in the base frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WrenchSample {
+    pub wrench: [f64; 6],
+    pub flange_pose: [f64; 6],
+}
+
+// Feedback lines look like "WRENCH: p[fx,fy,fz,tx,ty,tz] POSE: p[x,y,z,rx,ry,rz]".
+pub fn parse_wrench_feedback(feedback: &str) -> Option<WrenchSample> {
+    let rest = feedback.trim().strip_prefix("WRENCH:")?;
+    let (wrench, pose) = rest.split_once("POSE:")?;
+    Some(WrenchSample {
+        wrench: pose_from_string(wrench)?,
+        flange_pose: pose_from_string(pose)?,
+    })
+}
+
+fn skew(v: &Vector3<f64>) -> Matrix3<f64> {
+    Matrix3::new(0.0, -v.z, v.y, v.z, 0.0, -v.x, -v.y, v.x, 0.0)
+}
+
+fn solve_least_squares(a: DMatrix<f64>, b: DVector<f64>) -> Result<DVector<f64>, String> {
+    let svd = a.svd(true, true);
+    let max = svd.singular_values.max();
+    let min = svd.singular_values.min();
+    if max <= 0.0 || min / max < PAYLOAD_IDENTIFICATION_MIN_CONDITION {
+        return Err("the measured poses do not excite the payload enough".to_string());
+    }
+    svd.solve(&b, f64::EPSILON).map_err(|e| e.to_string())
+}
+
+/// Estimates mass and center of gravity (in the flange frame) with linear least squares.
+/// Constant force and torque sensor offsets are estimated alongside and discarded.
+/// The inertia is left at zero, the wrist poses can't excite it while standing still.
+pub fn estimate_payload(samples: &[WrenchSample]) -> Result<Payload, String> {
+    if samples.len() < PAYLOAD_IDENTIFICATION_MIN_SAMPLES {
+        return Err(format!(
+            "at least {} samples are needed, got {}",
+            PAYLOAD_IDENTIFICATION_MIN_SAMPLES,
+            samples.len()
+        ));
+    }
+
+    let gravity = Vector3::from(GRAVITY_IN_BASE);
+    let mut gravity_in_tool = vec![];
+    let mut forces_in_tool = vec![];
+    let mut torques_in_tool = vec![];
+    for sample in samples {
+        let w = sample.wrench;
+        let p = sample.flange_pose;
+        let rotation = Rotation3::new(Vector3::new(p[3], p[4], p[5]));
+        gravity_in_tool.push(rotation.inverse() * gravity);
+        forces_in_tool.push(Vector3::new(w[0], w[1], w[2]));
+        torques_in_tool.push(Vector3::new(w[3], w[4], w[5]));
+    }
+
+    // f = m * g + f_offset, unknowns [m, f_offset]
+    let mut a = DMatrix::<f64>::zeros(3 * samples.len(), 4);
+    let mut b = DVector::<f64>::zeros(3 * samples.len());
+    for (i, (g, f)) in gravity_in_tool.iter().zip(forces_in_tool.iter()).enumerate() {
+        for r in 0..3 {
+            a[(3 * i + r, 0)] = g[r];
+            a[(3 * i + r, 1 + r)] = 1.0;
+            b[3 * i + r] = f[r];
+        }
+    }
+    let x = solve_least_squares(a, b)?;
+    let mass = x[0];
+    if mass <= 0.0 {
+        return Err(format!("estimated a non-positive mass of {:.3} kg", mass));
+    }
+
+    // t = cog x (m * g) + t_offset = -[m * g]x * cog + t_offset, unknowns [cog, t_offset]
+    let mut a = DMatrix::<f64>::zeros(3 * samples.len(), 6);
+    let mut b = DVector::<f64>::zeros(3 * samples.len());
+    for (i, (g, t)) in gravity_in_tool.iter().zip(torques_in_tool.iter()).enumerate() {
+        let weight = -skew(&(g * mass));
+        for r in 0..3 {
+            for c in 0..3 {
+                a[(3 * i + r, c)] = weight[(r, c)];
+            }
+            a[(3 * i + r, 3 + r)] = 1.0;
+            b[3 * i + r] = t[r];
+        }
+    }
+    let x = solve_least_squares(a, b)?;
+
+    Ok(Payload {
+        mass: mass as f32,
+        cog_x: x[0] as f32,
+        cog_y: x[1] as f32,
+        cog_z: x[2] as f32,
+        ..Default::default()
+    })
+}
+
+#[test]
+fn test_estimate_payload() {
+    let mass = 1.88;
+    let cog = Vector3::new(0.002, 0.003, 0.071);
+    let force_offset = Vector3::new(1.5, -0.7, 3.2);
+    let torque_offset = Vector3::new(0.05, 0.12, -0.03);
+    let gravity = Vector3::from(GRAVITY_IN_BASE);
+
+    let samples = [
+        [0.0, 0.0, 0.0],
+        [3.0, 0.0, 0.0],
+        [1.5, 0.0, 0.0],
+        [0.0, 1.5, 0.0],
+        [0.0, -1.5, 0.8],
+        [1.2, 0.4, 2.0],
+    ]
+    .iter()
+    .map(|r| {
+        let rotation = Rotation3::new(Vector3::new(r[0], r[1], r[2]));
+        let g = rotation.inverse() * gravity * mass;
+        let f = g + force_offset;
+        let t = cog.cross(&g) + torque_offset;
+        WrenchSample {
+            wrench: [f.x, f.y, f.z, t.x, t.y, t.z],
+            flange_pose: [0.4, 0.1, 0.3, r[0], r[1], r[2]],
+        }
+    })
+    .collect::<Vec<WrenchSample>>();
+
+    let payload = estimate_payload(&samples).unwrap();
+    assert!((payload.mass - 1.88).abs() < 1e-4);
+    assert!((payload.cog_x - 0.002).abs() < 1e-4);
+    assert!((payload.cog_y - 0.003).abs() < 1e-4);
+    assert!((payload.cog_z - 0.071).abs() < 1e-4);
+
+    // A single orientation can't separate the mass from the sensor offset.
+    let same_pose = vec![samples[0].clone(); 5];
+    assert!(estimate_payload(&same_pose).is_err());
+}
+
+#[test]
+fn test_parse_wrench_feedback() {
+    let sample =
+        parse_wrench_feedback("WRENCH: p[1.0, 2.0, -18.4, 0.1, 0.2, 0.3] POSE: p[0.4,0.1,0.3,2.5,0.0,0.0]")
+            .unwrap();
+    assert_eq!(sample.wrench[2], -18.4);
+    assert_eq!(sample.flange_pose[3], 2.5);
+    assert!(parse_wrench_feedback("FORCE: 12.0").is_none());
+}
@@ -0,0 +1,199 @@
+use k::nalgebra::{Isometry3, Translation3, UnitQuaternion, Vector3};
+use micro_sp::*;
+
+use crate::*;
+
+/// The frame whose axes a relative pose is expressed in. The displacement is
+/// always applied at the TCP, only the direction of the axes changes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RelativeFrame {
+    // Same as pose_trans(get_forward_kin(), relative_pose)
+    Tool,
+    // The baseframe_id, same as pose_add(get_forward_kin(), relative_pose)
+    Base,
+    // Any other frame from the transform tree, the base of the controller included
+    Frame(String),
+}
+
+impl RelativeFrame {
+    pub fn from_frame_id(frame_id: &str, baseframe_id: &str) -> RelativeFrame {
+        match frame_id {
+            "" | "unknown" | "tool" => RelativeFrame::Tool,
+            frame if frame == baseframe_id => RelativeFrame::Base,
+            frame => RelativeFrame::Frame(frame.to_string()),
+        }
+    }
+}
+
+pub fn isometry_from_sp_transform(transform: &SPTransform) -> Isometry3<f64> {
+    Isometry3::from_parts(
+        Translation3::new(
+            transform.translation.x.into_inner(),
+            transform.translation.y.into_inner(),
+            transform.translation.z.into_inner(),
+        ),
+        UnitQuaternion::from_quaternion(k::nalgebra::Quaternion::new(
+            transform.rotation.w.into_inner(),
+            transform.rotation.x.into_inner(),
+            transform.rotation.y.into_inner(),
+            transform.rotation.z.into_inner(),
+        )),
+    )
+}
+
+// UR poses are a translation and a rotation vector, p[x, y, z, rx, ry, rz].
+pub fn isometry_from_pose(pose: [f64; 6]) -> Isometry3<f64> {
+    Isometry3::from_parts(
+        Translation3::new(pose[0], pose[1], pose[2]),
+        UnitQuaternion::from_scaled_axis(Vector3::new(pose[3], pose[4], pose[5])),
+    )
+}
+
+pub fn pose_from_isometry(isometry: &Isometry3<f64>) -> [f64; 6] {
+    let t = isometry.translation.vector;
+    let r = isometry.rotation.scaled_axis();
+    [t.x, t.y, t.z, r.x, r.y, r.z]
+}
+
+/// Reads a relative pose either as an array or as a 'p[...]' string.
+/// An unset value is no motion at all.
+pub fn relative_pose_from_value(value: Option<SPValue>) -> Result<[f64; 6], String> {
+    match value {
+        None
+        | Some(SPValue::Array(ArrayOrUnknown::UNKNOWN))
+        | Some(SPValue::String(StringOrUnknown::UNKNOWN)) => Ok([0.0; 6]),
+        Some(SPValue::Array(ArrayOrUnknown::Array(values))) => values
+            .iter()
+            .map(|value| match value {
+                SPValue::Float64(FloatOrUnknown::Float64(value)) => Some(value.into_inner()),
+                _ => None,
+            })
+            .collect::<Option<Vec<f64>>>()
+            .and_then(|values| values.try_into().ok())
+            .ok_or(format!("expected 6 floats, got {:?}", values)),
+        Some(SPValue::String(StringOrUnknown::String(pose))) => {
+            pose_from_string(&pose).ok_or(format!("'{}' is not a pose", pose))
+        }
+        Some(value) => Err(format!("{:?} is not a pose", value)),
+    }
+}
+
+/// The absolute target of a relative motion. The translation is taken along the axes of
+/// the reference frame and the rotation around them, with the TCP as the center.
+pub fn resolve_relative_pose(
+    tcp_in_base: &Isometry3<f64>,
+    frame_in_base: &UnitQuaternion<f64>,
+    relative_pose: [f64; 6],
+) -> Isometry3<f64> {
+    let delta = isometry_from_pose(relative_pose);
+    let translation = tcp_in_base.translation.vector + frame_in_base * delta.translation.vector;
+    let rotation = frame_in_base * delta.rotation * frame_in_base.inverse() * tcp_in_base.rotation;
+    Isometry3::from_parts(Translation3::from(translation), rotation)
+}
+
+// Nominal reach of the UR arms, from the shoulder to the tool flange.
+pub fn ur_max_reach(ur_type: &str) -> f64 {
+    match ur_type {
+        "ur3" | "ur3e" => 0.5,
+        "ur5" | "ur5e" => 0.85,
+        "ur16e" => 0.9,
+        "ur20" => 1.75,
+        _ => 1.3,
+    }
+}
+
+// The shoulder sits on the z axis of the base, d1 above it.
+pub fn ur_shoulder_in_base(ur_type: &str) -> Vector3<f64> {
+    Vector3::new(0.0, 0.0, UrDh::from_ur_type(ur_type).map_or(0.0, |dh| dh.d1))
+}
+
+/// Checks that the flange can get to where the TCP has to be at the target.
+pub fn validate_target_in_base(
+    target_in_base: &Isometry3<f64>,
+    tcp_in_faceplate: &Isometry3<f64>,
+    ur_type: &str,
+) -> Result<(), String> {
+    let pose = pose_from_isometry(target_in_base);
+    if pose.iter().any(|v| !v.is_finite()) {
+        return Err(format!("target {:?} is not finite", pose));
+    }
+    let flange_in_base = target_in_base * tcp_in_faceplate.inverse();
+    let distance = (flange_in_base.translation.vector - ur_shoulder_in_base(ur_type)).norm();
+    let max_reach = ur_max_reach(ur_type);
+    if distance > max_reach {
+        return Err(format!(
+            "the flange would be {:.3} m from the shoulder, the reach is {:.3} m",
+            distance, max_reach
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_resolve_relative_pose() {
+    let tcp_in_base = isometry_from_pose([0.4, 0.2, 0.3, 3.0, 0.0, 0.0]);
+    let relative_pose = [0.0, 0.0, 0.1, 0.0, 0.0, 0.5];
+
+    // In the tool frame this is exactly pose_trans
+    let target = resolve_relative_pose(&tcp_in_base, &tcp_in_base.rotation, relative_pose);
+    let expected = tcp_in_base * isometry_from_pose(relative_pose);
+    assert!((target.translation.vector - expected.translation.vector).norm() < 1e-9);
+    assert!(target.rotation.angle_to(&expected.rotation) < 1e-9);
+
+    // In the base frame the translation is along the base axes
+    let target = resolve_relative_pose(&tcp_in_base, &UnitQuaternion::identity(), relative_pose);
+    assert!((target.translation.vector - Vector3::new(0.4, 0.2, 0.4)).norm() < 1e-9);
+    let expected = UnitQuaternion::from_euler_angles(0.0, 0.0, 0.5) * tcp_in_base.rotation;
+    assert!(target.rotation.angle_to(&expected) < 1e-9);
+
+    let round_trip = isometry_from_pose(pose_from_isometry(&target));
+    assert!(round_trip.rotation.angle_to(&target.rotation) < 1e-9);
+}
+
+#[test]
+fn test_relative_frame_from_frame_id() {
+    assert_eq!(RelativeFrame::from_frame_id("unknown", "base_link"), RelativeFrame::Tool);
+    assert_eq!(RelativeFrame::from_frame_id("base_link", "base_link"), RelativeFrame::Base);
+    // The base of the controller is turned half a turn from base_link
+    assert_eq!(
+        RelativeFrame::from_frame_id("base", "base_link"),
+        RelativeFrame::Frame("base".to_string())
+    );
+}
+
+#[test]
+fn test_validate_target_in_base() {
+    let tcp = Isometry3::identity();
+    let target = isometry_from_pose([0.4, 0.2, 0.3, 3.0, 0.0, 0.0]);
+    assert!(validate_target_in_base(&target, &tcp, "ur10e").is_ok());
+    let target = isometry_from_pose([0.5, 0.2, 0.3, 3.0, 0.0, 0.0]);
+    assert!(validate_target_in_base(&target, &tcp, "ur3e").is_err());
+    let target = isometry_from_pose([1.2, 0.2, 0.3, 3.0, 0.0, 0.0]);
+    assert!(validate_target_in_base(&target, &tcp, "ur5e").is_err());
+
+    // Straight above the shoulder is further from the base than the reach
+    let target = isometry_from_pose([0.0, 0.0, 0.95, 0.0, 0.0, 0.0]);
+    assert!(validate_target_in_base(&target, &tcp, "ur5e").is_ok());
+
+    // The flange has to be at the target minus the tcp
+    let target = isometry_from_pose([0.0, 0.9, 0.1625, 0.0, 0.0, 0.0]);
+    let tcp_ahead = isometry_from_pose([0.0, 0.1, 0.0, 0.0, 0.0, 0.0]);
+    let tcp_behind = isometry_from_pose([0.0, -0.1, 0.0, 0.0, 0.0, 0.0]);
+    assert!(validate_target_in_base(&target, &tcp_ahead, "ur5e").is_ok());
+    assert!(validate_target_in_base(&target, &tcp_behind, "ur5e").is_err());
+}
+
+#[test]
+fn test_relative_pose_from_value() {
+    assert_eq!(relative_pose_from_value(None).unwrap(), [0.0; 6]);
+    assert_eq!(
+        relative_pose_from_value(Some(vec![0.0, 0.0, 0.2, 0.0, 0.0, 0.0].to_spvalue())).unwrap()[2],
+        0.2
+    );
+    assert_eq!(
+        relative_pose_from_value(Some("p[0.0, 0.0, 0.2, 0.0, 0.0, 0.0]".to_spvalue())).unwrap()[2],
+        0.2
+    );
+    assert!(relative_pose_from_value(Some("p[0.0, 0.2]".to_spvalue())).is_err());
+    assert!(relative_pose_from_value(Some(1.0.to_spvalue())).is_err());
+}
@@ -0,0 +1,164 @@
+use crate::*;
+
+// Commands whose accelleration and velocity are joint space values [rad/s^2, rad/s],
+// all other commands move the tool in cartesian space [m/s^2, m/s].
+pub static JOINT_SPACE_COMMANDS: [&str; 5] = [
+    "safe_move_j",
+    "unsafe_move_j",
+    "identify_payload",
+    "jog_joint",
+    "move_j_relative",
+];
+
+/// The commissioned speed of a robot, no request can make the robot move faster than this.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpeedLimits {
+    pub max_joint_accelleration: f64,
+    pub max_joint_velocity: f64,
+    pub max_tool_accelleration: f64,
+    pub max_tool_velocity: f64,
+}
+
+impl Default for SpeedLimits {
+    fn default() -> Self {
+        SpeedLimits {
+            max_joint_accelleration: 15.0,
+            max_joint_velocity: std::f64::consts::PI,
+            max_tool_accelleration: 5.0,
+            max_tool_velocity: 1.0,
+        }
+    }
+}
+
+/// The shortest time that a move over the distance can take without going over the
+/// accelleration and the velocity, with a trapezoidal velocity profile.
+pub fn minimum_move_time(distance: f64, max_accelleration: f64, max_velocity: f64) -> f64 {
+    let distance = distance.abs();
+    match distance > max_velocity.powi(2) / max_accelleration {
+        true => distance / max_velocity + max_velocity / max_accelleration,
+        false => 2.0 * (distance / max_accelleration).sqrt(),
+    }
+}
+
+/// Applies the cell-wide scaling to the command before it is rendered and clamps the
+/// result to the speed limits. Scalings have to be in (0.0, 1.0], and a time based move
+/// is stretched by the velocity scaling instead. The controller ignores the accelleration
+/// and the velocity of a time based move, so its time is held to the speed limits over
+/// the move distance, the largest joint travel in [rad] or the tool travel in [m]. Without
+/// a distance, e.g. a movej to a feature with no inverse kinematics, only the scaling applies.
+pub fn apply_speed_scaling(
+    robot_command: &mut RobotCommand,
+    speed_limits: &SpeedLimits,
+    move_distance: Option<f64>,
+) -> Result<(), String> {
+    for (name, scaling) in [
+        ("global_acceleration_scaling", robot_command.global_acceleration_scaling),
+        ("global_velocity_scaling", robot_command.global_velocity_scaling),
+    ] {
+        if !(scaling > 0.0 && scaling <= 1.0) {
+            return Err(format!("{} has to be in (0.0, 1.0], got {}", name, scaling));
+        }
+    }
+
+    // Planners leave the keys that a command doesn't use at 0.0
+    let uses_speed = !robot_command.use_execution_time
+        && !NON_MOTION_COMMANDS.contains(&robot_command.command_type.as_str());
+    for (name, value) in [
+        ("accelleration", robot_command.accelleration),
+        ("velocity", robot_command.velocity),
+    ] {
+        if value.is_nan() || value < 0.0 || (uses_speed && value == 0.0) {
+            return Err(format!("{} has to be positive, got {}", name, value));
+        }
+    }
+
+    let (max_accelleration, max_velocity) =
+        match JOINT_SPACE_COMMANDS.contains(&robot_command.command_type.as_str()) {
+            true => (speed_limits.max_joint_accelleration, speed_limits.max_joint_velocity),
+            false => (speed_limits.max_tool_accelleration, speed_limits.max_tool_velocity),
+        };
+
+    robot_command.accelleration = (robot_command.accelleration
+        * robot_command.global_acceleration_scaling)
+        .min(max_accelleration);
+    robot_command.velocity =
+        (robot_command.velocity * robot_command.global_velocity_scaling).min(max_velocity);
+    if robot_command.use_execution_time {
+        let minimum_time = move_distance
+            .map(|distance| minimum_move_time(distance, max_accelleration, max_velocity))
+            .unwrap_or(0.0);
+        robot_command.execution_time = (robot_command.execution_time
+            / robot_command.global_velocity_scaling)
+            .max(minimum_time);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_apply_speed_scaling() {
+    let mut robot_command = RobotCommand {
+        command_type: "safe_move_j".to_string(),
+        accelleration: 2.0,
+        velocity: 5.0,
+        global_acceleration_scaling: 0.5,
+        global_velocity_scaling: 0.5,
+        use_execution_time: true,
+        execution_time: 2.0,
+        use_blend_radius: false,
+        blend_radius: 0.0,
+        use_joint_positions: false,
+        joint_positions: vec![],
+        use_preferred_joint_config: false,
+        preferred_joint_config: vec![],
+        use_payload: false,
+        payload: Payload::default().to_string(),
+        target_in_base: "".to_string(),
+        relative_pose: vec![],
+        tcp_in_faceplate: "".to_string(),
+        force_threshold: 0.0,
+    };
+
+    let speed_limits = SpeedLimits::default();
+    apply_speed_scaling(&mut robot_command, &speed_limits, Some(0.5)).unwrap();
+    assert_eq!(robot_command.accelleration, 1.0);
+    assert_eq!(robot_command.velocity, 2.5);
+    assert_eq!(robot_command.execution_time, 4.0);
+
+    // A time based move can't be faster than the limits allow over its distance
+    robot_command.execution_time = 0.1;
+    robot_command.global_velocity_scaling = 1.0;
+    apply_speed_scaling(&mut robot_command, &speed_limits, Some(3.0)).unwrap();
+    let minimum_time = 3.0 / std::f64::consts::PI + std::f64::consts::PI / 15.0;
+    assert!((robot_command.execution_time - minimum_time).abs() < 1e-9);
+
+    // A movej to a feature has no joint distance without inverse kinematics, it is
+    // still sent, with only the scaling applied to its time
+    robot_command.execution_time = 0.1;
+    robot_command.global_velocity_scaling = 0.5;
+    apply_speed_scaling(&mut robot_command, &speed_limits, None).unwrap();
+    assert_eq!(robot_command.execution_time, 0.2);
+    robot_command.global_velocity_scaling = 1.0;
+
+    // Unused keys are left at zero for time based moves
+    robot_command.accelleration = 0.0;
+    robot_command.velocity = 0.0;
+    assert!(apply_speed_scaling(&mut robot_command, &speed_limits, Some(0.5)).is_ok());
+    robot_command.use_execution_time = false;
+    assert!(apply_speed_scaling(&mut robot_command, &speed_limits, None).is_err());
+    robot_command.velocity = -1.0;
+    robot_command.accelleration = 1.0;
+    assert!(apply_speed_scaling(&mut robot_command, &speed_limits, None).is_err());
+
+    // The cap holds no matter what the planner asks for
+    robot_command.command_type = "unsafe_move_l".to_string();
+    robot_command.velocity = 10.0;
+    apply_speed_scaling(&mut robot_command, &speed_limits, None).unwrap();
+    assert_eq!(robot_command.velocity, speed_limits.max_tool_velocity);
+
+    robot_command.global_velocity_scaling = 0.0;
+    assert!(apply_speed_scaling(&mut robot_command, &speed_limits, None).is_err());
+    robot_command.global_velocity_scaling = 1.5;
+    assert!(apply_speed_scaling(&mut robot_command, &speed_limits, None).is_err());
+
+    assert!((minimum_move_time(0.1, 1.0, 1.0) - 2.0 * 0.1_f64.sqrt()).abs() < 1e-9);
+}
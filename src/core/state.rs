@@ -1,5 +1,7 @@
 use micro_sp::*;
 
+use crate::*;
+
 pub fn generate_robot_interface_state(robot_name: &str) -> State {
     let state = State::new();
 
@@ -7,6 +9,16 @@ pub fn generate_robot_interface_state(robot_name: &str) -> State {
     let request_state = v!(&&format!("{}_request_state", robot_name));
     let dashboard_request_trigger = bv!(&&format!("{}_dashboard_request_trigger", robot_name));
     let dashboard_request_state = v!(&&format!("{}_dashboard_request_state", robot_name));
+    let dashboard_command_type = v!(&&format!("{}_dashboard_command_type", robot_name));
+    let dashboard_program = v!(&&format!("{}_dashboard_program", robot_name));
+    let dashboard_response = v!(&&format!("{}_dashboard_response", robot_name));
+    let robot_mode = v!(&&format!("{}_robot_mode", robot_name));
+    let safety_status = v!(&&format!("{}_safety_status", robot_name));
+    let program_running = bv!(&&format!("{}_program_running", robot_name));
+    let remote_control = bv!(&&format!("{}_remote_control", robot_name));
+    let protective_stop_count = iv!(&&format!("{}_protective_stop_count", robot_name));
+    let protective_stop_recovery = v!(&&format!("{}_protective_stop_recovery", robot_name));
+    let failure_diagnostics = v!(&&format!("{}_failure_diagnostics", robot_name));
     let total_fail_counter = iv!(&&format!("{}_total_fail_counter", robot_name));
     let subsequent_fail_counter = iv!(&&format!("{}_subsequent_fail_counter", robot_name));
 
@@ -14,6 +26,16 @@ pub fn generate_robot_interface_state(robot_name: &str) -> State {
     let state = state.add(assign!(request_state, "initial".to_spvalue()));
     let state = state.add(assign!(dashboard_request_trigger, false.to_spvalue()));
     let state = state.add(assign!(dashboard_request_state, "initial".to_spvalue()));
+    let state = state.add(assign!(dashboard_command_type, SPValue::String(StringOrUnknown::UNKNOWN)));
+    let state = state.add(assign!(dashboard_program, SPValue::String(StringOrUnknown::UNKNOWN)));
+    let state = state.add(assign!(dashboard_response, SPValue::String(StringOrUnknown::UNKNOWN)));
+    let state = state.add(assign!(robot_mode, SPValue::String(StringOrUnknown::UNKNOWN)));
+    let state = state.add(assign!(safety_status, SPValue::String(StringOrUnknown::UNKNOWN)));
+    let state = state.add(assign!(program_running, SPValue::Bool(BoolOrUnknown::UNKNOWN)));
+    let state = state.add(assign!(remote_control, SPValue::Bool(BoolOrUnknown::UNKNOWN)));
+    let state = state.add(assign!(protective_stop_count, 0.to_spvalue()));
+    let state = state.add(assign!(protective_stop_recovery, SPValue::String(StringOrUnknown::UNKNOWN)));
+    let state = state.add(assign!(failure_diagnostics, SPValue::String(StringOrUnknown::UNKNOWN)));
     let state = state.add(assign!(total_fail_counter, 0.to_spvalue()));
     let state = state.add(assign!(subsequent_fail_counter, 0.to_spvalue()));
 
@@ -29,10 +51,27 @@ pub fn generate_robot_interface_state(robot_name: &str) -> State {
     let use_joint_positions = bv!(&&format!("{}_use_joint_positions", robot_name));
     let joint_positions = av!(&&format!("{}_joint_positions", robot_name));
     let joint_states = av!(&&format!("{}_joint_states", robot_name));
+    let joint_states_timestamp = iv!(&&format!("{}_joint_states_timestamp", robot_name));
+    let joint_velocities = av!(&&format!("{}_joint_velocities", robot_name));
+    let joint_efforts = av!(&&format!("{}_joint_efforts", robot_name));
+    let joint_names = av!(&&format!("{}_joint_names", robot_name));
+    let tf_timestamp = iv!(&&format!("{}_tf_timestamp", robot_name));
+    let state_age_ms = iv!(&&format!("{}_state_age_ms", robot_name));
+    let is_moving = bv!(&&format!("{}_is_moving", robot_name));
+    let is_settled = bv!(&&format!("{}_is_settled", robot_name));
+    let tcp_speed = fv!(&&format!("{}_tcp_speed", robot_name));
+    let tcp_pose = v!(&&format!("{}_tcp_pose", robot_name));
+    let ik_joint_positions = av!(&&format!("{}_ik_joint_positions", robot_name));
+    let joint_index = iv!(&&format!("{}_joint_index", robot_name));
+    let joint_delta = fv!(&&format!("{}_joint_delta", robot_name));
+    let relative_joint_positions = av!(&&format!("{}_relative_joint_positions", robot_name));
     let use_preferred_joint_config = bv!(&&format!("{}_use_preferred_joint_config", robot_name));
     let preferred_joint_config = av!(&&format!("{}_preferred_joint_config", robot_name));
     let use_payload = bv!(&&format!("{}_use_payload", robot_name));
     let payload = v!(&&format!("{}_payload", robot_name));
+    let payload_library = v!(&&format!("{}_payload_library", robot_name));
+    let identified_payload_id = v!(&&format!("{}_identified_payload_id", robot_name));
+    let identified_payload = v!(&&format!("{}_identified_payload", robot_name));
     let baseframe_id = v!(&&format!("{}_baseframe_id", robot_name));
     let faceplate_id = v!(&&format!("{}_faceplate_id", robot_name));
     let goal_feature_id = v!(&&format!("{}_goal_feature_id", robot_name));
@@ -44,6 +83,7 @@ pub fn generate_robot_interface_state(robot_name: &str) -> State {
     let estimated_position = v!(&&format!("{}_estimated_position", robot_name));
     let use_relative_pose = bv!(&&format!("{}_use_relative_pose", robot_name));
     let relative_pose = av!(&&format!("{}_relative_pose", robot_name));
+    let relative_pose_frame_id = v!(&&format!("{}_relative_pose_frame_id", robot_name));
     let gripper_force = fv!(&&format!("{}_gripper_force", robot_name));
     let gripper_velocity = fv!(&&format!("{}_gripper_velocity", robot_name));
     let gripper_ref_pos_percentage = iv!(&&format!("{}_gripper_ref_pos_percentage", robot_name));
@@ -60,10 +100,33 @@ pub fn generate_robot_interface_state(robot_name: &str) -> State {
     let state = state.add(assign!(use_joint_positions, SPValue::Bool(BoolOrUnknown::UNKNOWN)));
     let state = state.add(assign!(joint_positions, SPValue::Array(ArrayOrUnknown::UNKNOWN)));
     let state = state.add(assign!(joint_states, SPValue::Array(ArrayOrUnknown::UNKNOWN)));
+    let state = state.add(assign!(joint_states_timestamp, SPValue::Int64(IntOrUnknown::UNKNOWN)));
+    let state = state.add(assign!(joint_velocities, SPValue::Array(ArrayOrUnknown::UNKNOWN)));
+    let state = state.add(assign!(joint_efforts, SPValue::Array(ArrayOrUnknown::UNKNOWN)));
+    let state = state.add(assign!(joint_names, SPValue::Array(ArrayOrUnknown::UNKNOWN)));
+    let state = state.add(assign!(tf_timestamp, SPValue::Int64(IntOrUnknown::UNKNOWN)));
+    let state = state.add(assign!(state_age_ms, SPValue::Int64(IntOrUnknown::UNKNOWN)));
+    let state = state.add(assign!(is_moving, SPValue::Bool(BoolOrUnknown::UNKNOWN)));
+    let state = state.add(assign!(is_settled, SPValue::Bool(BoolOrUnknown::UNKNOWN)));
+    let state = state.add(assign!(tcp_speed, SPValue::Float64(FloatOrUnknown::UNKNOWN)));
+    let state = state.add(assign!(tcp_pose, SPValue::String(StringOrUnknown::UNKNOWN)));
+    let state = state.add(assign!(ik_joint_positions, SPValue::Array(ArrayOrUnknown::UNKNOWN)));
+    let state = state.add(assign!(joint_index, SPValue::Int64(IntOrUnknown::UNKNOWN)));
+    let state = state.add(assign!(joint_delta, SPValue::Float64(FloatOrUnknown::UNKNOWN)));
+    let state = state.add(assign!(relative_joint_positions, SPValue::Array(ArrayOrUnknown::UNKNOWN)));
     let state = state.add(assign!(use_preferred_joint_config, SPValue::Bool(BoolOrUnknown::UNKNOWN)));
     let state = state.add(assign!(preferred_joint_config, SPValue::Array(ArrayOrUnknown::UNKNOWN)));
     let state = state.add(assign!(use_payload, SPValue::Bool(BoolOrUnknown::UNKNOWN)));
     let state = state.add(assign!(payload, SPValue::String(StringOrUnknown::UNKNOWN)));
+    // Only the default, a library that is already stored in Redis is kept at startup
+    let state = state.add(assign!(
+        payload_library,
+        serde_json::to_string(&default_payload_library())
+            .unwrap_or_else(|_| "{}".to_string())
+            .to_spvalue()
+    ));
+    let state = state.add(assign!(identified_payload_id, SPValue::String(StringOrUnknown::UNKNOWN)));
+    let state = state.add(assign!(identified_payload, SPValue::String(StringOrUnknown::UNKNOWN)));
     let state = state.add(assign!(baseframe_id, SPValue::String(StringOrUnknown::UNKNOWN)));
     let state = state.add(assign!(faceplate_id, SPValue::String(StringOrUnknown::UNKNOWN)));
     let state = state.add(assign!(goal_feature_id, SPValue::String(StringOrUnknown::UNKNOWN)));
@@ -75,6 +138,7 @@ pub fn generate_robot_interface_state(robot_name: &str) -> State {
     let state = state.add(assign!(force_feedback, SPValue::Float64(FloatOrUnknown::UNKNOWN)));
     let state = state.add(assign!(use_relative_pose, SPValue::Bool(BoolOrUnknown::UNKNOWN)));
     let state = state.add(assign!(relative_pose, SPValue::Array(ArrayOrUnknown::UNKNOWN)));
+    let state = state.add(assign!(relative_pose_frame_id, SPValue::String(StringOrUnknown::UNKNOWN)));
     let state = state.add(assign!(gripper_force, SPValue::Float64(FloatOrUnknown::UNKNOWN)));
     let state = state.add(assign!(gripper_velocity, SPValue::Float64(FloatOrUnknown::UNKNOWN)));
     let state = state.add(assign!(gripper_ref_pos_percentage, SPValue::Int64(IntOrUnknown::UNKNOWN)));
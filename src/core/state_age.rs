@@ -0,0 +1,118 @@
+use micro_sp::*;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// 0 turns the check off
+pub const DEFAULT_MAX_STATE_AGE: u64 = 500;
+pub const STATE_AGE_TICKER_RATE: u64 = 100;
+
+/// The commands that don't move the arm, these are sent even when the state is stale.
+pub static NON_MOTION_COMMANDS: [&str; 9] = [
+    "connect_robotiq_gripper",
+    "close_robotiq_gripper",
+    "open_robotiq_gripper",
+    "start_vacuum",
+    "stop_vacuum",
+    "set_payload",
+    "lock_rsp",
+    "unlock_rsp",
+    "get_force",
+];
+
+// Publishers that don't stamp their messages leave the header at zero.
+pub fn time_from_stamp(sec: i32, nanosec: u32) -> Option<SystemTime> {
+    match sec > 0 || nanosec > 0 {
+        true => Some(UNIX_EPOCH + Duration::new(sec.max(0) as u64, nanosec)),
+        false => None,
+    }
+}
+
+pub fn millis_since_epoch(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_millis() as i64,
+        Err(_) => 0,
+    }
+}
+
+// The timestamps are in ms since the epoch, a source that never arrived has none.
+pub fn timestamp_from_value(value: Option<SPValue>) -> Option<i64> {
+    match value {
+        Some(SPValue::Int64(IntOrUnknown::Int64(timestamp))) if timestamp > 0 => Some(timestamp),
+        _ => None,
+    }
+}
+
+/// The age of the oldest source, or None if one of them never arrived. A clock that
+/// runs ahead of ours doesn't make the data any younger than now.
+pub fn state_age_ms(timestamps: &[Option<i64>], now: SystemTime) -> Option<i64> {
+    let now = millis_since_epoch(now);
+    timestamps
+        .iter()
+        .map(|timestamp| timestamp.map(|timestamp| (now - timestamp).max(0)))
+        .try_fold(0, |oldest, age| age.map(|age| age.max(oldest)))
+}
+
+pub fn check_state_age(age: Option<i64>, max_age_ms: u64) -> Result<(), String> {
+    match age {
+        _ if max_age_ms == 0 => Ok(()),
+        Some(age) if age <= max_age_ms as i64 => Ok(()),
+        Some(age) => Err(format!(
+            "the joint states and transforms are {} ms old, at most {} ms is allowed",
+            age, max_age_ms
+        )),
+        None => Err("there are no joint states or transforms yet".to_string()),
+    }
+}
+
+/// Keeps {robot}_state_age_ms up to date, also when the driver stops publishing.
+pub async fn state_age_to_redis(
+    robot_name: &str,
+    connection_manager: &Arc<ConnectionManager>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let log_target = &format!("{robot_name}_state_age");
+    let keys = vec![
+        format!("{robot_name}_joint_states_timestamp"),
+        format!("{robot_name}_tf_timestamp"),
+    ];
+    let mut interval =
+        tokio::time::interval(std::time::Duration::from_millis(STATE_AGE_TICKER_RATE));
+    let mut con = connection_manager.get_connection().await;
+    loop {
+        interval.tick().await;
+        if let Err(_) = connection_manager.check_redis_health(&log_target).await {
+            continue;
+        }
+        let state = match StateManager::get_state_for_keys(&mut con, &keys).await {
+            Some(s) => s,
+            None => continue,
+        };
+        let timestamps = keys
+            .iter()
+            .map(|key| timestamp_from_value(state.get_value(key, &log_target)))
+            .collect::<Vec<Option<i64>>>();
+        let age = match state_age_ms(&timestamps, SystemTime::now()) {
+            Some(age) => age.to_spvalue(),
+            None => SPValue::Int64(IntOrUnknown::UNKNOWN),
+        };
+        StateManager::set_sp_value(&mut con, &format!("{robot_name}_state_age_ms"), &age).await;
+    }
+}
+
+#[test]
+fn test_state_age() {
+    assert_eq!(time_from_stamp(0, 0), None);
+    let stamp = time_from_stamp(1700000000, 500_000_000).unwrap();
+    assert_eq!(millis_since_epoch(stamp), 1700000000500);
+
+    let now = UNIX_EPOCH + Duration::from_millis(1700000001000);
+    assert_eq!(state_age_ms(&[Some(1700000000900), Some(1700000000500)], now), Some(500));
+    assert_eq!(state_age_ms(&[Some(1700000000900), None], now), None);
+    // Ahead of our clock
+    assert_eq!(state_age_ms(&[Some(1700000001200)], now), Some(0));
+
+    assert!(check_state_age(Some(500), 500).is_ok());
+    assert!(check_state_age(Some(501), 500).is_err());
+    assert!(check_state_age(None, 500).is_err());
+    assert!(check_state_age(None, 0).is_ok());
+    assert_eq!(timestamp_from_value(Some(SPValue::Int64(IntOrUnknown::UNKNOWN))), None);
+}
@@ -1,4 +1,6 @@
+use std::collections::HashMap;
 use std::fmt;
+use std::str::FromStr;
 
 use micro_sp::*;
 use serde::{Deserialize, Serialize};
@@ -18,13 +20,24 @@ pub enum CommandType {
     PlaceVacuum,
     StartVacuum,
     StopVacuum,
+    IdentifyPayload,
+    JogJoint,
+    MoveJRelative,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum DashboardCommandType {
     UNKNOWN,
     Stop,
     ResetProtectiveStop,
+    PowerOn,
+    PowerOff,
+    BrakeRelease,
+    UnlockProtectiveStop,
+    CloseSafetyPopup,
+    Play,
+    Pause,
+    LoadProgram,
 }
 
 impl fmt::Display for CommandType {
@@ -41,6 +54,9 @@ impl fmt::Display for CommandType {
             CommandType::PlaceVacuum => "place_vacuum",
             CommandType::StartVacuum => "start_vacuum",
             CommandType::StopVacuum => "stop_vacuum",
+            CommandType::IdentifyPayload => "identify_payload",
+            CommandType::JogJoint => "jog_joint",
+            CommandType::MoveJRelative => "move_j_relative",
             CommandType::UNKNOWN => "unknown",
         };
         write!(f, "{}", s)
@@ -52,12 +68,64 @@ impl fmt::Display for DashboardCommandType {
         let s = match self {
             DashboardCommandType::Stop => "stop",
             DashboardCommandType::ResetProtectiveStop => "reset_protective_stop",
+            DashboardCommandType::PowerOn => "power_on",
+            DashboardCommandType::PowerOff => "power_off",
+            DashboardCommandType::BrakeRelease => "brake_release",
+            DashboardCommandType::UnlockProtectiveStop => "unlock_protective_stop",
+            DashboardCommandType::CloseSafetyPopup => "close_safety_popup",
+            DashboardCommandType::Play => "play",
+            DashboardCommandType::Pause => "pause",
+            DashboardCommandType::LoadProgram => "load_program",
             DashboardCommandType::UNKNOWN => "unknown",
         };
         write!(f, "{}", s)
     }
 }
 
+impl FromStr for DashboardCommandType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stop" => Ok(DashboardCommandType::Stop),
+            "reset_protective_stop" => Ok(DashboardCommandType::ResetProtectiveStop),
+            "power_on" => Ok(DashboardCommandType::PowerOn),
+            "power_off" => Ok(DashboardCommandType::PowerOff),
+            "brake_release" => Ok(DashboardCommandType::BrakeRelease),
+            "unlock_protective_stop" => Ok(DashboardCommandType::UnlockProtectiveStop),
+            "close_safety_popup" => Ok(DashboardCommandType::CloseSafetyPopup),
+            "play" => Ok(DashboardCommandType::Play),
+            "pause" => Ok(DashboardCommandType::Pause),
+            "load_program" => Ok(DashboardCommandType::LoadProgram),
+            _ => Err(format!("unknown dashboard command '{}'", s)),
+        }
+    }
+}
+
+impl DashboardCommandType {
+    /// The line sent to the dashboard server, load_program needs the program to load.
+    pub fn to_dashboard_request(&self, program: &str) -> Option<String> {
+        match self {
+            DashboardCommandType::Stop => Some("stop".to_string()),
+            DashboardCommandType::ResetProtectiveStop
+            | DashboardCommandType::UnlockProtectiveStop => {
+                Some("unlock protective stop".to_string())
+            }
+            DashboardCommandType::PowerOn => Some("power on".to_string()),
+            DashboardCommandType::PowerOff => Some("power off".to_string()),
+            DashboardCommandType::BrakeRelease => Some("brake release".to_string()),
+            DashboardCommandType::CloseSafetyPopup => Some("close safety popup".to_string()),
+            DashboardCommandType::Play => Some("play".to_string()),
+            DashboardCommandType::Pause => Some("pause".to_string()),
+            DashboardCommandType::LoadProgram => match program.trim() {
+                "" | "unknown" => None,
+                program => Some(format!("load {}", program)),
+            },
+            DashboardCommandType::UNKNOWN => None,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct RobotCommand {
     // MoveJ, Movel, StartVacuum...
@@ -125,7 +193,8 @@ pub struct RobotCommand {
 //     pub ref_pos_percentage: i64, // fully closed: 100, fully open 0, or anything inbetween
 // }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(try_from = "PayloadRepr")]
 pub struct Payload {
     /// Payload Mass in kilograms.
     pub mass: f32,
@@ -161,9 +230,11 @@ impl Default for Payload {
     }
 }
 
-impl Payload {
-    pub fn to_string(&self) -> String {
-        format!(
+// Renders the payload the way set_payload() and set_target_payload() expect it.
+impl fmt::Display for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
             "{},[{},{},{}],[{},{},{},{},{},{}]",
             self.mass,
             self.cog_x,
@@ -179,6 +250,206 @@ impl Payload {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct PayloadParseError(pub String);
+
+impl fmt::Display for PayloadParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid payload: {}", self.0)
+    }
+}
+
+impl std::error::Error for PayloadParseError {}
+
+// Accepts both the UR script form 'mass,[cx,cy,cz],[ixx,iyy,izz,ixy,ixz,iyz]'
+// (the CoG and inertia lists are optional, as in URScript) and the JSON object form.
+impl FromStr for Payload {
+    type Err = PayloadParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.starts_with('{') {
+            return serde_json::from_str::<Payload>(s)
+                .map_err(|e| PayloadParseError(e.to_string()));
+        }
+
+        let compact: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+        if compact.ends_with(',') {
+            return Err(PayloadParseError(format!("trailing comma in '{}'", s)));
+        }
+        let (mass, mut rest) = match compact.split_once(',') {
+            Some((mass, rest)) => (mass, rest),
+            None => (compact.as_str(), ""),
+        };
+        let mass = parse_payload_number(mass)?;
+
+        let mut lists = vec![];
+        while !rest.is_empty() {
+            let inner = match rest.strip_prefix('[').and_then(|r| r.split_once(']')) {
+                Some((inner, tail)) => {
+                    rest = match tail.strip_prefix(',') {
+                        Some(tail) if !tail.is_empty() => tail,
+                        None if tail.is_empty() => tail,
+                        _ => return Err(PayloadParseError(format!("unexpected '{}'", tail))),
+                    };
+                    inner
+                }
+                None => return Err(PayloadParseError(format!("expected a list, got '{}'", rest))),
+            };
+            lists.push(
+                inner
+                    .split(',')
+                    .map(parse_payload_number)
+                    .collect::<Result<Vec<f32>, PayloadParseError>>()?,
+            );
+        }
+
+        let cog = match lists.first() {
+            Some(cog) if cog.len() == 3 => cog.clone(),
+            Some(cog) => {
+                return Err(PayloadParseError(format!(
+                    "center of gravity needs 3 values, got {}",
+                    cog.len()
+                )))
+            }
+            None => vec![0.0; 3],
+        };
+        let inertia = match lists.get(1) {
+            Some(inertia) if inertia.len() == 6 => inertia.clone(),
+            Some(inertia) => {
+                return Err(PayloadParseError(format!(
+                    "inertia needs 6 values, got {}",
+                    inertia.len()
+                )))
+            }
+            None => vec![0.0; 6],
+        };
+        if lists.len() > 2 {
+            return Err(PayloadParseError(format!("too many lists in '{}'", s)));
+        }
+
+        Payload::try_from(PayloadRepr::Object(PayloadFields {
+            mass,
+            cog_x: cog[0],
+            cog_y: cog[1],
+            cog_z: cog[2],
+            ixx: inertia[0],
+            iyy: inertia[1],
+            izz: inertia[2],
+            ixy: inertia[3],
+            ixz: inertia[4],
+            iyz: inertia[5],
+        }))
+    }
+}
+
+fn parse_payload_number(s: &str) -> Result<f32, PayloadParseError> {
+    s.parse::<f32>()
+        .map_err(|_| PayloadParseError(format!("'{}' is not a number", s)))
+}
+
+// Payloads are deserialized either from the UR script string or from an object.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PayloadRepr {
+    Script(String),
+    Object(PayloadFields),
+}
+
+#[derive(Deserialize)]
+struct PayloadFields {
+    mass: f32,
+    #[serde(default)]
+    cog_x: f32,
+    #[serde(default)]
+    cog_y: f32,
+    #[serde(default)]
+    cog_z: f32,
+    #[serde(default)]
+    ixx: f32,
+    #[serde(default)]
+    iyy: f32,
+    #[serde(default)]
+    izz: f32,
+    #[serde(default)]
+    ixy: f32,
+    #[serde(default)]
+    ixz: f32,
+    #[serde(default)]
+    iyz: f32,
+}
+
+impl TryFrom<PayloadRepr> for Payload {
+    type Error = PayloadParseError;
+
+    fn try_from(repr: PayloadRepr) -> Result<Self, Self::Error> {
+        let f = match repr {
+            PayloadRepr::Script(s) => return s.parse::<Payload>(),
+            PayloadRepr::Object(f) => f,
+        };
+        let payload = Payload {
+            mass: f.mass,
+            cog_x: f.cog_x,
+            cog_y: f.cog_y,
+            cog_z: f.cog_z,
+            ixx: f.ixx,
+            iyy: f.iyy,
+            izz: f.izz,
+            ixy: f.ixy,
+            ixz: f.ixz,
+            iyz: f.iyz,
+        };
+        let values = [
+            payload.mass,
+            payload.cog_x,
+            payload.cog_y,
+            payload.cog_z,
+            payload.ixx,
+            payload.iyy,
+            payload.izz,
+            payload.ixy,
+            payload.ixz,
+            payload.iyz,
+        ];
+        if values.iter().any(|v| !v.is_finite()) {
+            return Err(PayloadParseError("values must be finite".to_string()));
+        }
+        if payload.mass < 0.0 {
+            return Err(PayloadParseError(format!("negative mass {}", payload.mass)));
+        }
+        Ok(payload)
+    }
+}
+
+pub type PayloadLibrary = HashMap<String, Payload>;
+
+pub fn default_payload_library() -> PayloadLibrary {
+    [
+        ("rsp_only", crate::RSP_ONLY_PAYLOAD),
+        ("rsp_and_sponge", crate::RSP_AND_SPONGE_PAYLOAD),
+        ("rsp_and_gripper", crate::RSP_AND_GRIPPER_PAYLOAD),
+        ("rsp_and_bvt", crate::RSP_AND_BVT_PAYLOAD),
+        ("rsp_and_svt", crate::RSP_AND_SVT_PAYLOAD),
+        ("rsp_and_photoneo", crate::RSP_AND_PHOTONEO_PAYLOAD),
+    ]
+    .iter()
+    .filter_map(|(name, payload)| {
+        payload
+            .parse::<Payload>()
+            .ok()
+            .map(|payload| (name.to_string(), payload))
+    })
+    .collect()
+}
+
+// The payload key can either name an entry in the library or hold the payload itself.
+pub fn resolve_payload(value: &str, library: &PayloadLibrary) -> Result<Payload, PayloadParseError> {
+    match library.get(value.trim()) {
+        Some(payload) => Ok(payload.clone()),
+        None => value.parse::<Payload>(),
+    }
+}
+
 // pub fn payload_to_string(p: Payload) -> String {
 //     format!(
 //         "{},[{},{},{}],[{},{},{},{},{},{}]",
@@ -225,6 +496,109 @@ pub fn pose_to_string(pose: [f64; 6]) -> String {
     format!("p[{},{},{},{},{},{}]", pose[0], pose[1], pose[2], pose[3], pose[4], pose[5])
 }
 
+// None if the value is not an array of known floats.
+pub fn floats_from_value(value: Option<SPValue>) -> Option<Vec<f64>> {
+    match value {
+        Some(SPValue::Array(ArrayOrUnknown::Array(values))) => values
+            .iter()
+            .map(|value| match value {
+                SPValue::Float64(FloatOrUnknown::Float64(value)) => Some(value.into_inner()),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+pub fn strings_from_value(value: Option<SPValue>) -> Option<Vec<String>> {
+    match value {
+        Some(SPValue::Array(ArrayOrUnknown::Array(values))) => values
+            .iter()
+            .map(|value| match value {
+                SPValue::String(StringOrUnknown::String(value)) => Some(value.clone()),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+// Accepts both 'p[x,y,z,rx,ry,rz]' and '[x,y,z,rx,ry,rz]'.
+pub fn pose_from_string(pose: &str) -> Option<[f64; 6]> {
+    let pose = pose.trim();
+    let inner = pose
+        .strip_prefix('p')
+        .unwrap_or(pose)
+        .trim()
+        .strip_prefix('[')?
+        .strip_suffix(']')?;
+    let values = inner
+        .split(',')
+        .map(|v| v.trim().parse::<f64>().ok())
+        .collect::<Option<Vec<f64>>>()?;
+    values.try_into().ok()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JointLimit {
+    pub name: String,
+    // Position limits in [rad]
+    pub lower: f64,
+    pub upper: f64,
+    // Velocity limit in [rad/s]
+    pub velocity: f64,
+}
+
+/// What the controller needs to know about the commissioned robot.
+#[derive(Debug, Clone, Default)]
+pub struct RobotParameters {
+    pub ur_type: String,
+    pub speed_limits: SpeedLimits,
+    // In the order of UR_JOINT_NAMES
+    pub joint_limits: Vec<JointLimit>,
+    pub protective_stop_recovery: ProtectiveStopRecovery,
+    pub script_backend: ScriptBackend,
+    pub frames: FrameParameters,
+    // How old the joint states and transforms may be for a motion, in ms. 0 doesn't check.
+    pub max_state_age: u64,
+    pub settle: crate::SettleParameters,
+    // How long a script may run before it is stopped, in ms. 0 waits forever.
+    pub script_timeout: u64,
+    // The urdf to check the targets with inverse kinematics before they are sent, None skips the check
+    pub ik_urdf: Option<String>,
+}
+
+/// Where the frames of the robot go in a transform tree that other robots share.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameParameters {
+    // In front of every frame of the robot, e.g. "r1_"
+    pub tf_prefix: String,
+    // The frame that the root link of the robot is attached under
+    pub parent_frame_id: String,
+    // p[x, y, z, rx, ry, rz] of the root link in the parent frame
+    pub base_in_parent: [f64; 6],
+}
+
+impl Default for FrameParameters {
+    fn default() -> Self {
+        FrameParameters {
+            tf_prefix: "".to_string(),
+            parent_frame_id: crate::DEFAULT_ROOT_FRAME_ID.to_string(),
+            base_in_parent: [0.0; 6],
+        }
+    }
+}
+
+impl FrameParameters {
+    pub fn baseframe_id(&self) -> String {
+        format!("{}{}", self.tf_prefix, crate::DEFAULT_BASEFRAME_ID)
+    }
+
+    pub fn faceplate_id(&self) -> String {
+        format!("{}{}", self.tf_prefix, crate::DEFAULT_FACEPLATE_ID)
+    }
+}
+
 pub struct URDFParameters {
     pub name: String,
     pub ur_type: String,
@@ -250,3 +624,79 @@ impl Default for URDFParameters {
         }
     }
 }
+
+#[test]
+fn test_payload_round_trip() {
+    for payload in [
+        crate::RSP_ONLY_PAYLOAD,
+        crate::RSP_AND_SPONGE_PAYLOAD,
+        crate::RSP_AND_GRIPPER_PAYLOAD,
+        crate::RSP_AND_BVT_PAYLOAD,
+        crate::RSP_AND_SVT_PAYLOAD,
+        crate::RSP_AND_PHOTONEO_PAYLOAD,
+    ] {
+        let parsed = payload.parse::<Payload>().unwrap();
+        assert_eq!(parsed.to_string().parse::<Payload>().unwrap(), parsed);
+
+        let json = serde_json::to_string(&parsed).unwrap();
+        assert_eq!(json.parse::<Payload>().unwrap(), parsed);
+        assert_eq!(serde_json::from_str::<Payload>(&json).unwrap(), parsed);
+    }
+
+    let parsed = " 0.69, [0.026, -0.008, 0.012] ".parse::<Payload>().unwrap();
+    assert_eq!(parsed.cog_y, -0.008);
+    assert_eq!(parsed.ixx, 0.0);
+    let parsed = serde_json::from_str::<Payload>("\"1.5\"").unwrap();
+    assert_eq!(parsed.mass, 1.5);
+    let parsed = r#"{"mass": 1.2, "cog_z": 0.05}"#.parse::<Payload>().unwrap();
+    assert_eq!(parsed.cog_z, 0.05);
+}
+
+#[test]
+fn test_payload_rejects_malformed() {
+    for payload in [
+        "",
+        "heavy",
+        "1.0,",
+        "1.0,[0.0,0.0]",
+        "1.0,[0.0,0.0,0.0],[0.0]",
+        "1.0,[0.0,0.0,0.0],[0.0,0.0,0.0,0.0,0.0,0.0],[0.0]",
+        "1.0,[0.0,0.0,0.0",
+        "-1.0,[0.0,0.0,0.0]",
+        "NaN",
+        r#"{"cog_x": 0.1}"#,
+    ] {
+        assert!(payload.parse::<Payload>().is_err(), "accepted '{}'", payload);
+    }
+}
+
+#[test]
+fn test_resolve_payload() {
+    let library = default_payload_library();
+    assert_eq!(library.len(), 6);
+    assert_eq!(resolve_payload("rsp_only", &library).unwrap().mass, 0.69);
+    assert_eq!(resolve_payload("0.5,[0.0,0.0,0.1]", &library).unwrap().cog_z, 0.1);
+    assert!(resolve_payload("rsp_with_something_else", &library).is_err());
+}
+
+#[test]
+fn test_dashboard_command_type() {
+    for command in [
+        DashboardCommandType::Stop,
+        DashboardCommandType::PowerOn,
+        DashboardCommandType::BrakeRelease,
+        DashboardCommandType::LoadProgram,
+    ] {
+        assert_eq!(command.to_string().parse::<DashboardCommandType>().unwrap(), command);
+    }
+    assert_eq!(
+        DashboardCommandType::UnlockProtectiveStop.to_dashboard_request(""),
+        Some("unlock protective stop".to_string())
+    );
+    assert_eq!(
+        DashboardCommandType::LoadProgram.to_dashboard_request("pick.urp"),
+        Some("load pick.urp".to_string())
+    );
+    assert_eq!(DashboardCommandType::LoadProgram.to_dashboard_request("unknown"), None);
+    assert!("fly".parse::<DashboardCommandType>().is_err());
+}
@@ -0,0 +1,455 @@
+use k::nalgebra::{Isometry3, Translation3, UnitQuaternion, Vector3};
+use std::f64::consts::{FRAC_PI_2, PI, TAU};
+
+// How far outside of [-1, 1] a cosine may be rounded back in
+const EPSILON: f64 = 1e-9;
+
+/// The DH parameters of a UR arm. The base is the base frame of the controller and
+/// the end is the tool flange, the same frames as get_forward_kin and get_inverse_kin.
+/// These are the nominal parameters, not the calibration of the arm, so the solutions
+/// are off by up to about a mm from what the controller solves.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UrDh {
+    pub d1: f64,
+    pub a2: f64,
+    pub a3: f64,
+    pub d4: f64,
+    pub d5: f64,
+    pub d6: f64,
+}
+
+/// Which of the eight solutions for a pose.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UrConfiguration {
+    // The axis of the shoulder lift points to the left, seen from the base towards the wrist
+    pub shoulder_left: bool,
+    // The elbow is above the line from the shoulder to the wrist
+    pub elbow_up: bool,
+    // The wrist 1 link points up, from wrist 1 towards wrist 2
+    pub wrist_up: bool,
+}
+
+impl UrDh {
+    /// The nominal parameters from the UR website, the calibration of each arm is
+    /// off by a few tenths of a mm from these. The calibration in the urdf is not used.
+    pub fn from_ur_type(ur_type: &str) -> Option<UrDh> {
+        let [d1, a2, a3, d4, d5, d6] = match ur_type {
+            "ur3" => [0.1519, -0.24365, -0.21325, 0.11235, 0.08535, 0.0819],
+            "ur5" => [0.089159, -0.425, -0.39225, 0.10915, 0.09465, 0.0823],
+            "ur10" => [0.1273, -0.612, -0.5723, 0.163941, 0.1157, 0.0922],
+            "ur3e" => [0.15185, -0.24355, -0.2132, 0.13105, 0.08535, 0.0921],
+            "ur5e" => [0.1625, -0.425, -0.3922, 0.1333, 0.0997, 0.0996],
+            "ur10e" => [0.1807, -0.6127, -0.57155, 0.17415, 0.11985, 0.11655],
+            "ur16e" => [0.1807, -0.4784, -0.36, 0.17415, 0.11985, 0.11655],
+            "ur20" => [0.2363, -0.862, -0.7287, 0.201, 0.1593, 0.1543],
+            _ => return None,
+        };
+        Some(UrDh {
+            d1,
+            a2,
+            a3,
+            d4,
+            d5,
+            d6,
+        })
+    }
+
+    fn joint_transform(&self, joint: usize, theta: f64) -> Isometry3<f64> {
+        let (d, a, alpha) = match joint {
+            0 => (self.d1, 0.0, FRAC_PI_2),
+            1 => (0.0, self.a2, 0.0),
+            2 => (0.0, self.a3, 0.0),
+            3 => (self.d4, 0.0, FRAC_PI_2),
+            4 => (self.d5, 0.0, -FRAC_PI_2),
+            _ => (self.d6, 0.0, 0.0),
+        };
+        Isometry3::from_parts(
+            Translation3::new(0.0, 0.0, d),
+            UnitQuaternion::from_axis_angle(&Vector3::z_axis(), theta),
+        ) * Isometry3::from_parts(
+            Translation3::new(a, 0.0, 0.0),
+            UnitQuaternion::from_axis_angle(&Vector3::x_axis(), alpha),
+        )
+    }
+
+    // Every joint frame in the base, the last one is the tool flange
+    fn joint_frames(&self, joint_positions: &[f64; 6]) -> [Isometry3<f64>; 6] {
+        let mut frames = [Isometry3::identity(); 6];
+        let mut frame = Isometry3::identity();
+        for (joint, theta) in joint_positions.iter().enumerate() {
+            frame *= self.joint_transform(joint, *theta);
+            frames[joint] = frame;
+        }
+        frames
+    }
+
+    pub fn forward_kinematics(&self, joint_positions: &[f64; 6]) -> Isometry3<f64> {
+        self.joint_frames(joint_positions)[5]
+    }
+
+    /// All solutions for the tool flange in the base, up to eight, with every joint
+    /// in (-pi, pi]. A wrist singularity fixes the last joint at zero.
+    pub fn inverse_kinematics(&self, flange_in_base: &Isometry3<f64>) -> Vec<[f64; 6]> {
+        let rotation = flange_in_base.rotation.to_rotation_matrix();
+        let r = rotation.matrix();
+        let p06 = flange_in_base.translation.vector;
+        let p05 = p06 - self.d6 * r.column(2);
+        let mut solutions = vec![];
+
+        // The wrist has to be further from the base axis than the shoulder offset
+        let p05_xy = p05.x.hypot(p05.y);
+        if p05_xy < self.d4.abs() {
+            return solutions;
+        }
+        let psi = p05.y.atan2(p05.x);
+        let phi = (self.d4 / p05_xy).acos();
+
+        for theta1 in [psi + phi + FRAC_PI_2, psi - phi + FRAC_PI_2] {
+            let (s1, c1) = theta1.sin_cos();
+            let c5 = (p06.x * s1 - p06.y * c1 - self.d4) / self.d6;
+            if c5.abs() > 1.0 + EPSILON {
+                continue;
+            }
+            let theta5_abs = c5.clamp(-1.0, 1.0).acos();
+
+            for theta5 in [theta5_abs, -theta5_abs] {
+                let s5 = theta5.sin();
+                let theta6 = match s5.abs() < EPSILON {
+                    true => 0.0,
+                    false => ((-r[(0, 1)] * s1 + r[(1, 1)] * c1) / s5)
+                        .atan2((r[(0, 0)] * s1 - r[(1, 0)] * c1) / s5),
+                };
+
+                // The three parallel joints are a planar arm in the xy plane of the shoulder
+                let t14 = self.joint_transform(0, theta1).inverse()
+                    * flange_in_base
+                    * (self.joint_transform(4, theta5) * self.joint_transform(5, theta6)).inverse();
+                let p14 = t14.translation.vector;
+                let p14_xy = p14.x.hypot(p14.y);
+                let c3 = (p14_xy.powi(2) - self.a2.powi(2) - self.a3.powi(2)) / (2.0 * self.a2 * self.a3);
+                if c3.abs() > 1.0 + EPSILON || p14_xy < EPSILON {
+                    continue;
+                }
+                let theta3_abs = c3.clamp(-1.0, 1.0).acos();
+
+                for theta3 in [theta3_abs, -theta3_abs] {
+                    let theta2 = p14.y.atan2(p14.x)
+                        - (self.a3 * theta3.sin()).atan2(self.a2 + self.a3 * theta3.cos());
+                    let t34 = (self.joint_transform(1, theta2) * self.joint_transform(2, theta3))
+                        .inverse()
+                        * t14;
+                    let x34 = t34.rotation * Vector3::x();
+                    let theta4 = x34.y.atan2(x34.x);
+                    solutions.push([theta1, theta2, theta3, theta4, theta5, theta6].map(wrap_angle));
+                }
+            }
+        }
+        solutions
+    }
+
+    pub fn configuration(&self, joint_positions: &[f64; 6]) -> UrConfiguration {
+        let frames = self.joint_frames(joint_positions);
+        let shoulder = frames[0].translation.vector;
+        let elbow = frames[1].translation.vector;
+        let wrist_1 = frames[2].translation.vector;
+        let wrist_center = frames[4].translation.vector;
+
+        let shoulder_axis = frames[0].rotation * Vector3::z();
+        let left = Vector3::new(-wrist_center.y, wrist_center.x, 0.0);
+
+        let arm = wrist_1 - shoulder;
+        let along = match arm.norm_squared() < EPSILON {
+            true => 0.0,
+            false => (elbow - shoulder).dot(&arm) / arm.norm_squared(),
+        };
+        let below_elbow = shoulder + along * arm;
+
+        UrConfiguration {
+            shoulder_left: shoulder_axis.dot(&left) > 0.0,
+            elbow_up: elbow.z > below_elbow.z,
+            wrist_up: (frames[3].rotation * Vector3::z()).z > 0.0,
+        }
+    }
+
+}
+
+/// The base frame of the controller in base_link, which the ur_description turns half
+/// a turn about z. The targets come in base_link, the DH parameters start in base.
+pub fn ur_base_in_base_link() -> Isometry3<f64> {
+    Isometry3::from_parts(
+        Translation3::identity(),
+        UnitQuaternion::from_axis_angle(&Vector3::z_axis(), PI),
+    )
+}
+
+fn wrap_angle(angle: f64) -> f64 {
+    let wrapped = angle.rem_euclid(TAU);
+    match wrapped > PI {
+        true => wrapped - TAU,
+        false => wrapped,
+    }
+}
+
+// The joints turn two full turns, so each angle has a twin a turn away
+fn turns(solution: &[f64; 6]) -> Vec<[f64; 6]> {
+    solution
+        .iter()
+        .enumerate()
+        .fold(vec![*solution], |turns, (joint, angle)| {
+            turns
+                .into_iter()
+                .flat_map(|turn| {
+                    [0.0, TAU, -TAU]
+                        .into_iter()
+                        .map(move |offset| angle + offset)
+                        .filter(|angle| angle.abs() <= TAU)
+                        .map(move |angle| {
+                            let mut turn = turn;
+                            turn[joint] = angle;
+                            turn
+                        })
+                })
+                .collect()
+        })
+}
+
+fn closest(solutions: impl Iterator<Item = [f64; 6]>, near: &[f64]) -> Option<[f64; 6]> {
+    let distance = |solution: &[f64; 6]| {
+        solution
+            .iter()
+            .zip(near)
+            .map(|(joint, near)| (joint - near).powi(2))
+            .sum::<f64>()
+    };
+    solutions.min_by(|a, b| distance(a).total_cmp(&distance(b)))
+}
+
+/// The solution closest to the given joint positions, like the qnear of get_inverse_kin.
+/// Every joint is taken the turn that is closest.
+pub fn closest_solution(solutions: &[[f64; 6]], near: &[f64]) -> Option<[f64; 6]> {
+    closest(solutions.iter().flat_map(turns), near)
+}
+
+/// The analytical counterpart of solve_inverse_kinematics, for a target in base_link
+/// like the rest of the transform tree. It can't get stuck, so only the first seed
+/// matters, the solution closest to it is picked among the ones within the joint
+/// limits. With a configuration only the solutions in that configuration count.
+pub fn solve_ur_inverse_kinematics(
+    dh: &UrDh,
+    target_in_base_link: &Isometry3<f64>,
+    tcp_in_faceplate: &Isometry3<f64>,
+    seeds: &[Vec<f64>],
+    configuration: Option<&UrConfiguration>,
+    joint_limits: &[crate::JointLimit],
+) -> Result<Vec<f64>, String> {
+    let flange_in_base =
+        ur_base_in_base_link().inverse() * target_in_base_link * tcp_in_faceplate.inverse();
+    let solutions = dh.inverse_kinematics(&flange_in_base);
+    if solutions.is_empty() {
+        return Err("the target is out of reach, there is no analytical solution".to_string());
+    }
+    let solutions = match configuration {
+        Some(configuration) => solutions
+            .into_iter()
+            .filter(|solution| dh.configuration(solution) == *configuration)
+            .collect(),
+        None => solutions,
+    };
+    if solutions.is_empty() {
+        return Err(format!("there is no solution in the configuration {:?}", configuration));
+    }
+    // Every turn of every solution, a twin can be within the limits when the other is not
+    let solutions = solutions
+        .iter()
+        .flat_map(turns)
+        .filter(|solution| {
+            joint_limits.is_empty() || crate::check_joint_limits(solution, joint_limits).is_ok()
+        })
+        .collect::<Vec<[f64; 6]>>();
+    let solution = match seeds.first() {
+        Some(near) => closest(solutions.into_iter(), near),
+        None => solutions.first().copied(),
+    };
+    solution
+        .map(|solution| solution.to_vec())
+        .ok_or("every solution is outside of the joint limits".to_string())
+}
+
+#[test]
+fn test_ur_inverse_kinematics() {
+    // Close enough to the base for all eight
+    let joint_positions = [
+        [0.5, -1.9, 2.2, -1.8, -1.3, 0.3],
+        [-2.1, -0.9, 1.9, -2.4, 1.2, -2.9],
+        [1.0, -2.2, 2.4, 1.5, 0.7, 3.0],
+    ];
+    for ur_type in ["ur3", "ur5", "ur10", "ur3e", "ur5e", "ur10e", "ur16e", "ur20"] {
+        let dh = UrDh::from_ur_type(ur_type).unwrap();
+        for joint_positions in joint_positions {
+            let target = dh.forward_kinematics(&joint_positions);
+            let solutions = dh.inverse_kinematics(&target);
+            assert_eq!(solutions.len(), 8, "{ur_type} {joint_positions:?}");
+
+            let mut configurations = vec![];
+            for solution in &solutions {
+                let reached = dh.forward_kinematics(solution);
+                assert!((reached.translation.vector - target.translation.vector).norm() < 1e-9);
+                assert!(reached.rotation.angle_to(&target.rotation) < 1e-9);
+                let configuration = dh.configuration(solution);
+                assert!(!configurations.contains(&configuration), "{ur_type} {solution:?}");
+                configurations.push(configuration);
+            }
+
+            // The configuration of the joints that the pose came from finds them again
+            let configuration = dh.configuration(&joint_positions);
+            let selected = solutions
+                .iter()
+                .find(|solution| dh.configuration(solution) == configuration)
+                .unwrap();
+            let closest = closest_solution(&solutions, &joint_positions).unwrap();
+            for i in 0..6 {
+                assert!((wrap_angle(selected[i] - joint_positions[i])).abs() < 1e-6);
+                assert!((closest[i] - joint_positions[i]).abs() < 1e-6);
+            }
+        }
+    }
+    assert_eq!(UrDh::from_ur_type("ur7"), None);
+
+    // Further out, wrist 1 can't be reached on the other side of the wrist
+    let dh = UrDh::from_ur_type("ur3").unwrap();
+    let solutions = dh.inverse_kinematics(&dh.forward_kinematics(&[0.3, -1.2, 1.4, -1.9, -1.5, 0.4]));
+    assert_eq!(solutions.len(), 4);
+
+    let dh = UrDh::from_ur_type("ur5e").unwrap();
+    let out_of_reach = Isometry3::translation(2.0, 0.0, 0.5);
+    assert!(dh.inverse_kinematics(&out_of_reach).is_empty());
+    assert!(
+        solve_ur_inverse_kinematics(&dh, &out_of_reach, &Isometry3::identity(), &[], None, &[]).is_err()
+    );
+
+    // A turn away, but within the two turns of the joint
+    let near = [0.3 - TAU, -1.2, 1.4, -1.9, -1.5, 0.4 - TAU];
+    let target = dh.forward_kinematics(&[0.3, -1.2, 1.4, -1.9, -1.5, 0.4]);
+    let target_in_base_link = ur_base_in_base_link() * target;
+    let solve = |near: &[f64], configuration, joint_limits: &[crate::JointLimit]| {
+        solve_ur_inverse_kinematics(
+            &dh,
+            &target_in_base_link,
+            &Isometry3::identity(),
+            &[near.to_vec()],
+            configuration,
+            joint_limits,
+        )
+    };
+    let solution = solve(&near, None, &[]).unwrap();
+    for i in 0..6 {
+        assert!((solution[i] - near[i]).abs() < 1e-6);
+    }
+
+    // The twin within the limits, even though the one outside is closer
+    let configuration = dh.configuration(&[0.3, -1.2, 1.4, -1.9, -1.5, 0.4]);
+    let limit = |lower: f64, upper: f64| crate::JointLimit {
+        name: "joint".to_string(),
+        lower,
+        upper,
+        velocity: 3.0,
+    };
+    let mut joint_limits = vec![limit(-TAU, TAU); 6];
+    joint_limits[0] = limit(-PI, PI);
+    let solution = solve(&near, Some(&configuration), &joint_limits).unwrap();
+    assert!((solution[0] - 0.3).abs() < 1e-6);
+    assert!((solution[5] - near[5]).abs() < 1e-6);
+    joint_limits[0] = limit(-PI, -3.0);
+    joint_limits[1] = limit(-PI, -3.0);
+    assert!(solve(&near, Some(&configuration), &joint_limits).is_err());
+
+    // The configuration wins over the seed
+    let elbow_down = UrConfiguration {
+        elbow_up: !configuration.elbow_up,
+        ..configuration
+    };
+    let solution = solve(&near, Some(&elbow_down), &[]).unwrap();
+    assert_eq!(dh.configuration(&solution.clone().try_into().unwrap()), elbow_down);
+    let reached = dh.forward_kinematics(&solution.try_into().unwrap());
+    assert!((reached.translation.vector - target.translation.vector).norm() < 1e-9);
+}
+
+#[test]
+fn test_ur_inverse_kinematics_in_urdf() {
+    // The ur5e as the ur_description puts it together, base_link_inertia and base
+    // are both turned half a turn from base_link
+    let joint = |name: &str, parent: &str, child: &str, xyz: &str, rpy: &str| {
+        format!(
+            r#"<joint name="{name}" type="revolute">
+                <parent link="{parent}"/>
+                <child link="{child}"/>
+                <origin xyz="{xyz}" rpy="{rpy}"/>
+                <axis xyz="0 0 1"/>
+                <limit lower="-6.28" upper="6.28" effort="1.0" velocity="3.0"/>
+            </joint>"#
+        )
+    };
+    let fixed = |name: &str, parent: &str, child: &str, rpy: &str| {
+        format!(
+            r#"<joint name="{name}" type="fixed">
+                <parent link="{parent}"/>
+                <child link="{child}"/>
+                <origin xyz="0 0 0" rpy="{rpy}"/>
+            </joint>"#
+        )
+    };
+    let links = [
+        "base_link",
+        "base_link_inertia",
+        "base",
+        "shoulder_link",
+        "upper_arm_link",
+        "forearm_link",
+        "wrist_1_link",
+        "wrist_2_link",
+        "wrist_3_link",
+        "flange",
+        "tool0",
+    ];
+    let urdf = format!(
+        r#"<robot name="ur5e">{}{}{}{}{}{}{}{}{}{}{}</robot>"#,
+        links.iter().map(|link| format!(r#"<link name="{link}"/>"#)).collect::<String>(),
+        fixed("base_link-base_link_inertia", "base_link", "base_link_inertia", "0 0 3.141592653589793"),
+        fixed("base_link-base_fixed_joint", "base_link", "base", "0 0 3.141592653589793"),
+        joint("shoulder_pan_joint", "base_link_inertia", "shoulder_link", "0 0 0.1625", "0 0 0"),
+        joint("shoulder_lift_joint", "shoulder_link", "upper_arm_link", "0 0 0", "1.570796327 0 0"),
+        joint("elbow_joint", "upper_arm_link", "forearm_link", "-0.425 0 0", "0 0 0"),
+        joint("wrist_1_joint", "forearm_link", "wrist_1_link", "-0.3922 0 0.1333", "0 0 0"),
+        joint("wrist_2_joint", "wrist_1_link", "wrist_2_link", "0 -0.0997 0", "1.570796327 0 0"),
+        joint(
+            "wrist_3_joint",
+            "wrist_2_link",
+            "wrist_3_link",
+            "0 0.0996 0",
+            "1.570796326589793 3.141592653589793 3.141592653589793",
+        ),
+        fixed("wrist_3-flange", "wrist_3_link", "flange", "0 -1.5707963267948966 -1.5707963267948966"),
+        fixed("flange-tool0", "flange", "tool0", "1.5707963267948966 0 1.5707963267948966"),
+    );
+    let arm = crate::arm_from_urdf(&urdf, "tool0").unwrap();
+    let dh = UrDh::from_ur_type("ur5e").unwrap();
+
+    let joint_positions = [0.5, -1.9, 2.2, -1.8, -1.3, 0.3];
+    arm.set_joint_positions(&joint_positions).unwrap();
+    arm.update_transforms();
+    let base_link = arm.find_link("base_link").unwrap().world_transform().unwrap();
+    let tool0_in_base_link = base_link.inverse() * arm.end_transform();
+
+    let solution = solve_ur_inverse_kinematics(
+        &dh,
+        &tool0_in_base_link,
+        &Isometry3::identity(),
+        &[joint_positions.to_vec()],
+        None,
+        &[],
+    )
+    .unwrap();
+    for i in 0..6 {
+        assert!((solution[i] - joint_positions[i]).abs() < 1e-6, "{solution:?}");
+    }
+}
@@ -10,12 +10,37 @@ pub const RSP_AND_PHOTONEO_PAYLOAD: &str = "3.29,[0.008,0.003,0.082],[0.0,0.0,0.
 pub mod core;
 pub use core::structs::*;
 pub use core::state::*;
+pub use core::payload_identification::*;
+pub use core::speed_scaling::*;
+pub use core::relative_motion::*;
+pub use core::joint_jog::*;
+pub use core::backend::*;
+pub use core::state_age::*;
+pub use core::joint_states::*;
+pub use core::mirroring::*;
+pub use core::motion::*;
+pub use core::inverse_kinematics::*;
+pub use core::ur_kinematics::*;
 
 pub mod ros;
 pub use ros::action_client::*;
-// pub use ros::dashboard_client::*;
+pub use ros::dashboard_client::*;
 // pub use ros::control_ghost::*;
 pub use ros::robot_state_publisher::*;
 pub use ros::ur_script_driver::*;
 pub use ros::urdf_parsing::*;
-pub use ros::joint_subscriber::*;
\ No newline at end of file
+pub use ros::joint_subscriber::*;
+pub use ros::script_backend::*;
+#[cfg(any(test, feature = "mock"))]
+pub use ros::mock_script_server::*;
+pub use ros::static_transforms::*;
+pub use ros::forward_kinematics::*;
+
+pub mod ur;
+pub use ur::address::*;
+pub use ur::dashboard::*;
+pub use ur::primary_interface::*;
+pub use ur::protective_stop::*;
+pub use ur::robot_status::*;
+pub use ur::rtde::*;
+pub use ur::script_sender::*;
\ No newline at end of file
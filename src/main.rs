@@ -25,7 +25,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     };
     let urdf_dir = std::env::var("URDF_DIR").expect("URDF_DIR is not set");
     let templates_dir = std::env::var("TEMPLATES_DIR").expect("TEMPLATES_DIR is not set");
-    let _override_host = match std::env::var("OVERRIDE_HOST") {
+    let override_host = match std::env::var("OVERRIDE_HOST") {
         Ok(val_str) => match val_str.to_lowercase().parse::<bool>() {
             Ok(b_val) => b_val,
             Err(e) => {
@@ -44,19 +44,154 @@ async fn main() -> Result<(), Box<dyn Error>> {
         std::env::var("OVERRIDE_HOST_ADDRESS").expect("OVERRIDE_HOST_ADDRESS is not set");
     let ur_address = std::env::var("UR_ADDRESS").expect("UR_ADDRESS is not set");
 
+    let default_speed_limits = SpeedLimits::default();
+    let speed_limits = SpeedLimits {
+        max_joint_accelleration: value_from_env(
+            "MAX_JOINT_ACCELLERATION",
+            default_speed_limits.max_joint_accelleration,
+        ),
+        max_joint_velocity: value_from_env(
+            "MAX_JOINT_VELOCITY",
+            default_speed_limits.max_joint_velocity,
+        ),
+        max_tool_accelleration: value_from_env(
+            "MAX_TOOL_ACCELLERATION",
+            default_speed_limits.max_tool_accelleration,
+        ),
+        max_tool_velocity: value_from_env("MAX_TOOL_VELOCITY", default_speed_limits.max_tool_velocity),
+    };
+
     let mut path = PathBuf::from(&urdf_dir);
     path.push("ur.urdf.xacro");
 
     let urdf_path = path.to_string_lossy().to_string();
 
+    // Robots that share a Redis need their own frames, e.g. TF_PREFIX=r1_. The prefix is
+    // empty by default since ur_script_driver publishes the unprefixed joint names.
+    let default_frames = FrameParameters::default();
+    let frames = FrameParameters {
+        tf_prefix: std::env::var("TF_PREFIX").unwrap_or(default_frames.tf_prefix),
+        parent_frame_id: std::env::var("BASE_PARENT_FRAME_ID").unwrap_or(default_frames.parent_frame_id),
+        base_in_parent: match std::env::var("BASE_IN_PARENT") {
+            Ok(pose) => match pose_from_string(&pose) {
+                Some(pose) => pose,
+                None => {
+                    log::error!(target: &&format!("r2r_ur_controller"), "Failed to parse BASE_IN_PARENT: {}", pose);
+                    log::error!(target: &&format!("r2r_ur_controller"), "Attaching the robot without an offset.");
+                    default_frames.base_in_parent
+                }
+            },
+            Err(_) => default_frames.base_in_parent,
+        },
+    };
+
     let mut params = URDFParameters::default();
+    params.tf_prefix = frames.tf_prefix.clone();
 
     params.description_file = urdf_path.clone(); //format!("{}/src/description/urdf/ur.urdf.xacro", manifest_dir);
+    let ur_type = params.ur_type.clone();
+    let tf_prefix = params.tf_prefix.clone();
     let urdf = match convert_xacro_to_urdf(params) {
         Some(urdf) => urdf,
         None => panic!("Failed to parse urdf."),
     };
 
+    let joint_limits = match joint_limits_from_urdf(&urdf, &tf_prefix) {
+        Ok(joint_limits) => joint_limits,
+        Err(e) => panic!("Failed to get joint limits from the urdf: {}", e),
+    };
+
+    let joint_names = match joint_names_from_urdf(&urdf, &tf_prefix) {
+        Ok(joint_names) => joint_names,
+        Err(e) => panic!("Failed to get the joint names from the urdf: {}", e),
+    };
+
+    let arm = match arm_from_urdf(&urdf, &frames.faceplate_id()) {
+        Ok(arm) => arm,
+        Err(e) => panic!("Failed to get the arm from the urdf: {}", e),
+    };
+
+    // Link poses from our own forward kinematics instead of the robot_state_publisher
+    let internal_fk = value_from_env("INTERNAL_FK", false);
+    let kinematics = match RobotKinematics::from_urdf(&urdf, &joint_names) {
+        Ok(kinematics) => kinematics,
+        Err(e) => panic!("Failed to get the kinematics from the urdf: {}", e),
+    };
+
+    let default_settle = SettleParameters::default();
+    let settle = SettleParameters {
+        settled_velocity: value_from_env("SETTLED_VELOCITY", default_settle.settled_velocity),
+        settle_time: value_from_env("SETTLE_TIME", default_settle.settle_time),
+        wait_for_settled: value_from_env("WAIT_FOR_SETTLED", default_settle.wait_for_settled),
+        settle_timeout: value_from_env("SETTLE_TIMEOUT", default_settle.settle_timeout),
+    };
+
+    let default_protective_stop_recovery = ProtectiveStopRecovery::default();
+    let protective_stop_recovery = ProtectiveStopRecovery {
+        enabled: value_from_env(
+            "AUTO_PROTECTIVE_STOP_RECOVERY",
+            default_protective_stop_recovery.enabled,
+        ),
+        max_recoveries_per_hour: value_from_env(
+            "MAX_PROTECTIVE_STOP_RECOVERIES_PER_HOUR",
+            default_protective_stop_recovery.max_recoveries_per_hour,
+        ),
+    };
+
+    let default_rtde_config = RtdeConfig::default();
+    let rtde_config = RtdeConfig {
+        frequency: value_from_env("RTDE_FREQUENCY", default_rtde_config.frequency),
+        decimation: value_from_env("RTDE_DECIMATION", default_rtde_config.decimation),
+        input_registers: match std::env::var("RTDE_INPUT_REGISTERS") {
+            Ok(mapping) => match RtdeInputRegister::parse_mapping(&mapping) {
+                Ok(input_registers) => input_registers,
+                Err(e) => {
+                    log::error!(target: &&format!("r2r_ur_controller"), "Failed to parse RTDE_INPUT_REGISTERS: {}", e);
+                    log::error!(target: &&format!("r2r_ur_controller"), "Not writing any input registers.");
+                    default_rtde_config.input_registers
+                }
+            },
+            Err(_) => default_rtde_config.input_registers,
+        },
+        input_rate: value_from_env("RTDE_INPUT_RATE", default_rtde_config.input_rate),
+    };
+
+    let default_mirror_config = MirrorConfig::default();
+    let mirror_config = MirrorConfig {
+        joint_state_rate: value_from_env("JOINT_STATE_RATE", default_mirror_config.joint_state_rate),
+        tf_rate: value_from_env("TF_RATE", default_mirror_config.tf_rate),
+        joint_deadband: value_from_env("JOINT_DEADBAND", default_mirror_config.joint_deadband),
+        translation_deadband: value_from_env(
+            "TRANSLATION_DEADBAND",
+            default_mirror_config.translation_deadband,
+        ),
+        rotation_deadband: value_from_env("ROTATION_DEADBAND", default_mirror_config.rotation_deadband),
+    };
+
+    let script_backend = match std::env::var("SCRIPT_BACKEND").as_deref() {
+        Ok("direct") => ScriptBackend::Direct(DirectScriptConfig {
+            host_address: match override_host {
+                true => override_host_address.clone(),
+                false => local_address_towards(&ur_address).unwrap_or(override_host_address.clone()),
+            },
+            port: value_from_env("SCRIPT_CALLBACK_PORT", 0),
+        }),
+        _ => ScriptBackend::Ros,
+    };
+
+    let robot_parameters = RobotParameters {
+        ur_type,
+        speed_limits,
+        joint_limits,
+        protective_stop_recovery: protective_stop_recovery.clone(),
+        script_backend: script_backend.clone(),
+        frames: frames.clone(),
+        max_state_age: value_from_env("MAX_STATE_AGE", DEFAULT_MAX_STATE_AGE),
+        settle: settle.clone(),
+        script_timeout: value_from_env("SCRIPT_TIMEOUT", DEFAULT_SCRIPT_TIMEOUT),
+        ik_urdf: value_from_env("IK_PRECHECK", false).then(|| urdf.clone()),
+    };
+
     let ctx = r2r::Context::create()?;
     let node = r2r::Node::create(ctx, NODE_ID, "")?;
     let arc_node = Arc::new(Mutex::new(node));
@@ -66,6 +201,28 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .unwrap()
         .subscribe::<TFMessage>("tf", QosProfile::volatile(QosProfile::default()))?;
 
+    // Published once, late joiners only get them with transient local durability
+    let tf_static_subscriber = arc_node
+        .lock()
+        .unwrap()
+        .subscribe::<TFMessage>("tf_static", QosProfile::transient_local(QosProfile::default()))?;
+
+    let (tf_publisher, tf_static_publisher) = match internal_fk {
+        true => (
+            Some(
+                arc_node
+                    .lock()
+                    .unwrap()
+                    .create_publisher::<TFMessage>("tf", QosProfile::default())?,
+            ),
+            Some(arc_node.lock().unwrap().create_publisher::<TFMessage>(
+                "tf_static",
+                QosProfile::transient_local(QosProfile::default()),
+            )?),
+        ),
+        false => (None, None),
+    };
+
     let joint_subsc = arc_node
         .lock()
         .unwrap()
@@ -76,9 +233,25 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let gripper_state = generate_gripper_interface_state("g1");
     let state = state.extend(gripper_state, true);
 
-    let connection_manager = ConnectionManager::new().await;
-    StateManager::set_state(&mut connection_manager.get_connection().await, &state).await;
-    let con_arc = Arc::new(connection_manager);
+    let con_arc = Arc::new(ConnectionManager::new().await);
+    let mut con = con_arc.get_connection().await;
+
+    // Payloads identified or stored at runtime outlive a restart, the default
+    // library is only written when there is none in Redis yet
+    let payload_library_key = format!("{}_payload_library", robot_id);
+    let stored_payload_library =
+        StateManager::get_state_for_keys(&mut con, &vec![payload_library_key.clone()])
+            .await
+            .and_then(|stored| stored.get_value(&payload_library_key, "r2r_ur_controller"))
+            .filter(|library| matches!(library, SPValue::String(StringOrUnknown::String(_))));
+    let state = match stored_payload_library {
+        Some(library) => {
+            state.extend(state_from_values(vec![(payload_library_key, library)]), true)
+        }
+        None => state,
+    };
+
+    StateManager::set_state(&mut con, &state).await;
 
     let templates: tera::Tera = {
         let tera = match tera::Tera::new(&format!("{}/*.script", templates_dir)) {
@@ -105,12 +278,35 @@ async fn main() -> Result<(), Box<dyn Error>> {
     }
 
 
+    let diagnostics = RobotDiagnostics::default();
+    let diagnostics_clone = diagnostics.clone();
+    let robot_id_clone = robot_id.clone();
+    let ur_address_clone = ur_address.clone();
+    tokio::task::spawn(async move {
+        match primary_interface_monitor(&robot_id_clone, &ur_address_clone, &diagnostics_clone).await {
+            Ok(()) => (),
+            Err(e) => {
+                log::error!(target: &&format!("main robot runner"), "failed with: {}", e)
+            }
+        }
+    });
+
     let arc_node_clone: Arc<Mutex<r2r::Node>> = arc_node.clone();
     let con_arc_clone = con_arc.clone();
     let robot_id_clone = robot_id.clone();
     let ur_address_clone = ur_address.clone();
     tokio::task::spawn(async move {
-        match action_client(&ur_address_clone, &robot_id_clone, arc_node_clone, &con_arc_clone, &templates).await {
+        match action_client(
+            &ur_address_clone,
+            &robot_id_clone,
+            arc_node_clone,
+            &con_arc_clone,
+            &templates,
+            &robot_parameters,
+            &diagnostics,
+        )
+        .await
+        {
             Ok(()) => (),
             Err(e) => {
                 log::error!(target: &&format!("main robot runner"), "failed with: {}", e)
@@ -118,10 +314,15 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     });
 
+    let arc_node_clone: Arc<Mutex<r2r::Node>> = arc_node.clone();
     let con_arc_clone = con_arc.clone();
     let robot_id_clone = robot_id.clone();
+    let dashboard_backend = match std::env::var("DASHBOARD_BACKEND").as_deref() {
+        Ok("ros") => DashboardBackend::Ros,
+        _ => DashboardBackend::Native(ur_address.clone()),
+    };
     tokio::task::spawn(async move {
-        match joint_subscriber(&robot_id_clone, joint_subsc, &con_arc_clone).await {
+        match dashboard_client(&robot_id_clone, arc_node_clone, &con_arc_clone, dashboard_backend).await {
             Ok(()) => (),
             Err(e) => {
                 log::error!(target: &&format!("main robot runner"), "failed with: {}", e)
@@ -131,8 +332,16 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let con_arc_clone = con_arc.clone();
     let robot_id_clone = robot_id.clone();
+    let ur_address_clone = ur_address.clone();
     tokio::task::spawn(async move {
-        match robot_state_to_redis(&robot_id_clone, tf_subscriber, &con_arc_clone).await {
+        match robot_status_to_redis(
+            &robot_id_clone,
+            &ur_address_clone,
+            &con_arc_clone,
+            &protective_stop_recovery,
+        )
+        .await
+        {
             Ok(()) => (),
             Err(e) => {
                 log::error!(target: &&format!("main robot runner"), "failed with: {}", e)
@@ -140,16 +349,147 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     });
 
+    let con_arc_clone = con_arc.clone();
+    let robot_id_clone = robot_id.clone();
+    let ur_address_clone = ur_address.clone();
+    let rtde_config_clone = rtde_config.clone();
     tokio::task::spawn(async move {
-        ur_script_driver(
-            Some(ur_address),
-            Some(override_host_address)
+        match rtde_to_redis(&robot_id_clone, &ur_address_clone, &con_arc_clone, &rtde_config_clone).await {
+            Ok(()) => (),
+            Err(e) => {
+                log::error!(target: &&format!("main robot runner"), "failed with: {}", e)
+            }
+        }
+    });
+
+    let con_arc_clone = con_arc.clone();
+    let robot_id_clone = robot_id.clone();
+    let ur_address_clone = ur_address.clone();
+    tokio::task::spawn(async move {
+        match redis_to_rtde_inputs(&robot_id_clone, &ur_address_clone, &con_arc_clone, &rtde_config).await {
+            Ok(()) => (),
+            Err(e) => {
+                log::error!(target: &&format!("main robot runner"), "failed with: {}", e)
+            }
+        }
+    });
+
+    let con_arc_clone = con_arc.clone();
+    let robot_id_clone = robot_id.clone();
+    let mirror_config_clone = mirror_config.clone();
+    tokio::task::spawn(async move {
+        match joint_subscriber(
+            &robot_id_clone,
+            &joint_names,
+            &arm,
+            &mirror_config_clone,
+            &settle,
+            joint_subsc,
+            &con_arc_clone,
         )
         .await
-        .unwrap()
+        {
+            Ok(()) => (),
+            Err(e) => {
+                log::error!(target: &&format!("main robot runner"), "failed with: {}", e)
+            }
+        }
     });
 
-    tokio::task::spawn(async move { robot_state_publisher(&urdf, "").await.unwrap() });
+    let con_arc_clone = con_arc.clone();
+    let robot_id_clone = robot_id.clone();
+    let frames_clone = frames.clone();
+    let mirror_config_clone = mirror_config.clone();
+    tokio::task::spawn(async move {
+        match forward_kinematics_to_redis(
+            &robot_id_clone,
+            &kinematics,
+            &frames_clone,
+            &mirror_config_clone,
+            internal_fk,
+            tf_publisher,
+            tf_static_publisher,
+            &con_arc_clone,
+        )
+        .await
+        {
+            Ok(()) => (),
+            Err(e) => {
+                log::error!(target: &&format!("main robot runner"), "failed with: {}", e)
+            }
+        }
+    });
+
+    let con_arc_clone = con_arc.clone();
+    let robot_id_clone = robot_id.clone();
+    let urdf_clone = urdf.clone();
+    tokio::task::spawn(async move {
+        match robot_state_to_redis(
+            &robot_id_clone,
+            &urdf_clone,
+            &frames,
+            &mirror_config,
+            internal_fk,
+            tf_subscriber,
+            &con_arc_clone,
+        )
+        .await
+        {
+            Ok(()) => (),
+            Err(e) => {
+                log::error!(target: &&format!("main robot runner"), "failed with: {}", e)
+            }
+        }
+    });
+
+    let con_arc_clone = con_arc.clone();
+    let robot_id_clone = robot_id.clone();
+    tokio::task::spawn(async move {
+        match state_age_to_redis(&robot_id_clone, &con_arc_clone).await {
+            Ok(()) => (),
+            Err(e) => {
+                log::error!(target: &&format!("main robot runner"), "failed with: {}", e)
+            }
+        }
+    });
+
+    let con_arc_clone = con_arc.clone();
+    let robot_id_clone = robot_id.clone();
+    let urdf_clone = urdf.clone();
+    // E.g. preparation/scenario/transforms/scenario_2
+    let scenario_dir = std::env::var("SCENARIO_TRANSFORMS_DIR").ok();
+    tokio::task::spawn(async move {
+        match static_transforms_to_redis(
+            &robot_id_clone,
+            &urdf_clone,
+            scenario_dir,
+            tf_static_subscriber,
+            &con_arc_clone,
+        )
+        .await
+        {
+            Ok(()) => (),
+            Err(e) => {
+                log::error!(target: &&format!("main robot runner"), "failed with: {}", e)
+            }
+        }
+    });
+
+    // The direct backend talks to the robot itself
+    if script_backend == ScriptBackend::Ros {
+        tokio::task::spawn(async move {
+            ur_script_driver(
+                Some(ur_address),
+                Some(override_host_address)
+            )
+            .await
+            .unwrap()
+        });
+    }
+
+    if !internal_fk {
+        tokio::task::spawn(async move { robot_state_publisher(&urdf, "").await.unwrap() });
+    }
 
     // let mut ghost_params = URDFParameters::default();
     // ghost_params.tf_prefix = "ghost_".to_string();
@@ -181,3 +521,24 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+fn value_from_env<T>(name: &str, default: T) -> T
+where
+    T: std::str::FromStr + std::fmt::Display,
+    T::Err: std::fmt::Display,
+{
+    match std::env::var(name) {
+        Ok(val_str) => match val_str.to_lowercase().parse::<T>() {
+            Ok(val) => val,
+            Err(e) => {
+                log::error!(target: &&format!("r2r_ur_controller"), "Failed to parse {} value '{}': {}", name, val_str, e);
+                log::error!(target: &&format!("r2r_ur_controller"), "Setting {} to {}.", name, default);
+                default
+            }
+        },
+        Err(_) => {
+            log::info!(target: &&format!("r2r_ur_controller"), "{} is not set, using {}.", name, default);
+            default
+        }
+    }
+}
@@ -7,8 +7,8 @@ use std::{
 // use std::io;
 
 use futures::StreamExt;
+use k::nalgebra::UnitQuaternion;
 use micro_sp::*;
-use r2r::ur_script_msgs::action::ExecuteScript;
 // use serde::{Deserialize, Serialize};
 
 // use crate::core::structs::{transform_to_string, CommandType, Payload};
@@ -20,20 +20,28 @@ pub static DEFAULT_BASEFRAME_ID: &'static str = "base_link"; // base_link if sim
 pub static DEFAULT_FACEPLATE_ID: &'static str = "tool0";
 // pub static DEFAULT_TCP_ID: &'static str = "svt_tcp";
 pub static DEFAULT_ROOT_FRAME_ID: &'static str = "world";
+pub static DEFAULT_IDENTIFIED_PAYLOAD_ID: &'static str = "identified";
 
 pub async fn action_client(
-    _ur_address: &str,
+    ur_address: &str,
     robot_name: &str,
     // gripper_id: &str,
     arc_node: Arc<Mutex<r2r::Node>>,
     connection_manager: &Arc<ConnectionManager>,
     templates: &tera::Tera,
+    robot_parameters: &RobotParameters,
+    diagnostics: &RobotDiagnostics,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let log_target = &format!("{robot_name}_action_client");
-    let client = arc_node
-        .lock()
-        .unwrap()
-        .create_action_client::<ExecuteScript::Action>(&format!("ur_script"))?;
+    let backend = robot_backend(ur_address, &arc_node, robot_parameters)?;
+    // The solver moves the joints of the chain, so the action client has one of its own
+    let ik_arm = match &robot_parameters.ik_urdf {
+        Some(urdf) => Some(arm_from_urdf(
+            urdf,
+            &robot_parameters.frames.faceplate_id(),
+        )?),
+        None => None,
+    };
     // let waiting_for_server = r2r::Node::is_available(&client)?;
 
     let mut timer =
@@ -69,10 +77,19 @@ pub async fn action_client(
         format!("{}_blend_radius", robot_name),
         format!("{}_use_joint_positions", robot_name),
         format!("{}_joint_positions", robot_name),
+        format!("{}_joint_states", robot_name),
+        format!("{}_joint_names", robot_name),
+        format!("{}_joint_states_timestamp", robot_name),
+        format!("{}_tf_timestamp", robot_name),
+        format!("{}_joint_index", robot_name),
+        format!("{}_joint_delta", robot_name),
+        format!("{}_relative_joint_positions", robot_name),
         format!("{}_use_preferred_joint_config", robot_name),
         format!("{}_preferred_joint_config", robot_name),
         format!("{}_use_payload", robot_name),
         format!("{}_payload", robot_name),
+        format!("{}_payload_library", robot_name),
+        format!("{}_identified_payload_id", robot_name),
         format!("{}_baseframe_id", robot_name),
         format!("{}_faceplate_id", robot_name),
         format!("{}_goal_feature_id", robot_name),
@@ -81,6 +98,7 @@ pub async fn action_client(
         format!("{}_force_threshold", robot_name),
         format!("{}_use_relative_pose", robot_name),
         format!("{}_relative_pose", robot_name),
+        format!("{}_relative_pose_frame_id", robot_name),
         format!("{}_force_feedback", robot_name),
         format!("{}_reset_request_mechanism", robot_name),
         // format!("{}_gripper_velocity", robot_name),
@@ -91,8 +109,18 @@ pub async fn action_client(
     .map(|k| k.to_string())
     .collect();
 
+    let context = RequestContext {
+        robot_name,
+        backend: backend.as_ref(),
+        ik_arm: ik_arm.as_ref(),
+        connection_manager,
+        templates,
+        robot_parameters,
+        diagnostics,
+    };
+
     let mut con = connection_manager.get_connection().await;
-    'scan: loop {
+    loop {
         timer.tick().await?;
         if let Err(_) = connection_manager.check_redis_health(&log_target).await {
             continue;
@@ -130,377 +158,710 @@ pub async fn action_client(
         if request_trigger {
             request_trigger = false;
             if request_state == ActionRequestState::Initial.to_string() {
-                // let gripper_velocity =
-                //     state.get_float_or_value(&format!("{robot_name}_gripper_velocity"), 100.0, &log_target);
-
-                // let gripper_force =
-                //     state.get_float_or_value(&format!("{robot_name}_gripper_force"), 100.0, &log_target);
-
-                // let gripper_ref_pos_percentage = state.get_int_or_default_to_zero(
-                //     &format!("{robot_name}_gripper_ref_pos_percentage"),
-                //     &log_target,
-                // );
+                request_state = match handle_request(&context, &state).await {
+                    Ok(request_state) => request_state,
+                    // The request stays pending and is picked up again on the next scan
+                    Err(RequestError::Retry) => continue,
+                    Err(RequestError::Failed(e)) => {
+                        r2r::log_error!(&format!("{}_ur_controller", robot_name), "{}.", e);
+                        ActionRequestState::Failed.to_string()
+                    }
+                };
+            }
 
-                let command_type = state.get_string_or_default_to_unknown(
-                    &format!("{robot_name}_command_type"),
-                    &log_target,
-                );
+            StateManager::set_sp_value(
+                &mut con,
+                &format!("{robot_name}_request_state"),
+                &request_state.to_spvalue(),
+            )
+            .await;
+            StateManager::set_sp_value(
+                &mut con,
+                &format!("{robot_name}_request_trigger"),
+                &request_trigger.to_spvalue(),
+            )
+            .await;
+        }
+    }
+}
 
-                let accelleration = state.get_float_or_default_to_zero(
-                    &format!("{robot_name}_accelleration"),
-                    &log_target,
-                );
+// What every request needs from the action client.
+struct RequestContext<'a> {
+    robot_name: &'a str,
+    backend: &'a dyn RobotBackend,
+    ik_arm: Option<&'a k::SerialChain<f64>>,
+    connection_manager: &'a Arc<ConnectionManager>,
+    templates: &'a tera::Tera,
+    robot_parameters: &'a RobotParameters,
+    diagnostics: &'a RobotDiagnostics,
+}
 
-                let velocity = state
-                    .get_float_or_default_to_zero(&format!("{robot_name}_velocity"), &log_target);
+enum RequestError {
+    // Something the request needs is not in Redis yet, e.g. a transform
+    Retry,
+    // The request can not be sent as it is
+    Failed(String),
+}
 
-                let global_acceleration_scaling = state.get_float_or_default_to_zero(
-                    &format!("{robot_name}_global_acceleration_scaling"),
-                    &log_target,
-                );
+impl From<String> for RequestError {
+    fn from(e: String) -> Self {
+        RequestError::Failed(e)
+    }
+}
 
-                let global_velocity_scaling = state.get_float_or_default_to_zero(
-                    &format!("{robot_name}_global_velocity_scaling"),
-                    &log_target,
-                );
+/// Turns the state into a script, runs it and returns the new request state. Requests
+/// that are rejected before anything is sent come back as errors.
+async fn handle_request(
+    context: &RequestContext<'_>,
+    state: &State,
+) -> Result<String, RequestError> {
+    let RequestContext {
+        robot_name,
+        backend,
+        ik_arm,
+        connection_manager,
+        templates,
+        robot_parameters,
+        diagnostics,
+    } = *context;
+    let log_target = &format!("{robot_name}_action_client");
+    let mut con = connection_manager.get_connection().await;
+    let request_started = SystemTime::now();
+    StateManager::set_sp_value(
+        &mut con,
+        &format!("{robot_name}_failure_diagnostics"),
+        &SPValue::String(StringOrUnknown::UNKNOWN),
+    )
+    .await;
+    // let gripper_velocity =
+    //     state.get_float_or_value(&format!("{robot_name}_gripper_velocity"), 100.0, &log_target);
+
+    // let gripper_force =
+    //     state.get_float_or_value(&format!("{robot_name}_gripper_force"), 100.0, &log_target);
+
+    // let gripper_ref_pos_percentage = state.get_int_or_default_to_zero(
+    //     &format!("{robot_name}_gripper_ref_pos_percentage"),
+    //     &log_target,
+    // );
 
-                let use_execution_time = state.get_bool_or_default_to_false(
-                    &format!("{robot_name}_use_execution_time"),
-                    &log_target,
-                );
+    let command_type =
+        state.get_string_or_default_to_unknown(&format!("{robot_name}_command_type"), &log_target);
+
+    // A dead driver leaves the last joint states and transforms behind,
+    // the arm should not be moved based on those.
+    let moves_arm = !NON_MOTION_COMMANDS.contains(&command_type.as_str());
+    if moves_arm {
+        let timestamps = [
+            format!("{robot_name}_joint_states_timestamp"),
+            format!("{robot_name}_tf_timestamp"),
+        ]
+        .iter()
+        .map(|key| timestamp_from_value(state.get_value(key, &log_target)))
+        .collect::<Vec<Option<i64>>>();
+        check_state_age(
+            state_age_ms(&timestamps, SystemTime::now()),
+            robot_parameters.max_state_age,
+        )
+        .map_err(|e| format!("Refusing to move, {}", e))?;
+    }
 
-                let execution_time = state.get_float_or_default_to_zero(
-                    &format!("{robot_name}_execution_time"),
-                    &log_target,
-                );
+    let accelleration =
+        state.get_float_or_default_to_zero(&format!("{robot_name}_accelleration"), &log_target);
 
-                let use_blend_radius = state.get_bool_or_default_to_false(
-                    &format!("{robot_name}_use_blend_radius"),
-                    &log_target,
-                );
+    let velocity =
+        state.get_float_or_default_to_zero(&format!("{robot_name}_velocity"), &log_target);
 
-                let blend_radius = state.get_float_or_default_to_zero(
-                    &format!("{robot_name}_blend_radius"),
-                    &log_target,
-                );
+    let global_acceleration_scaling = state.get_float_or_value(
+        &format!("{robot_name}_global_acceleration_scaling"),
+        1.0,
+        &log_target,
+    );
 
-                let use_joint_positions = state.get_bool_or_default_to_false(
-                    &format!("{robot_name}_use_joint_positions"),
-                    &log_target,
-                );
+    let global_velocity_scaling = state.get_float_or_value(
+        &format!("{robot_name}_global_velocity_scaling"),
+        1.0,
+        &log_target,
+    );
 
-                let force_threshold = state.get_float_or_default_to_zero(
-                    &format!("{robot_name}_force_threshold"),
-                    &log_target,
-                );
+    let use_execution_time = state
+        .get_bool_or_default_to_false(&format!("{robot_name}_use_execution_time"), &log_target);
 
-                // let gripper_position = state.get_int_or_default_to_zero(
-                //     &format!("{robot_name}_gripper_reference_position"),
-                //     &log_target,
-                // );
-
-                let joint_positions = if let Some(value) =
-                    state.get_value(&format!("{robot_name}_joint_positions"), &log_target)
-                {
-                    match value {
-                        micro_sp::SPValue::Array(array_or_unknown) => match array_or_unknown {
-                            ArrayOrUnknown::UNKNOWN => SAFE_HOME_JOINT_STATE.to_vec(),
-                            ArrayOrUnknown::Array(values) => values
-                                .iter()
-                                .enumerate()
-                                .map(|(i, val)| match val {
-                                    micro_sp::SPValue::Float64(float_or_unknown) => {
-                                        match float_or_unknown {
-                                            FloatOrUnknown::UNKNOWN => SAFE_HOME_JOINT_STATE[i],
-                                            FloatOrUnknown::Float64(ordered_float) => {
-                                                ordered_float.into_inner()
-                                            }
-                                        }
-                                    }
-                                    _ => SAFE_HOME_JOINT_STATE[i],
-                                })
-                                .collect(),
-                        },
-                        _ => SAFE_HOME_JOINT_STATE.to_vec(),
-                    }
-                } else {
-                    SAFE_HOME_JOINT_STATE.to_vec()
-                };
+    let execution_time =
+        state.get_float_or_default_to_zero(&format!("{robot_name}_execution_time"), &log_target);
 
-                let use_preferred_joint_config = state.get_bool_or_default_to_false(
-                    &format!("{robot_name}_use_preferred_joint_config"),
-                    &log_target,
-                );
+    let use_blend_radius =
+        state.get_bool_or_default_to_false(&format!("{robot_name}_use_blend_radius"), &log_target);
 
-                let preferred_joint_config = if let Some(value) =
-                    state.get_value(&format!("{robot_name}_preferred_joint_config"), &log_target)
-                {
-                    match value {
-                        micro_sp::SPValue::Array(array_or_unknown) => match array_or_unknown {
-                            ArrayOrUnknown::UNKNOWN => SAFE_HOME_JOINT_STATE.to_vec(),
-                            ArrayOrUnknown::Array(values) => values
-                                .iter()
-                                .enumerate()
-                                .map(|(i, val)| match val {
-                                    micro_sp::SPValue::Float64(float_or_unknown) => {
-                                        match float_or_unknown {
-                                            FloatOrUnknown::UNKNOWN => SAFE_HOME_JOINT_STATE[i],
-                                            FloatOrUnknown::Float64(ordered_float) => {
-                                                ordered_float.into_inner()
-                                            }
-                                        }
-                                    }
-                                    _ => SAFE_HOME_JOINT_STATE[i],
-                                })
-                                .collect(),
-                        },
-                        _ => SAFE_HOME_JOINT_STATE.to_vec(),
-                    }
-                } else {
-                    SAFE_HOME_JOINT_STATE.to_vec()
-                };
+    let blend_radius =
+        state.get_float_or_default_to_zero(&format!("{robot_name}_blend_radius"), &log_target);
 
-                let use_payload = state.get_bool_or_default_to_false(
-                    &format!("{robot_name}_use_payload"),
-                    &log_target,
-                );
+    let mut use_joint_positions = state
+        .get_bool_or_default_to_false(&format!("{robot_name}_use_joint_positions"), &log_target);
 
-                let payload = state.get_string_or_value(
-                    &format!("{robot_name}_payload"),
-                    Payload::default().to_string(),
-                    &log_target,
-                );
+    let force_threshold =
+        state.get_float_or_default_to_zero(&format!("{robot_name}_force_threshold"), &log_target);
 
-                let baseframe_id = state.get_string_or_value(
-                    &format!("{robot_name}_baseframe_id"),
-                    DEFAULT_BASEFRAME_ID.to_string(),
-                    &log_target,
-                );
+    // let gripper_position = state.get_int_or_default_to_zero(
+    //     &format!("{robot_name}_gripper_reference_position"),
+    //     &log_target,
+    // );
 
-                let faceplate_id = state.get_string_or_value(
-                    &format!("{robot_name}_faceplate_id"),
-                    DEFAULT_FACEPLATE_ID.to_string(),
-                    &log_target,
+    let mut joint_positions = if let Some(value) =
+        state.get_value(&format!("{robot_name}_joint_positions"), &log_target)
+    {
+        match value {
+            micro_sp::SPValue::Array(array_or_unknown) => match array_or_unknown {
+                ArrayOrUnknown::UNKNOWN => SAFE_HOME_JOINT_STATE.to_vec(),
+                ArrayOrUnknown::Array(values) => values
+                    .iter()
+                    .enumerate()
+                    .map(|(i, val)| match val {
+                        micro_sp::SPValue::Float64(float_or_unknown) => match float_or_unknown {
+                            FloatOrUnknown::UNKNOWN => SAFE_HOME_JOINT_STATE[i],
+                            FloatOrUnknown::Float64(ordered_float) => ordered_float.into_inner(),
+                        },
+                        _ => SAFE_HOME_JOINT_STATE[i],
+                    })
+                    .collect(),
+            },
+            _ => SAFE_HOME_JOINT_STATE.to_vec(),
+        }
+    } else {
+        SAFE_HOME_JOINT_STATE.to_vec()
+    };
+
+    let use_preferred_joint_config = state.get_bool_or_default_to_false(
+        &format!("{robot_name}_use_preferred_joint_config"),
+        &log_target,
+    );
+
+    let preferred_joint_config = if let Some(value) =
+        state.get_value(&format!("{robot_name}_preferred_joint_config"), &log_target)
+    {
+        match value {
+            micro_sp::SPValue::Array(array_or_unknown) => match array_or_unknown {
+                ArrayOrUnknown::UNKNOWN => SAFE_HOME_JOINT_STATE.to_vec(),
+                ArrayOrUnknown::Array(values) => values
+                    .iter()
+                    .enumerate()
+                    .map(|(i, val)| match val {
+                        micro_sp::SPValue::Float64(float_or_unknown) => match float_or_unknown {
+                            FloatOrUnknown::UNKNOWN => SAFE_HOME_JOINT_STATE[i],
+                            FloatOrUnknown::Float64(ordered_float) => ordered_float.into_inner(),
+                        },
+                        _ => SAFE_HOME_JOINT_STATE[i],
+                    })
+                    .collect(),
+            },
+            _ => SAFE_HOME_JOINT_STATE.to_vec(),
+        }
+    } else {
+        SAFE_HOME_JOINT_STATE.to_vec()
+    };
+
+    let use_payload =
+        state.get_bool_or_default_to_false(&format!("{robot_name}_use_payload"), &log_target);
+
+    let payload = state.get_string_or_value(
+        &format!("{robot_name}_payload"),
+        Payload::default().to_string(),
+        &log_target,
+    );
+
+    let mut payload_library =
+        match serde_json::from_str::<PayloadLibrary>(&state.get_string_or_value(
+            &format!("{robot_name}_payload_library"),
+            "{}".to_string(),
+            &log_target,
+        )) {
+            Ok(library) => library,
+            Err(e) => {
+                r2r::log_warn!(
+                    &format!("{}_ur_controller", robot_name),
+                    "Failed to parse the payload library with: {}, using the default one.",
+                    e
                 );
+                default_payload_library()
+            }
+        };
 
-                let goal_feature_id = state.get_string_or_default_to_unknown(
-                    &format!("{robot_name}_goal_feature_id"),
-                    &log_target,
-                );
+    let identified_payload_id = state.get_string_or_value(
+        &format!("{robot_name}_identified_payload_id"),
+        DEFAULT_IDENTIFIED_PAYLOAD_ID.to_string(),
+        &log_target,
+    );
+
+    // Never paste an unchecked payload into the script, a malformed
+    // one would only surface as a runtime error on the controller.
+    let payload = if use_payload {
+        resolve_payload(&payload, &payload_library)
+            .map_err(|e| format!("Failed to parse payload '{}' with: {}", payload, e))?
+            .to_string()
+    } else {
+        Payload::default().to_string()
+    };
+
+    let baseframe_id = state.get_string_or_value(
+        &format!("{robot_name}_baseframe_id"),
+        robot_parameters.frames.baseframe_id(),
+        &log_target,
+    );
+
+    let faceplate_id = state.get_string_or_value(
+        &format!("{robot_name}_faceplate_id"),
+        robot_parameters.frames.faceplate_id(),
+        &log_target,
+    );
+
+    let goal_feature_id = state
+        .get_string_or_default_to_unknown(&format!("{robot_name}_goal_feature_id"), &log_target);
+
+    let tcp_id =
+        state.get_string_or_default_to_unknown(&format!("{robot_name}_tcp_id"), &log_target);
+
+    let _root_frame_id = state.get_string_or_value(
+        &format!("{robot_name}_root_frame_id"),
+        DEFAULT_ROOT_FRAME_ID.to_string(),
+        &log_target,
+    );
+
+    let use_relative_pose =
+        state.get_bool_or_default_to_false(&format!("{robot_name}_use_relative_pose"), &log_target);
+
+    // let relative_pose = state.get_array_or_default_to_empty(
+    //     &format!("{robot_name}_relative_pose"),
+    //     "p[0.0, 0.0, 0.0, 0.0, 0.0, 0.0]".to_string(),
+    //     log_target,
+    // );
 
-                let tcp_id = state
-                    .get_string_or_default_to_unknown(&format!("{robot_name}_tcp_id"), &log_target);
+    let relative_pose_frame_id = state.get_string_or_default_to_unknown(
+        &format!("{robot_name}_relative_pose_frame_id"),
+        &log_target,
+    );
 
-                let _root_frame_id = state.get_string_or_value(
-                    &format!("{robot_name}_root_frame_id"),
-                    DEFAULT_ROOT_FRAME_ID.to_string(),
-                    &log_target,
-                );
+    let relative_pose = relative_pose_from_value(
+        state.get_value(&format!("{robot_name}_relative_pose"), &log_target),
+    )
+    .map_err(|e| format!("Failed to read the relative pose with: {}", e))?;
 
-                let use_relative_pose = state.get_bool_or_default_to_false(
-                    &format!("{robot_name}_use_relative_pose"),
-                    &log_target,
-                );
+    let arm_joint_states = match (
+        strings_from_value(state.get_value(&format!("{robot_name}_joint_names"), &log_target)),
+        floats_from_value(state.get_value(&format!("{robot_name}_joint_states"), &log_target)),
+    ) {
+        (Some(joint_names), Some(joint_states)) => arm_joint_positions(
+            &joint_names,
+            &joint_states,
+            &robot_parameters.frames.tf_prefix,
+        ),
+        _ => None,
+    };
+
+    // Joint space jogs are turned into a movej with absolute targets,
+    // so that they can be checked against the joint limits first.
+    if JOINT_JOG_COMMANDS.contains(&command_type.as_str()) {
+        let joint_states = arm_joint_states
+            .clone()
+            .ok_or_else(|| "No valid joint states to jog from".to_string())?;
+        let target = if command_type == CommandType::JogJoint.to_string() {
+            match state.get_value(&format!("{robot_name}_joint_index"), &log_target) {
+                Some(SPValue::Int64(IntOrUnknown::Int64(joint_index))) => {
+                    let joint_delta = state.get_float_or_default_to_zero(
+                        &format!("{robot_name}_joint_delta"),
+                        &log_target,
+                    );
+                    jog_joint(&joint_states, joint_index, joint_delta)
+                }
+                _ => Err("the joint index is not set".to_string()),
+            }
+        } else {
+            match floats_from_value(state.get_value(
+                &format!("{robot_name}_relative_joint_positions"),
+                &log_target,
+            )) {
+                Some(deltas) => move_joints_relative(&joint_states, &deltas),
+                None => Err("the relative joint positions are not set".to_string()),
+            }
+        };
+        joint_positions = target
+            .and_then(|target| {
+                check_joint_limits(&target, &robot_parameters.joint_limits).map(|_| target)
+            })
+            .map_err(|e| format!("Joint jog rejected: {}", e))?;
+        use_joint_positions = true;
+    }
 
-                // let relative_pose = state.get_array_or_default_to_empty(
-                //     &format!("{robot_name}_relative_pose"),
-                //     "p[0.0, 0.0, 0.0, 0.0, 0.0, 0.0]".to_string(),
-                //     log_target,
-                // );
-
-                let relative_pose = if let Some(value) =
-                    state.get_value(&format!("{robot_name}_relative_pose"), &log_target)
-                {
-                    match value {
-                        micro_sp::SPValue::Array(array_or_unknown) => match array_or_unknown {
-                            ArrayOrUnknown::UNKNOWN => [0.0, 0.0, 0.0, 0.0, 0.0, 0.0].to_vec(),
-                            ArrayOrUnknown::Array(values) => values
-                                .iter()
-                                .enumerate()
-                                .map(|(i, val)| match val {
-                                    micro_sp::SPValue::Float64(float_or_unknown) => {
-                                        match float_or_unknown {
-                                            FloatOrUnknown::UNKNOWN => [0.0, 0.0, 0.0, 0.0, 0.0, 0.0][i],
-                                            FloatOrUnknown::Float64(ordered_float) => {
-                                                ordered_float.into_inner()
-                                            }
-                                        }
-                                    }
-                                    _ => [0.0, 0.0, 0.0, 0.0, 0.0, 0.0][i],
-                                })
-                                .collect(),
-                        },
-                        _ => [0.0, 0.0, 0.0, 0.0, 0.0, 0.0].to_vec(),
-                    }
-                } else {
-                    [0.0, 0.0, 0.0, 0.0, 0.0, 0.0].to_vec()
-                };
+    // if command_type != "gripper_move"
+    //     && command_type != "gripper_activate"
+    //     && command_type != "gripper_open"
+    //     && command_type != "gripper_close"
+    // {
+
+    let mut target_in_base = transform_to_string(&SPTransformStamped {
+        active_transform: true,
+        enable_transform: true,
+        time_stamp: SystemTime::now(),
+        parent_frame_id: "".to_string(),
+        child_frame_id: "".to_string(),
+        transform: SPTransform::default(),
+        metadata: MapOrUnknown::UNKNOWN,
+    });
+    let mut tcp_in_faceplate = target_in_base.clone();
+    if !use_joint_positions && !use_relative_pose {
+        target_in_base =
+            TransformsManager::lookup_transform(&mut con, &baseframe_id, &goal_feature_id)
+                .await
+                .map(|transform| transform_to_string(&transform))
+                .map_err(|_| RequestError::Retry)?;
+
+        tcp_in_faceplate = TransformsManager::lookup_transform(&mut con, &faceplate_id, &tcp_id)
+            .await
+            .map(|transform| transform_to_string(&transform))
+            .map_err(|_| RequestError::Retry)?;
+    }
 
-                // if command_type != "gripper_move"
-                //     && command_type != "gripper_activate"
-                //     && command_type != "gripper_open"
-                //     && command_type != "gripper_close"
-                // {
-
-                let mut target_in_base = transform_to_string(&SPTransformStamped {
-                    active_transform: true,
-                    enable_transform: true,
-                    time_stamp: SystemTime::now(),
-                    parent_frame_id: "".to_string(),
-                    child_frame_id: "".to_string(),
-                    transform: SPTransform::default(),
-                    metadata: MapOrUnknown::UNKNOWN,
-                });
-                let mut tcp_in_faceplate = target_in_base.clone();
-                if !use_joint_positions && !use_relative_pose {
-                    target_in_base = match TransformsManager::lookup_transform(
-                        &mut con,
-                        &baseframe_id,
-                        &goal_feature_id,
-                    )
+    let tcp_frame_id = match tcp_id.as_str() {
+        "unknown" => faceplate_id.clone(),
+        _ => tcp_id.clone(),
+    };
+
+    // Relative moves are resolved here against the transform tree, so that
+    // the script gets an absolute target which can be checked before sending.
+    if !use_joint_positions && use_relative_pose {
+        let tcp_in_base =
+            TransformsManager::lookup_transform(&mut con, &baseframe_id, &tcp_frame_id)
+                .await
+                .map(|transform| isometry_from_sp_transform(&transform.transform))
+                .map_err(|_| format!("Failed to look up {} in {}", tcp_frame_id, baseframe_id))?;
+        if tcp_frame_id != faceplate_id {
+            tcp_in_faceplate =
+                TransformsManager::lookup_transform(&mut con, &faceplate_id, &tcp_frame_id)
                     .await
-                    {
-                        Ok(transform) => transform_to_string(&transform),
-                        Err(_) => continue 'scan,
-                    };
-
-                    tcp_in_faceplate =
-                        match TransformsManager::lookup_transform(&mut con, &faceplate_id, &tcp_id)
-                            .await
-                        {
-                            Ok(transform) => transform_to_string(&transform),
-                            Err(_) => continue 'scan,
-                        };
+                    .map(|transform| transform_to_string(&transform))
+                    .map_err(|_| {
+                        format!("Failed to look up {} in {}", tcp_frame_id, faceplate_id)
+                    })?;
+        }
+        let frame_in_base =
+            match RelativeFrame::from_frame_id(&relative_pose_frame_id, &baseframe_id) {
+                RelativeFrame::Tool => tcp_in_base.rotation,
+                RelativeFrame::Base => UnitQuaternion::identity(),
+                RelativeFrame::Frame(frame_id) => {
+                    TransformsManager::lookup_transform(&mut con, &baseframe_id, &frame_id)
+                        .await
+                        .map(|transform| isometry_from_sp_transform(&transform.transform).rotation)
+                        .map_err(|_| {
+                            format!("Failed to look up {} in {}", frame_id, baseframe_id)
+                        })?
                 }
+            };
+        let target = resolve_relative_pose(&tcp_in_base, &frame_in_base, relative_pose);
+        let tcp_offset = pose_from_string(&tcp_in_faceplate)
+            .map(isometry_from_pose)
+            .ok_or_else(|| format!("{} is not a pose", tcp_in_faceplate))?;
+        validate_target_in_base(&target, &tcp_offset, &robot_parameters.ur_type)
+            .map_err(|e| format!("Relative move rejected: {}", e))?;
+        target_in_base = pose_to_string(pose_from_isometry(&target));
+    }
 
-                let robot_command = RobotCommand {
-                    command_type,
-                    accelleration,
-                    velocity,
-                    global_acceleration_scaling,
-                    global_velocity_scaling,
-                    use_execution_time,
-                    execution_time,
-                    use_blend_radius,
-                    blend_radius,
-                    use_joint_positions,
-                    joint_positions,
-                    use_preferred_joint_config,
-                    preferred_joint_config,
-                    use_payload,
-                    payload,
-                    target_in_base,
-                    tcp_in_faceplate,
-                    force_threshold,
-                    relative_pose,
-                    // gripper_velocity,
-                    // gripper_force,
-                    // gripper_ref_pos_percentage,
-                };
-
-                let script = match generate_script(robot_name, robot_command, templates) {
-                    Ok(script) => script,
-                    Err(_) => {
-                        r2r::log_error!("robot", "Failed to generate UR Script.");
-
-                        continue 'scan;
+    // The controller finds out about an unreachable target only after the goal is
+    // sent, and then the planner gets no joint positions back. The solution goes
+    // into the script, so that the controller doesn't solve onto another branch.
+    if let Some(ik_arm) = ik_arm {
+        if !use_joint_positions && INVERSE_KINEMATICS_COMMANDS.contains(&command_type.as_str()) {
+            let seeds = use_preferred_joint_config
+                .then(|| preferred_joint_config.clone())
+                .into_iter()
+                .chain(arm_joint_states.clone())
+                .collect::<Vec<Vec<f64>>>();
+            let solution = match (
+                pose_from_string(&target_in_base),
+                pose_from_string(&tcp_in_faceplate),
+            ) {
+                (Some(target), Some(tcp)) => match UrDh::from_ur_type(&robot_parameters.ur_type) {
+                    // Closed form for the arms we know, it finds every solution, so
+                    // a miss means that the target can't be reached
+                    Some(dh) => {
+                        // The preferred joint config is more than a seed, the arm has
+                        // to stay in its shoulder, elbow and wrist configuration
+                        let configuration = match use_preferred_joint_config {
+                            true => <[f64; 6]>::try_from(preferred_joint_config.as_slice())
+                                .ok()
+                                .map(|preferred| dh.configuration(&preferred)),
+                            false => None,
+                        };
+                        solve_ur_inverse_kinematics(
+                            &dh,
+                            &isometry_from_pose(target),
+                            &isometry_from_pose(tcp),
+                            &seeds,
+                            configuration.as_ref(),
+                            &robot_parameters.joint_limits,
+                        )
+                        .map(Some)
                     }
-                };
-
-                let goal = ExecuteScript::Goal { script };
-
-                let (_goal_handle, result, mut feedback) = match client.send_goal_request(goal) {
-                    Ok(x) => match x.await {
-                        Ok(y) => y,
+                    // The jacobian solver can miss targets that are in reach, so the
+                    // controller gets the last word on those
+                    None => match solve_inverse_kinematics(
+                        ik_arm,
+                        &baseframe_id,
+                        &isometry_from_pose(target),
+                        &isometry_from_pose(tcp),
+                        &seeds,
+                    )
+                    .and_then(|solution| {
+                        check_joint_limits(&solution, &robot_parameters.joint_limits)
+                            .map(|_| solution)
+                    }) {
+                        Ok(solution) => Ok(Some(solution)),
                         Err(e) => {
-                            r2r::log_info!(
+                            r2r::log_warn!(
                                 &format!("{}_ur_controller", robot_name),
-                                "Could not send goal request."
+                                "No inverse kinematics solution, leaving it to the controller: {}.",
+                                e
                             );
-                            return Err(Box::new(e));
+                            Ok(None)
                         }
                     },
-                    Err(e) => {
-                        r2r::log_info!(
-                            &format!("{}_ur_controller", robot_name),
-                            "Did not get goal."
-                        );
-                        return Err(Box::new(e));
-                    }
-                };
+                },
+                _ => Err(format!("{} is not a pose", target_in_base)),
+            };
+            StateManager::set_sp_value(
+                &mut con,
+                &format!("{robot_name}_ik_joint_positions"),
+                &match &solution {
+                    Ok(Some(solution)) => solution.to_spvalue(),
+                    _ => SPValue::Array(ArrayOrUnknown::UNKNOWN),
+                },
+            )
+            .await;
+            if let Some(solution) =
+                solution.map_err(|e| format!("Inverse kinematics check failed: {}", e))?
+            {
+                joint_positions = solution;
+                use_joint_positions = true;
+            }
+        }
+    }
 
-                // Feedback that we can use to get data directly from the robot
-                let connection_manager_clone = connection_manager.clone();
-                tokio::spawn(async move {
-                    while let Some(msg) = feedback.next().await {
-                        println!("got feedback msg: {}", msg.feedback);
-                        let feedback_string = &msg.feedback;
-                        if let Some(value_str) = feedback_string.strip_prefix("FORCE: ") {
-                            if let Ok(force_data) = value_str.trim().parse::<f64>() {
-                                r2r::log_info!(
-                                    "ur_controller",
-                                    "Received Force Feedback: {}",
-                                    force_data
-                                );
-
-                                let mut con = connection_manager_clone.get_connection().await;
-                                let force_feedback = force_data;
-
-                                StateManager::set_sp_value(
-                                    &mut con,
-                                    "force_feedback",
-                                    &force_feedback.to_spvalue(),
-                                )
-                                .await;
-                            }
-                        }
-                    }
-                });
+    // The time of a time based move is held to the speed limits over its distance
+    let move_distance = match use_execution_time {
+        false => None,
+        true if JOINT_SPACE_COMMANDS.contains(&command_type.as_str()) => {
+            match (use_joint_positions, &arm_joint_states) {
+                (true, Some(arm_joint_states)) => Some(
+                    joint_positions
+                        .iter()
+                        .zip(arm_joint_states)
+                        .map(|(target, current)| (target - current).abs())
+                        .fold(0.0, f64::max),
+                ),
+                // A movej to a feature only has a joint target after inverse kinematics
+                _ => None,
+            }
+        }
+        true => {
+            let tcp_in_base =
+                TransformsManager::lookup_transform(&mut con, &baseframe_id, &tcp_frame_id)
+                    .await
+                    .ok()
+                    .map(|transform| isometry_from_sp_transform(&transform.transform));
+            match (tcp_in_base, pose_from_string(&target_in_base)) {
+                (Some(tcp_in_base), Some(target)) => Some(
+                    (isometry_from_pose(target).translation.vector
+                        - tcp_in_base.translation.vector)
+                        .norm(),
+                ),
+                _ => None,
+            }
+        }
+    };
+    if use_execution_time && move_distance.is_none() {
+        r2r::log_warn!(
+            &format!("{}_ur_controller", robot_name),
+            "The distance of the {} is unknown, its time is not held to the speed limits.",
+            command_type
+        );
+    }
 
-                match result.await {
-                    Ok((status, msg)) => match status {
-                        r2r::GoalStatus::Aborted => {
-                            r2r::log_error!(
-                                &format!("{}_ur_controller", robot_name),
-                                "Goal aborted, result is {}.",
-                                msg.ok
-                            );
-                            request_state = ActionRequestState::Failed.to_string();
-                        }
-                        _ => {
-                            r2r::log_info!(
-                                &format!("{}_ur_controller", robot_name),
-                                "Goal succeeded, result is {}.",
-                                msg.ok
-                            );
-                            request_state = ActionRequestState::Succeeded.to_string();
-                        }
-                    },
-                    Err(e) => {
-                        r2r::log_error!(
-                            &format!("{}_ur_controller", robot_name),
-                            "Goal failed with {}.",
-                            e
-                        );
-                        request_state = ActionRequestState::Failed.to_string();
-                    }
+    let identify_payload = command_type == CommandType::IdentifyPayload.to_string();
+
+    let mut robot_command = RobotCommand {
+        command_type,
+        accelleration,
+        velocity,
+        global_acceleration_scaling,
+        global_velocity_scaling,
+        use_execution_time,
+        execution_time,
+        use_blend_radius,
+        blend_radius,
+        use_joint_positions,
+        joint_positions,
+        use_preferred_joint_config,
+        preferred_joint_config,
+        use_payload,
+        payload,
+        target_in_base,
+        tcp_in_faceplate,
+        force_threshold,
+        relative_pose: relative_pose.to_vec(),
+        // gripper_velocity,
+        // gripper_force,
+        // gripper_ref_pos_percentage,
+    };
+
+    apply_speed_scaling(
+        &mut robot_command,
+        &robot_parameters.speed_limits,
+        move_distance,
+    )
+    .map_err(|e| format!("Failed to apply the speed scaling with: {}", e))?;
+
+    let script = generate_script(robot_name, robot_command, templates).map_err(|_| {
+        r2r::log_error!("robot", "Failed to generate UR Script.");
+        RequestError::Retry
+    })?;
+
+    // Feedback that we can use to get data directly from the robot
+    let (feedback_sender, mut feedback) = futures::channel::mpsc::unbounded::<String>();
+    let connection_manager_clone = connection_manager.clone();
+    tokio::spawn(async move {
+        while let Some(feedback_string) = feedback.next().await {
+            println!("got feedback msg: {}", feedback_string);
+            if let Some(value_str) = feedback_string.strip_prefix("FORCE: ") {
+                if let Ok(force_data) = value_str.trim().parse::<f64>() {
+                    r2r::log_info!("ur_controller", "Received Force Feedback: {}", force_data);
+
+                    let mut con = connection_manager_clone.get_connection().await;
+                    let force_feedback = force_data;
+
+                    StateManager::set_sp_value(
+                        &mut con,
+                        "force_feedback",
+                        &force_feedback.to_spvalue(),
+                    )
+                    .await;
                 }
             }
+        }
+    });
 
+    let mut wrench_samples: Vec<WrenchSample> = vec![];
+    let result = execute_script(backend, script, |feedback_string| {
+        if let Some(sample) = parse_wrench_feedback(&feedback_string) {
+            wrench_samples.push(sample);
+        }
+        let _ = feedback_sender.unbounded_send(feedback_string);
+    })
+    .await;
+
+    let mut request_state = request_state_after(&result, false);
+    match &result {
+        Ok(message) => {
+            r2r::log_info!(&format!("{}_ur_controller", robot_name), "{}", message);
+        }
+        Err(message) => {
+            r2r::log_error!(&format!("{}_ur_controller", robot_name), "{}", message);
+
+            // The robot explains itself on the primary interface at about the same time
+            tokio::time::sleep(std::time::Duration::from_millis(
+                UR_DIAGNOSTICS_GRACE_PERIOD,
+            ))
+            .await;
+            let robot_messages = diagnostics.since(request_started);
             StateManager::set_sp_value(
                 &mut con,
-                &format!("{robot_name}_request_state"),
-                &request_state.to_spvalue(),
+                &format!("{robot_name}_failure_diagnostics"),
+                &std::iter::once(message.clone())
+                    .chain(robot_messages)
+                    .collect::<Vec<String>>()
+                    .join("; ")
+                    .to_spvalue(),
             )
             .await;
+
+            // Tell the planner that this failure is being taken care of
+            if robot_parameters.protective_stop_recovery.enabled {
+                let protective_stop = wait_for_protective_stop(
+                    robot_name,
+                    connection_manager,
+                    2 * UR_ROBOT_STATUS_TICKER_RATE,
+                )
+                .await;
+                request_state = request_state_after(&result, protective_stop);
+            }
+        }
+    }
+
+    // The script ends with the last target, the arm can still be ringing
+    if robot_parameters.settle.wait_for_settled
+        && request_state == ActionRequestState::Succeeded.to_string()
+        && moves_arm
+    {
+        if let Err(e) = wait_until_settled(
+            robot_name,
+            connection_manager,
+            robot_parameters.settle.settle_timeout,
+        )
+        .await
+        {
+            r2r::log_error!(&format!("{}_ur_controller", robot_name), "{}", e);
             StateManager::set_sp_value(
                 &mut con,
-                &format!("{robot_name}_request_trigger"),
-                &request_trigger.to_spvalue(),
+                &format!("{robot_name}_failure_diagnostics"),
+                &e.to_spvalue(),
             )
             .await;
+            request_state = ActionRequestState::Failed.to_string();
+        }
+    }
+
+    // The script returns to its starting pose after the last reading,
+    // so all wrench samples have arrived by the time the goal succeeds.
+    if identify_payload && request_state == ActionRequestState::Succeeded.to_string() {
+        match estimate_payload(&wrench_samples) {
+            Ok(identified) => {
+                r2r::log_info!(
+                    &format!("{}_ur_controller", robot_name),
+                    "Identified payload '{}' as {}.",
+                    identified_payload_id,
+                    identified
+                );
+                StateManager::set_sp_value(
+                    &mut con,
+                    &format!("{robot_name}_identified_payload"),
+                    &identified.to_string().to_spvalue(),
+                )
+                .await;
+                payload_library.insert(identified_payload_id, identified);
+                match serde_json::to_string(&payload_library) {
+                    Ok(library) => {
+                        StateManager::set_sp_value(
+                            &mut con,
+                            &format!("{robot_name}_payload_library"),
+                            &library.to_spvalue(),
+                        )
+                        .await
+                    }
+                    Err(e) => r2r::log_error!(
+                        &format!("{}_ur_controller", robot_name),
+                        "Failed to serialize the payload library with: {}.",
+                        e
+                    ),
+                }
+            }
+            Err(e) => {
+                r2r::log_error!(
+                    &format!("{}_ur_controller", robot_name),
+                    "Payload identification failed with: {}.",
+                    e
+                );
+                request_state = ActionRequestState::Failed.to_string();
+            }
         }
     }
+
+    Ok(request_state)
 }
 
 fn generate_script(
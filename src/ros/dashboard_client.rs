@@ -3,26 +3,67 @@ use micro_sp::*;
 use r2r::ur_script_msgs::srv::DashboardCommand as DBCommand;
 use r2r::QosProfile;
 use std::sync::{Arc, Mutex};
-use tokio::sync::{mpsc, oneshot};
 
 use crate::*;
 
 pub const UR_DASHBOARD_SERVER_TICKER_RATE: u64 = 50;
 
+/// Who talks to the dashboard server of the robot.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DashboardBackend {
+    // The dashboard_command service of the external ur_script_driver
+    Ros,
+    // Our own connection to port 29999 of the robot at this address
+    Native(String),
+}
+
+enum DashboardConnection {
+    Ros(r2r::Client<DBCommand::Service>),
+    Native(DashboardClient),
+}
+
+// Whether the robot did what was asked, and what it said if we get to know that.
+// The dashboard_command service only passes on the former.
+struct DashboardAnswer {
+    success: bool,
+    answer: Option<String>,
+}
+
+impl DashboardConnection {
+    // Fails only when there is no answer at all.
+    async fn request(&mut self, cmd: &str) -> Result<DashboardAnswer, String> {
+        match self {
+            DashboardConnection::Ros(client) => {
+                let request = DBCommand::Request { cmd: cmd.to_string() };
+                match client.request(&request) {
+                    Ok(future) => match future.await {
+                        Ok(response) => Ok(DashboardAnswer {
+                            success: response.ok,
+                            answer: None,
+                        }),
+                        Err(e) => Err(format!("'{}' failed with {}.", cmd, e)),
+                    },
+                    Err(e) => Err(format!("'{}' failed with {}.", cmd, e)),
+                }
+            }
+            DashboardConnection::Native(client) => match client.send(cmd).await {
+                Ok(response) => Ok(DashboardAnswer {
+                    success: dashboard_request_accepted(cmd, &response),
+                    answer: Some(response),
+                }),
+                Err(e) => Err(format!("'{}' failed, {}.", cmd, e)),
+            },
+        }
+    }
+}
+
 pub async fn dashboard_client(
     robot_name: &str,
     arc_node: Arc<Mutex<r2r::Node>>,
-    state_mgmt: mpsc::Sender<StateManagement>, // instead of &Arc<Mutex<State>>
+    connection_manager: &Arc<ConnectionManager>,
+    backend: DashboardBackend,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    // let client = arc_node
-    //     .lock()
-    //     .unwrap()
-    //     .create_client::<DBCommand::Service>(&format!("{robot_name}_dashboard_server"), QosProfile::default())?;
-    let client = arc_node
-        .lock()
-        .unwrap()
-        .create_client::<DBCommand::Service>(&format!("dashboard_command"), QosProfile::default())?;
-    let waiting_for_server = r2r::Node::is_available(&client)?;
+    let log_target = &format!("{robot_name}_dashboard_client");
     let mut timer =
         arc_node
             .lock()
@@ -36,101 +77,149 @@ pub async fn dashboard_client(
         "Waiting for the {robot_name} dashboard server..."
     );
 
-    waiting_for_server.await?;
+    let mut dashboard = match backend {
+        DashboardBackend::Ros => {
+            let client = arc_node.lock().unwrap().create_client::<DBCommand::Service>(
+                &format!("dashboard_command"),
+                QosProfile::default(),
+            )?;
+            let waiting_for_server = r2r::Node::is_available(&client)?;
+            waiting_for_server.await?;
+            DashboardConnection::Ros(client)
+        }
+        DashboardBackend::Native(ur_address) => {
+            let mut client = DashboardClient::new(&ur_address);
+            loop {
+                match client.connect().await {
+                    Ok(_) => break,
+                    Err(_) => tokio::time::sleep(std::time::Duration::from_millis(1000)).await,
+                }
+            }
+            DashboardConnection::Native(client)
+        }
+    };
     r2r::log_info!(
         &format!("{robot_name}_dashboard_client"),
         "Robot {robot_name} dashboard server available."
     );
 
+    let keys: Vec<String> = vec![
+        format!("{}_dashboard_request_trigger", robot_name),
+        format!("{}_dashboard_request_state", robot_name),
+        format!("{}_dashboard_command_type", robot_name),
+        format!("{}_dashboard_program", robot_name),
+    ]
+    .iter()
+    .map(|k| k.to_string())
+    .collect();
+
+    let mut con = connection_manager.get_connection().await;
     loop {
-        let (response_tx, response_rx) = oneshot::channel();
-        state_mgmt
-            .send(StateManagement::GetState(response_tx))
-            .await?;
-        let state = response_rx.await?;
-
-        let request_trigger =
-            match state.get_value(&format!("{robot_name}_dashboard_request_trigger")) {
-                micro_sp::SPValue::Bool(value) => value,
-                _ => {
-                    r2r::log_error!(
-                        &format!("{robot_name}_dashboard_client"),
-                        "Couldn't get {} from the shared state.",
-                        &format!("{robot_name}_dashboard_request_trigger")
-                    );
-                    false
-                }
-            };
-        let mut request_state =
-            match state.get_value(&format!("{robot_name}_dashboard_request_state")) {
-                micro_sp::SPValue::String(value) => value,
-                _ => {
-                    r2r::log_error!(
-                        &format!("{robot_name}_dashboard_client"),
-                        "Couldn't get {} from the shared state.",
-                        &format!("{robot_name}_dashboard_request_state")
-                    );
-                    "unknown".to_string()
-                }
-            };
+        timer.tick().await?;
+        if let Err(_) = connection_manager.check_redis_health(&log_target).await {
+            continue;
+        }
+        let state = match StateManager::get_state_for_keys(&mut con, &keys).await {
+            Some(s) => s,
+            None => continue,
+        };
+
+        let mut request_trigger = state.get_bool_or_default_to_false(
+            &format!("{robot_name}_dashboard_request_trigger"),
+            &log_target,
+        );
+
+        let mut request_state = state.get_string_or_default_to_unknown(
+            &format!("{robot_name}_dashboard_request_state"),
+            &log_target,
+        );
 
         if request_trigger {
+            request_trigger = false;
             if request_state == ServiceRequestState::Initial.to_string() {
-                let command_type =
-                    match state.get_value(&format!("{robot_name}_dashboard_command_type")) {
-                        micro_sp::SPValue::String(value) => value,
-                        _ => {
-                            r2r::log_error!(
-                                &format!("{robot_name}_dashboard_client"),
-                                "Couldn't get {} from the shared state.",
-                                &format!("{robot_name}_dashboard_command_type")
-                            );
-                            DashboardCommandType::UNKNOWN.to_string()
-                        }
-                    };
+                let command_type = state.get_string_or_default_to_unknown(
+                    &format!("{robot_name}_dashboard_command_type"),
+                    &log_target,
+                );
 
-                let request = DBCommand::Request { cmd: command_type };
-                match client.request(&request) {
-                    Ok(future) => match future.await {
-                        Ok(response) => {
-                            if response.ok {
+                let program = state.get_string_or_default_to_unknown(
+                    &format!("{robot_name}_dashboard_program"),
+                    &log_target,
+                );
+
+                let cmd = command_type
+                    .parse::<DashboardCommandType>()
+                    .map_err(|e| format!("failed, {}.", e))
+                    .and_then(|command| {
+                        command.to_dashboard_request(&program).ok_or_else(|| {
+                            format!("'{}' can't be sent, no program is set.", command_type)
+                        })
+                    });
+                let response = match cmd {
+                    Ok(cmd) => dashboard.request(&cmd).await.map(|answer| (cmd, answer)),
+                    Err(e) => Err(e),
+                };
+
+                // Only what the robot said goes to Redis, nothing is made up for it
+                let answer = match response {
+                    Ok((cmd, DashboardAnswer { success, answer })) => {
+                        let said = answer.clone().unwrap_or_else(|| format!("ok: {}", success));
+                        match success {
+                            true => {
                                 r2r::log_info!(
                                     &format!("{robot_name}_dashboard_client"),
-                                    "Dashboard request succeeded."
+                                    "Dashboard request '{}' answered: {}",
+                                    cmd,
+                                    said
                                 );
                                 request_state = ServiceRequestState::Succeeded.to_string();
-                            } else {
-                                r2r::log_info!(
+                            }
+                            false => {
+                                r2r::log_error!(
                                     &format!("{robot_name}_dashboard_client"),
-                                    "Dashboard request failed."
+                                    "Dashboard request '{}' answered: {}",
+                                    cmd,
+                                    said
                                 );
                                 request_state = ServiceRequestState::Failed.to_string();
                             }
                         }
-                        Err(e) => {
-                            r2r::log_info!(
-                                &format!("{robot_name}_dashboard_client"),
-                                "Dashboard request failed with {e}."
-                            );
-                            request_state = ServiceRequestState::Failed.to_string();
-                        }
-                    },
+                        answer
+                    }
                     Err(e) => {
-                        r2r::log_info!(
+                        r2r::log_error!(
                             &format!("{robot_name}_dashboard_client"),
-                            "Dashboard request failed with {e}."
+                            "Dashboard request {}",
+                            e
                         );
                         request_state = ServiceRequestState::Failed.to_string();
+                        None
                     }
                 };
+
+                StateManager::set_sp_value(
+                    &mut con,
+                    &format!("{robot_name}_dashboard_response"),
+                    &match answer {
+                        Some(answer) => answer.to_spvalue(),
+                        None => SPValue::String(StringOrUnknown::UNKNOWN),
+                    },
+                )
+                .await;
             }
-            state_mgmt
-                .send(StateManagement::Set((
-                    format!("{robot_name}_dashboard_request_state"),
-                    request_state.to_spvalue(),
-                )))
-                .await?;
+
+            StateManager::set_sp_value(
+                &mut con,
+                &format!("{robot_name}_dashboard_request_state"),
+                &request_state.to_spvalue(),
+            )
+            .await;
+            StateManager::set_sp_value(
+                &mut con,
+                &format!("{robot_name}_dashboard_request_trigger"),
+                &request_trigger.to_spvalue(),
+            )
+            .await;
         }
-        timer.tick().await?;
     }
 }
@@ -0,0 +1,314 @@
+use k::nalgebra::Isometry3;
+use micro_sp::*;
+use r2r::builtin_interfaces::msg::Time;
+use r2r::geometry_msgs::msg::{Quaternion, Transform, TransformStamped, Vector3};
+use r2r::std_msgs::msg::Header;
+use r2r::tf2_msgs::msg::TFMessage;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::ros::robot_state_to_redis::link_to_sp_tf;
+use crate::*;
+
+/// Forward kinematics of the whole robot from the urdf, so that the link poses
+/// don't need the external robot_state_publisher.
+pub struct RobotKinematics {
+    chain: k::Chain<f64>,
+    // Where the moving joints of the chain are among the joint names
+    joint_indices: Vec<usize>,
+    // The parent and the child link of every joint
+    joint_links: HashMap<String, (String, String)>,
+}
+
+impl RobotKinematics {
+    pub fn from_urdf(urdf: &str, joint_names: &[String]) -> Result<RobotKinematics, String> {
+        let robot = urdf_rs::read_from_string(urdf).map_err(|e| e.to_string())?;
+        let chain = k::Chain::<f64>::from(&robot);
+        let joint_indices = chain
+            .iter_joints()
+            .filter(|joint| joint.is_movable())
+            .map(|joint| {
+                joint_names
+                    .iter()
+                    .position(|name| *name == joint.name)
+                    .ok_or(format!("the joint {} is not in the joint states", joint.name))
+            })
+            .collect::<Result<Vec<usize>, String>>()?;
+        let joint_links = robot
+            .joints
+            .iter()
+            .map(|joint| {
+                (
+                    joint.name.clone(),
+                    (joint.parent.link.clone(), joint.child.link.clone()),
+                )
+            })
+            .collect();
+        Ok(RobotKinematics {
+            chain,
+            joint_indices,
+            joint_links,
+        })
+    }
+
+    /// Takes the joint positions in the order of the joint names.
+    pub fn set_joint_positions(&self, positions: &[f64]) -> Result<(), String> {
+        let positions = self
+            .joint_indices
+            .iter()
+            .map(|i| positions.get(*i).copied())
+            .collect::<Option<Vec<f64>>>()
+            .ok_or(format!(
+                "expected {} joint positions, got {}",
+                self.joint_indices.len(),
+                positions.len()
+            ))?;
+        self.chain.set_joint_positions_clamped(&positions);
+        self.chain.update_transforms();
+        Ok(())
+    }
+
+    /// Every link in its parent link at the last joint positions, as the
+    /// robot_state_publisher puts them on /tf and /tf_static.
+    pub fn link_transforms(&self) -> Vec<LinkTransform> {
+        self.chain
+            .iter_joints()
+            .filter_map(|joint| {
+                let (parent, child) = self.joint_links.get(&joint.name)?;
+                let mut link = LinkTransform {
+                    parent_frame_id: parent.clone(),
+                    child_frame_id: child.clone(),
+                    translation: [0.0; 3],
+                    rotation: [0.0, 0.0, 0.0, 1.0],
+                    moving: joint.is_movable(),
+                };
+                link.set_isometry(&joint.local_transform());
+                Some(link)
+            })
+            .collect()
+    }
+
+    pub fn link_in_link(&self, parent_link: &str, child_link: &str) -> Option<Isometry3<f64>> {
+        let parent = self.chain.find_link(parent_link)?.world_transform()?;
+        let child = self.chain.find_link(child_link)?.world_transform()?;
+        Some(parent.inverse() * child)
+    }
+}
+
+fn stamp_from_millis(timestamp: i64) -> Time {
+    Time {
+        sec: (timestamp / 1000) as i32,
+        nanosec: ((timestamp % 1000) * 1_000_000) as u32,
+    }
+}
+
+fn link_to_tf(link: &LinkTransform, stamp: &Time) -> TransformStamped {
+    TransformStamped {
+        header: Header {
+            stamp: stamp.clone(),
+            frame_id: link.parent_frame_id.clone(),
+        },
+        child_frame_id: link.child_frame_id.clone(),
+        transform: Transform {
+            translation: Vector3 {
+                x: link.translation[0],
+                y: link.translation[1],
+                z: link.translation[2],
+            },
+            rotation: Quaternion {
+                x: link.rotation[0],
+                y: link.rotation[1],
+                z: link.rotation[2],
+                w: link.rotation[3],
+            },
+        },
+    }
+}
+
+/// Keeps {robot}_tcp_pose, the active tcp in the base frame, up to date from the
+/// mirrored joint states. With internal_fk it also stands in for the
+/// robot_state_publisher: the moving links go straight to Redis, stamped with the
+/// joint states, and out on the publishers for everyone else.
+pub async fn forward_kinematics_to_redis(
+    robot_name: &str,
+    kinematics: &RobotKinematics,
+    frames: &FrameParameters,
+    mirror_config: &MirrorConfig,
+    internal_fk: bool,
+    tf_publisher: Option<r2r::Publisher<TFMessage>>,
+    tf_static_publisher: Option<r2r::Publisher<TFMessage>>,
+    connection_manager: &Arc<ConnectionManager>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let log_target = &format!("{robot_name}_forward_kinematics");
+    let baseframe_id = frames.baseframe_id();
+    let faceplate_id = frames.faceplate_id();
+    let keys = vec![
+        format!("{robot_name}_joint_states"),
+        format!("{robot_name}_joint_states_timestamp"),
+        format!("{robot_name}_tcp_id"),
+    ];
+
+    // The same tree as in Redis, hung under the parent frame of the robot
+    let link_tree = || attach_link_tree(kinematics.link_transforms(), frames);
+
+    if let Some(tf_static_publisher) = &tf_static_publisher {
+        let stamp = stamp_from_millis(millis_since_epoch(SystemTime::now()));
+        tf_static_publisher.publish(&TFMessage {
+            transforms: link_tree()
+                .iter()
+                .filter(|link| !link.moving)
+                .map(|link| link_to_tf(link, &stamp))
+                .collect(),
+        })?;
+    }
+
+    let mut interval = tokio::time::interval(mirror_period(mirror_config.tf_rate));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let mut con = connection_manager.get_connection().await;
+    let mut computed: Option<(i64, String)> = None;
+    let mut written: HashMap<String, Isometry3<f64>> = HashMap::new();
+    let mut warned = false;
+    loop {
+        interval.tick().await;
+        if let Err(_) = connection_manager.check_redis_health(log_target).await {
+            continue;
+        }
+        let state = match StateManager::get_state_for_keys(&mut con, &keys).await {
+            Some(s) => s,
+            None => continue,
+        };
+        let timestamp = match timestamp_from_value(state.get_value(&keys[1], log_target)) {
+            Some(timestamp) => timestamp,
+            None => continue,
+        };
+        let tcp_id = state.get_string_or_default_to_unknown(&keys[2], log_target);
+        if computed.as_ref() == Some(&(timestamp, tcp_id.clone())) {
+            continue;
+        }
+        let joint_positions = match floats_from_value(state.get_value(&keys[0], log_target)) {
+            Some(joint_positions) => joint_positions,
+            None => continue,
+        };
+        if let Err(e) = kinematics.set_joint_positions(&joint_positions) {
+            if !warned {
+                r2r::log_warn!(log_target, "No forward kinematics, {}.", e);
+                warned = true;
+            }
+            continue;
+        }
+        computed = Some((timestamp, tcp_id.clone()));
+
+        if internal_fk {
+            let time_stamp = SystemTime::UNIX_EPOCH + Duration::from_millis(timestamp as u64);
+            let moved = link_tree()
+                .iter()
+                .filter(|link| link.moving)
+                .filter(|link| {
+                    transform_outside_deadband(
+                        written.get(&link.child_frame_id),
+                        &link.isometry(),
+                        mirror_config.translation_deadband,
+                        mirror_config.rotation_deadband,
+                    )
+                })
+                .map(|link| SPTransformStamped {
+                    time_stamp,
+                    ..link_to_sp_tf(link)
+                })
+                .collect::<Vec<SPTransformStamped>>();
+            if !moved.is_empty() {
+                TransformsManager::insert_transforms(&mut con, &moved).await?;
+                for sp_tf in moved {
+                    written.insert(sp_tf.child_frame_id, isometry_from_sp_transform(&sp_tf.transform));
+                }
+            }
+            // Also when nothing moved, a robot standing still is still fresh
+            StateManager::set_sp_value(
+                &mut con,
+                &format!("{robot_name}_tf_timestamp"),
+                &timestamp.to_spvalue(),
+            )
+            .await;
+        }
+
+        if let Some(tf_publisher) = &tf_publisher {
+            let stamp = stamp_from_millis(timestamp);
+            let message = TFMessage {
+                transforms: link_tree()
+                    .iter()
+                    .filter(|link| link.moving)
+                    .map(|link| link_to_tf(link, &stamp))
+                    .collect(),
+            };
+            if let Err(e) = tf_publisher.publish(&message) {
+                r2r::log_error!(log_target, "Failed to publish /tf with {}.", e);
+            }
+        }
+
+        // The tcps hang under the faceplate in the transform tree
+        let tcp_in_faceplate = match tcp_id.as_str() {
+            "unknown" => Some(Isometry3::identity()),
+            _ => TransformsManager::lookup_transform(&mut con, &faceplate_id, &tcp_id)
+                .await
+                .ok()
+                .map(|transform| isometry_from_sp_transform(&transform.transform)),
+        };
+        let tcp_pose = match (
+            kinematics.link_in_link(&baseframe_id, &faceplate_id),
+            tcp_in_faceplate,
+        ) {
+            (Some(faceplate_in_base), Some(tcp_in_faceplate)) => {
+                pose_to_string(pose_from_isometry(&(faceplate_in_base * tcp_in_faceplate)))
+                    .to_spvalue()
+            }
+            _ => SPValue::String(StringOrUnknown::UNKNOWN),
+        };
+        StateManager::set_sp_value(&mut con, &format!("{robot_name}_tcp_pose"), &tcp_pose).await;
+    }
+}
+
+#[test]
+fn test_robot_kinematics() {
+    let urdf = r#"<robot name="arm">
+        <link name="world"/>
+        <link name="base_link"/>
+        <link name="link_a"/>
+        <link name="tool0"/>
+        <joint name="world_joint" type="fixed">
+            <parent link="world"/>
+            <child link="base_link"/>
+            <origin xyz="0 0 1" rpy="0 0 0"/>
+        </joint>
+        <joint name="joint_a" type="revolute">
+            <parent link="base_link"/>
+            <child link="link_a"/>
+            <axis xyz="0 0 1"/>
+            <limit lower="-3.14" upper="3.14" effort="1.0" velocity="3.0"/>
+        </joint>
+        <joint name="flange" type="fixed">
+            <parent link="link_a"/>
+            <child link="tool0"/>
+            <origin xyz="1 0 0" rpy="0 0 0"/>
+        </joint>
+    </robot>"#;
+    let joint_names = vec!["finger_joint".to_string(), "joint_a".to_string()];
+    let kinematics = RobotKinematics::from_urdf(urdf, &joint_names).unwrap();
+    assert!(RobotKinematics::from_urdf(urdf, &joint_names[..1]).is_err());
+    assert!(kinematics.set_joint_positions(&[0.0]).is_err());
+
+    kinematics
+        .set_joint_positions(&[0.3, std::f64::consts::FRAC_PI_2])
+        .unwrap();
+    let links = kinematics.link_transforms();
+    assert_eq!(links.len(), 3);
+    let link_a = links.iter().find(|link| link.child_frame_id == "link_a").unwrap();
+    assert!(link_a.moving);
+    assert_eq!(link_a.parent_frame_id, "base_link");
+    assert!(links.iter().filter(|link| !link.moving).count() == 2);
+
+    let tool0_in_base = kinematics.link_in_link("base_link", "tool0").unwrap();
+    assert!((tool0_in_base.translation.vector - k::nalgebra::Vector3::new(0.0, 1.0, 0.0)).norm() < 1e-9);
+    let tool0_in_world = kinematics.link_in_link("world", "tool0").unwrap();
+    assert!((tool0_in_world.translation.z - 1.0).abs() < 1e-9);
+}
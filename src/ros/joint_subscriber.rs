@@ -4,33 +4,136 @@ use std::sync::Arc;
 use futures::{Stream, StreamExt};
 use micro_sp::ToSPValue;
 use micro_sp::*;
+use std::time::SystemTime;
 
+use crate::*;
+
+/// Mirrors the joint states to Redis at the joint_state_rate of the mirror config,
+/// whatever rate the driver publishes at. The timestamp is written on every tick with
+/// new messages, the joint states only when they moved outside of the deadband.
+/// Whether the arm is moving or settled, and how fast the faceplate goes, comes
+/// from the same stream.
 pub async fn joint_subscriber(
     robot_name: &str,
+    joint_names: &[String],
+    arm: &k::SerialChain<f64>,
+    mirror_config: &MirrorConfig,
+    settle: &SettleParameters,
     mut subscriber: impl Stream<Item = JointState> + Unpin,
     connection_manager: &Arc<ConnectionManager>,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let log_target = &format!("{robot_name}_joint_subscriber");
+    let mut joint_states = JointStates::new(joint_names.to_vec());
+    let mut warned = false;
+    // The newest stamp since the last write
+    let mut received: Option<SystemTime> = None;
+    let mut written_positions: Option<Vec<f64>> = None;
+    let mut written_velocities: Option<Vec<f64>> = None;
+    let mut motion_detector = MotionDetector::new(settle);
+    let mut motion: Option<MotionStatus> = None;
+    let mut written_motion: Option<MotionStatus> = None;
+    let arm_joints = arm_joint_indices(arm, joint_names);
+    if arm_joints.is_none() {
+        r2r::log_warn!(log_target, "The joints of the arm are not in the urdf, no tcp speed.");
+    }
+    let mut interval = tokio::time::interval(mirror_period(mirror_config.joint_state_rate));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
     let mut con = connection_manager.get_connection().await;
     loop {
-        match subscriber.next().await {
-            Some(message) => {
+        tokio::select! {
+            message = subscriber.next() => match message {
+                Some(message) => {
+                    let timestamp = time_from_stamp(message.header.stamp.sec, message.header.stamp.nanosec)
+                        .unwrap_or_else(SystemTime::now);
+
+                    // Other joints share the topic, e.g. those of a gripper or another robot
+                    if joint_states.update(&message.name, &message.position, &message.velocity, &message.effort) == 0 {
+                        if !warned {
+                            r2r::log_warn!(
+                                log_target,
+                                "None of the joints {:?} are in the urdf, expected {:?}.",
+                                message.name,
+                                joint_states.names
+                            );
+                            warned = true;
+                        }
+                        continue;
+                    }
+                    received = received.max(Some(timestamp));
+                    if let Some(joint_positions) = JointStates::complete(&joint_states.positions) {
+                        let joint_velocities = JointStates::complete(&joint_states.velocities);
+                        motion = Some(motion_detector.update(
+                            &joint_positions,
+                            joint_velocities.as_deref(),
+                            timestamp,
+                        ));
+                    }
+                }
+                None => {
+                    r2r::log_error!(log_target, "Joint state subscriber did not get the message?");
+                }
+            },
+            _ = interval.tick() => {
+                let timestamp = match received {
+                    Some(timestamp) => timestamp,
+                    None => continue,
+                };
                 if let Err(_) = connection_manager.check_redis_health("joint_subscriber").await {
                     continue;
                 }
-                let joint_states = message.position;
+                let joint_positions = match JointStates::complete(&joint_states.positions) {
+                    Some(joint_positions) => joint_positions,
+                    None => continue,
+                };
+                received = None;
 
-                StateManager::set_sp_value(
-                    &mut con,
-                    &format!("{robot_name}_joint_states"),
-                    &joint_states.to_spvalue(),
-                )
-                .await;
-            }
-            None => {
-                r2r::log_error!(
-                    &format!("{robot_name}_joint_subscriber"),
-                    "Joint state subscriber did not get the message?"
-                );
+                // A robot standing still is still fresh
+                let mut values = vec![(
+                    format!("{robot_name}_joint_states_timestamp"),
+                    millis_since_epoch(timestamp).to_spvalue(),
+                )];
+                let joint_velocities = JointStates::complete(&joint_states.velocities);
+                let moved = outside_deadband(
+                    written_positions.as_deref(),
+                    &joint_positions,
+                    mirror_config.joint_deadband,
+                ) || joint_velocities.as_ref().is_some_and(|joint_velocities| {
+                    outside_deadband(
+                        written_velocities.as_deref(),
+                        joint_velocities,
+                        mirror_config.joint_deadband,
+                    )
+                });
+                if moved {
+                    values.push((format!("{robot_name}_joint_states"), joint_positions.to_spvalue()));
+                    values.push((
+                        format!("{robot_name}_joint_names"),
+                        SPValue::Array(ArrayOrUnknown::Array(
+                            joint_states.names.iter().map(|name| name.to_spvalue()).collect(),
+                        )),
+                    ));
+                    if let Some(joint_velocities) = &joint_velocities {
+                        values.push((format!("{robot_name}_joint_velocities"), joint_velocities.to_spvalue()));
+                    }
+                    if let Some(joint_efforts) = JointStates::complete(&joint_states.efforts) {
+                        values.push((format!("{robot_name}_joint_efforts"), joint_efforts.to_spvalue()));
+                    }
+                    if let (Some(arm_joints), Some(joint_velocities)) = (&arm_joints, &joint_velocities) {
+                        let select = |values: &[f64]| arm_joints.iter().map(|i| values[*i]).collect::<Vec<f64>>();
+                        if let Some(speed) = tcp_speed(arm, &select(&joint_positions), &select(joint_velocities)) {
+                            values.push((format!("{robot_name}_tcp_speed"), speed.to_spvalue()));
+                        }
+                    }
+                    written_positions = Some(joint_positions);
+                    written_velocities = joint_velocities;
+                }
+                // Settling happens while the joints stay within the deadband
+                if let Some(motion) = motion.filter(|motion| Some(*motion) != written_motion) {
+                    values.push((format!("{robot_name}_is_moving"), motion.is_moving.to_spvalue()));
+                    values.push((format!("{robot_name}_is_settled"), motion.is_settled.to_spvalue()));
+                    written_motion = Some(motion);
+                }
+                StateManager::set_state(&mut con, &state_from_values(values)).await;
             }
         }
     }
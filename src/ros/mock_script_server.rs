@@ -0,0 +1,306 @@
+use futures::StreamExt;
+use r2r::ur_script_msgs::action::ExecuteScript;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How the mock server ends a goal.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MockGoalStatus {
+    Succeed { ok: bool },
+    Abort,
+    // Runs until it is cancelled
+    NeverComplete,
+}
+
+/// What the mock server does with the next goal it gets.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockGoal {
+    pub feedback: Vec<String>,
+    pub delay: Duration,
+    pub status: MockGoalStatus,
+}
+
+impl MockGoal {
+    pub fn succeed() -> MockGoal {
+        MockGoal {
+            feedback: vec![],
+            delay: Duration::ZERO,
+            status: MockGoalStatus::Succeed { ok: true },
+        }
+    }
+
+    // Succeeds, but the script itself returned False
+    pub fn returns_false() -> MockGoal {
+        MockGoal {
+            status: MockGoalStatus::Succeed { ok: false },
+            ..MockGoal::succeed()
+        }
+    }
+
+    pub fn abort() -> MockGoal {
+        MockGoal {
+            status: MockGoalStatus::Abort,
+            ..MockGoal::succeed()
+        }
+    }
+
+    pub fn never_complete() -> MockGoal {
+        MockGoal {
+            status: MockGoalStatus::NeverComplete,
+            ..MockGoal::succeed()
+        }
+    }
+
+    pub fn with_feedback(mut self, feedback: &[&str]) -> MockGoal {
+        self.feedback = feedback.iter().map(|line| line.to_string()).collect();
+        self
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> MockGoal {
+        self.delay = delay;
+        self
+    }
+}
+
+/// Stands in for the ur_script_driver in tests. It serves the ExecuteScript action,
+/// records the scripts it gets and plays back the queued goals, or succeeds when there
+/// are none left. The node has to be spun for it to see any goals.
+#[derive(Debug, Clone, Default)]
+pub struct MockScriptServer {
+    pub scripts: Arc<Mutex<Vec<String>>>,
+    goals: Arc<Mutex<VecDeque<MockGoal>>>,
+}
+
+impl MockScriptServer {
+    pub fn push_goal(&self, goal: MockGoal) {
+        self.goals.lock().unwrap().push_back(goal);
+    }
+
+    pub fn scripts(&self) -> Vec<String> {
+        self.scripts.lock().unwrap().clone()
+    }
+
+    pub fn serve(
+        &self,
+        arc_node: &Arc<Mutex<r2r::Node>>,
+        action_name: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut requests = arc_node
+            .lock()
+            .unwrap()
+            .create_action_server::<ExecuteScript::Action>(action_name)?;
+        let server = self.clone();
+        let log_target = format!("{action_name}_mock_server");
+        tokio::spawn(async move {
+            while let Some(request) = requests.next().await {
+                server.scripts.lock().unwrap().push(request.goal.script.clone());
+                let mock_goal = server
+                    .goals
+                    .lock()
+                    .unwrap()
+                    .pop_front()
+                    .unwrap_or_else(MockGoal::succeed);
+                let (mut goal, mut cancel_requests) = match request.accept() {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        r2r::log_error!(&log_target, "Failed to accept the goal with {}.", e);
+                        continue;
+                    }
+                };
+                let log_target = log_target.clone();
+                tokio::spawn(async move {
+                    let MockGoal {
+                        feedback,
+                        delay,
+                        status,
+                    } = mock_goal;
+                    for line in feedback {
+                        let _ = goal.publish_feedback(ExecuteScript::Feedback { feedback: line });
+                    }
+                    let finished = async {
+                        tokio::time::sleep(delay).await;
+                        if status == MockGoalStatus::NeverComplete {
+                            std::future::pending::<()>().await;
+                        }
+                    };
+                    let result = tokio::select! {
+                        _ = finished => match status {
+                            MockGoalStatus::Succeed { ok } => goal.succeed(ExecuteScript::Result { ok }),
+                            _ => goal.abort(ExecuteScript::Result { ok: false }),
+                        },
+                        Some(cancel_request) = cancel_requests.next() => {
+                            cancel_request.accept();
+                            goal.cancel(ExecuteScript::Result { ok: false })
+                        }
+                    };
+                    if let Err(e) = result {
+                        r2r::log_error!(&log_target, "Failed to end the goal with {}.", e);
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+}
+
+// A node of its own, spun in the background like in main. The namespace keeps the
+// actions of tests that run at the same time apart.
+#[cfg(test)]
+fn spun_test_node(name: &str) -> Arc<Mutex<r2r::Node>> {
+    let ctx = r2r::Context::create().unwrap();
+    let node = Arc::new(Mutex::new(
+        r2r::Node::create(ctx, name, &format!("/{name}")).unwrap(),
+    ));
+    let node_clone = node.clone();
+    std::thread::spawn(move || loop {
+        node_clone
+            .lock()
+            .unwrap()
+            .spin_once(Duration::from_millis(10));
+    });
+    node
+}
+
+#[tokio::test]
+#[ignore = "needs a ROS 2 environment"]
+async fn test_mock_script_server() {
+    use crate::*;
+
+    let node = spun_test_node("mock_script_server_test");
+    let server = MockScriptServer::default();
+    server.serve(&node, "ur_script").unwrap();
+    server.push_goal(MockGoal::succeed().with_feedback(&["FORCE: 1.5"]));
+    server.push_goal(MockGoal::abort().with_delay(Duration::from_millis(100)));
+    server.push_goal(MockGoal::never_complete());
+
+    let backend = RosScriptBackend::new(&node).unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let result = execute_script(&backend, "script_1".to_string(), |_| ()).await;
+    assert_eq!(result, Ok("Script returned true.".to_string()));
+
+    let result = execute_script(&backend, "script_2".to_string(), |_| ()).await;
+    assert_eq!(result, Err("Goal aborted, result is false.".to_string()));
+
+    let execution = execute_script(&backend, "script_3".to_string(), |_| ());
+    let cancel = async {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        backend.cancel().await
+    };
+    let (result, cancelled) = tokio::join!(execution, cancel);
+    assert_eq!(result, Err("Goal canceled.".to_string()));
+    assert!(cancelled.is_ok());
+
+    assert_eq!(server.scripts(), vec!["script_1", "script_2", "script_3"]);
+}
+
+// The whole loop from a Redis request to the goal and back.
+#[tokio::test]
+#[ignore = "needs a local Redis and a ROS 2 environment"]
+async fn test_action_client_end_to_end() {
+    use crate::*;
+    use micro_sp::*;
+
+    let robot_name = "mock_r";
+    let node = spun_test_node("action_client_end_to_end_test");
+    let server = MockScriptServer::default();
+    server.serve(&node, "ur_script").unwrap();
+    // What the goal does, the request state it should end in and how the failure is explained
+    let cases = vec![
+        (
+            MockGoal::succeed().with_feedback(&["FORCE: 2.5"]),
+            Some(ActionRequestState::Succeeded),
+            None,
+        ),
+        (
+            MockGoal::abort().with_delay(Duration::from_millis(100)),
+            Some(ActionRequestState::Failed),
+            Some("Goal aborted"),
+        ),
+        (
+            MockGoal::returns_false(),
+            Some(ActionRequestState::Failed),
+            Some("Script returned false."),
+        ),
+        (
+            MockGoal::succeed().with_delay(Duration::from_millis(1000)),
+            Some(ActionRequestState::Succeeded),
+            None,
+        ),
+        // The request stays pending for as long as the goal runs
+        (MockGoal::never_complete(), None, None),
+    ];
+    for (goal, _, _) in &cases {
+        server.push_goal(goal.clone());
+    }
+
+    let connection_manager = Arc::new(ConnectionManager::new().await);
+    let mut con = connection_manager.get_connection().await;
+    StateManager::set_state(&mut con, &generate_robot_interface_state(robot_name)).await;
+    for (key, value) in [
+        ("command_type", "safe_move_j".to_spvalue()),
+        ("accelleration", 0.1.to_spvalue()),
+        ("velocity", 0.1.to_spvalue()),
+        ("use_joint_positions", true.to_spvalue()),
+        ("joint_positions", SAFE_HOME_JOINT_STATE.to_vec().to_spvalue()),
+    ] {
+        StateManager::set_sp_value(&mut con, &format!("{robot_name}_{key}"), &value).await;
+    }
+
+    let templates =
+        tera::Tera::new(&format!("{}/templates/*.script", env!("CARGO_MANIFEST_DIR"))).unwrap();
+    let connection_manager_clone = connection_manager.clone();
+    let node_clone = node.clone();
+    tokio::spawn(async move {
+        action_client(
+            "127.0.0.1",
+            robot_name,
+            node_clone,
+            &connection_manager_clone,
+            &templates,
+            &RobotParameters::default(),
+            &RobotDiagnostics::default(),
+        )
+        .await
+        .unwrap()
+    });
+
+    let keys = vec![
+        format!("{robot_name}_request_state"),
+        format!("{robot_name}_failure_diagnostics"),
+    ];
+    for (_, expected, diagnostics) in &cases {
+        StateManager::set_sp_value(&mut con, &format!("{robot_name}_request_state"), &"initial".to_spvalue()).await;
+        StateManager::set_sp_value(&mut con, &format!("{robot_name}_request_trigger"), &true.to_spvalue()).await;
+        let attempts = match expected {
+            Some(_) => 40,
+            None => 8,
+        };
+        let mut state = State::new();
+        let mut request_state = String::new();
+        for _ in 0..attempts {
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            state = StateManager::get_state_for_keys(&mut con, &keys).await.unwrap();
+            request_state =
+                state.get_string_or_default_to_unknown(&format!("{robot_name}_request_state"), "test");
+            if request_state != "initial" {
+                break;
+            }
+        }
+        let expected = expected
+            .as_ref()
+            .map(|expected| expected.to_string())
+            .unwrap_or("initial".to_string());
+        assert_eq!(request_state, expected);
+        if let Some(diagnostics) = diagnostics {
+            assert!(state
+                .get_string_or_default_to_unknown(&format!("{robot_name}_failure_diagnostics"), "test")
+                .starts_with(diagnostics));
+        }
+    }
+
+    let scripts = server.scripts();
+    assert_eq!(scripts.len(), cases.len());
+    assert!(scripts.iter().all(|script| script.contains("movej")));
+}
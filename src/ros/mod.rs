@@ -1,8 +1,13 @@
 pub mod action_client;
-// pub mod dashboard_client;
+pub mod dashboard_client;
 // pub mod control_ghost;
 pub mod urdf_parsing;
 pub mod robot_state_publisher;
 pub mod ur_script_driver;
 pub mod joint_subscriber;
-pub mod robot_state_to_redis;
\ No newline at end of file
+pub mod script_backend;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock_script_server;
+pub mod robot_state_to_redis;
+pub mod static_transforms;
+pub mod forward_kinematics;
\ No newline at end of file
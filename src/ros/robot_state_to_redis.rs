@@ -1,17 +1,23 @@
 use ordered_float::OrderedFloat;
 use r2r::geometry_msgs::msg::TransformStamped;
 use r2r::tf2_msgs::msg::TFMessage;
+use k::nalgebra::Isometry3;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use std::time::SystemTime;
 
 use futures::{Stream, StreamExt};
 use micro_sp::*;
 
-fn tf_to_sp_tf(tf: TransformStamped) -> SPTransformStamped {
+use crate::*;
+
+// Stamped with the header, so that a frozen transform can be told from a fresh one.
+pub fn tf_to_sp_tf(tf: TransformStamped) -> SPTransformStamped {
     SPTransformStamped {
         active_transform: true,
         enable_transform: true,
-        time_stamp: SystemTime::now(),
+        time_stamp: time_from_stamp(tf.header.stamp.sec, tf.header.stamp.nanosec)
+            .unwrap_or_else(SystemTime::now),
         parent_frame_id: tf.header.frame_id,
         child_frame_id: tf.child_frame_id,
         transform: SPTransform {
@@ -31,83 +37,126 @@ fn tf_to_sp_tf(tf: TransformStamped) -> SPTransformStamped {
     }
 }
 
+pub fn link_to_sp_tf(link: &LinkTransform) -> SPTransformStamped {
+    SPTransformStamped {
+        active_transform: true,
+        enable_transform: true,
+        time_stamp: SystemTime::now(),
+        parent_frame_id: link.parent_frame_id.clone(),
+        child_frame_id: link.child_frame_id.clone(),
+        transform: SPTransform {
+            translation: SPTranslation {
+                x: OrderedFloat(link.translation[0]),
+                y: OrderedFloat(link.translation[1]),
+                z: OrderedFloat(link.translation[2]),
+            },
+            rotation: SPRotation {
+                x: OrderedFloat(link.rotation[0]),
+                y: OrderedFloat(link.rotation[1]),
+                z: OrderedFloat(link.rotation[2]),
+                w: OrderedFloat(link.rotation[3]),
+            },
+        },
+        metadata: MapOrUnknown::UNKNOWN,
+    }
+}
+
+/// Mirrors the moving links from /tf at the tf_rate of the mirror config. The frames
+/// that moved outside of the deadband since the last tick are written together.
+/// With internal_fk the moving links come from forward_kinematics_to_redis instead,
+/// and what comes back on /tf are only our own frames, so they are left out.
 pub async fn robot_state_to_redis(
     robot_name: &str,
+    urdf: &str,
+    frames: &FrameParameters,
+    mirror_config: &MirrorConfig,
+    internal_fk: bool,
     mut subscriber: impl Stream<Item = TFMessage> + Unpin,
     connection_manager: &Arc<ConnectionManager>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     tokio::time::sleep(std::time::Duration::from_millis(250)).await;
 
-    let initial = vec![
-        ("base_link", "base_link_inertia"),
-        ("base_link_inertia", "shoulder_link"),
-        ("shoulder_link", "upper_arm_link"),
-        ("upper_arm_link", "forearm_link"),
-        ("forearm_link", "wrist_1_link"),
-        ("wrist_1_link", "wrist_2_link"),
-        ("wrist_2_link", "wrist_3_link"),
-        ("wrist_3_link", "flange"),
-        ("wrist_3_link", "ft_frame"),
-        ("flange", "tool0"),
-    ];
+    // The fixed links get their transform from the urdf here, only the
+    // moving ones are kept up to date from /tf.
+    let link_tree = attach_link_tree(link_tree_from_urdf(urdf)?, frames);
+    let links_to_move: HashSet<String> = link_tree
+        .iter()
+        .filter(|link| link.moving && !internal_fk)
+        .map(|link| link.child_frame_id.clone())
+        .collect();
 
     let mut con = connection_manager.get_connection().await;
     TransformsManager::insert_transforms(
         &mut con,
-        &initial
+        &link_tree
             .iter()
-            .map(|t| SPTransformStamped {
-                active_transform: true,
-                enable_transform: true,
-                time_stamp: SystemTime::now(),
-                parent_frame_id: t.0.to_string(),
-                child_frame_id: t.1.to_string(),
-                transform: SPTransform::default(),
-                metadata: MapOrUnknown::UNKNOWN,
-            })
+            .map(link_to_sp_tf)
             .collect::<Vec<SPTransformStamped>>(),
     )
     .await?;
 
+    // The latest transform of every link since the last tick, and what was written
+    let mut received: HashMap<String, SPTransformStamped> = HashMap::new();
+    let mut written: HashMap<String, Isometry3<f64>> = HashMap::new();
+    let mut interval = tokio::time::interval(mirror_period(mirror_config.tf_rate));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
     loop {
-        match subscriber.next().await {
-            Some(message) => {
+        tokio::select! {
+            message = subscriber.next() => match message {
+                Some(message) => {
+                    for tf in message.transforms {
+                        if links_to_move.contains(&tf.child_frame_id) {
+                            received.insert(tf.child_frame_id.clone(), tf_to_sp_tf(tf));
+                        }
+                    }
+                }
+                None => {
+                    r2r::log_error!(
+                        &format!("{robot_name}_joint_subscriber"),
+                        "Joint state subscriber did not get the message?"
+                    );
+                }
+            },
+            _ = interval.tick() => {
+                if received.is_empty() {
+                    continue;
+                }
                 if let Err(_) = connection_manager
                     .check_redis_health(&&format!("{robot_name}_action_client"))
                     .await
                 {
                     continue;
                 }
-                let links_to_move = [
-                    "base_link_inertia",
-                    "shoulder_link",
-                    "upper_arm_link",
-                    "forearm_link",
-                    "upper_arm_link",
-                    "wrist_1_link",
-                    "wrist_2_link",
-                    "wrist_3_link",
-                    "flange",
-                    "ft_frame",
-                    "tool0",
-                ];
 
-                for tf in &message.transforms {
-                    if links_to_move.contains(&tf.child_frame_id.as_str()) {
-                        TransformsManager::move_transform(
-                            &mut con,
-                            &tf.child_frame_id,
-                            tf_to_sp_tf(tf.clone()).transform,
+                let newest = received.values().map(|sp_tf| sp_tf.time_stamp).max();
+                let moved = received
+                    .drain()
+                    .map(|(_, sp_tf)| sp_tf)
+                    .filter(|sp_tf| {
+                        transform_outside_deadband(
+                            written.get(&sp_tf.child_frame_id),
+                            &isometry_from_sp_transform(&sp_tf.transform),
+                            mirror_config.translation_deadband,
+                            mirror_config.rotation_deadband,
                         )
-                        .await?;
+                    })
+                    .collect::<Vec<SPTransformStamped>>();
+                if !moved.is_empty() {
+                    TransformsManager::insert_transforms(&mut con, &moved).await?;
+                    for sp_tf in moved {
+                        written.insert(sp_tf.child_frame_id, isometry_from_sp_transform(&sp_tf.transform));
                     }
                 }
-            }
-            None => {
-                r2r::log_error!(
-                    &format!("{robot_name}_joint_subscriber"),
-                    "Joint state subscriber did not get the message?"
-                );
+                // Also when nothing moved, a robot standing still is still fresh
+                if let Some(newest) = newest {
+                    StateManager::set_sp_value(
+                        &mut con,
+                        &format!("{robot_name}_tf_timestamp"),
+                        &millis_since_epoch(newest).to_spvalue(),
+                    )
+                    .await;
+                }
             }
         }
     }
@@ -0,0 +1,90 @@
+use futures::StreamExt;
+use r2r::ur_script_msgs::action::ExecuteScript;
+use std::sync::{Arc, Mutex};
+
+use crate::*;
+
+/// The ur_script action of the external ur_script_driver.
+pub struct RosScriptBackend {
+    client: Mutex<r2r::ActionClient<ExecuteScript::Action>>,
+    // The goal that runs now, to cancel it
+    goal: Mutex<Option<r2r::ActionClientGoal<ExecuteScript::Action>>>,
+}
+
+impl RosScriptBackend {
+    pub fn new(arc_node: &Arc<Mutex<r2r::Node>>) -> Result<RosScriptBackend, Box<dyn std::error::Error>> {
+        let client = arc_node
+            .lock()
+            .unwrap()
+            .create_action_client::<ExecuteScript::Action>(&format!("ur_script"))?;
+        Ok(RosScriptBackend {
+            client: Mutex::new(client),
+            goal: Mutex::new(None),
+        })
+    }
+}
+
+impl RobotBackend for RosScriptBackend {
+    fn send_script(&self, script: String) -> BackendFuture<'_, Result<ScriptExecution, String>> {
+        Box::pin(async move {
+            let goal = ExecuteScript::Goal { script };
+            let request = self
+                .client
+                .lock()
+                .unwrap()
+                .send_goal_request(goal)
+                .map_err(|e| format!("Did not get goal, {}.", e))?;
+            let (goal, result, feedback) = request
+                .await
+                .map_err(|e| format!("Could not send goal request, {}.", e))?;
+            *self.goal.lock().unwrap() = Some(goal);
+
+            let result: BackendFuture<'static, Result<String, String>> =
+                Box::pin(async move {
+                    match result.await {
+                        Ok((status, msg)) => match status {
+                            r2r::GoalStatus::Aborted => {
+                                Err(format!("Goal aborted, result is {}.", msg.ok))
+                            }
+                            r2r::GoalStatus::Canceled => Err("Goal canceled.".to_string()),
+                            _ => script_returned(msg.ok),
+                        },
+                        Err(e) => Err(format!("Goal failed with {}.", e)),
+                    }
+                });
+            Ok(ScriptExecution {
+                feedback: feedback.map(|msg| msg.feedback).boxed(),
+                result,
+            })
+        })
+    }
+
+    fn cancel(&self) -> BackendFuture<'_, Result<(), String>> {
+        Box::pin(async move {
+            let goal = match self.goal.lock().unwrap().take() {
+                Some(goal) => goal,
+                None => return Ok(()),
+            };
+            goal.cancel()
+                .map_err(|e| format!("Failed to cancel the goal with {}.", e))?
+                .await
+                .map_err(|e| format!("Failed to cancel the goal with {}.", e))
+        })
+    }
+}
+
+/// The backend that the script_backend parameter asks for.
+pub fn robot_backend(
+    ur_address: &str,
+    arc_node: &Arc<Mutex<r2r::Node>>,
+    robot_parameters: &RobotParameters,
+) -> Result<Box<dyn RobotBackend>, Box<dyn std::error::Error>> {
+    Ok(match &robot_parameters.script_backend {
+        ScriptBackend::Ros => Box::new(RosScriptBackend::new(arc_node)?),
+        ScriptBackend::Direct(config) => Box::new(ScriptSender::new(
+            ur_address,
+            config,
+            robot_parameters.script_timeout,
+        )),
+    })
+}
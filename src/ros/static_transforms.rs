@@ -0,0 +1,295 @@
+use futures::{Stream, StreamExt};
+use micro_sp::*;
+use ordered_float::OrderedFloat;
+use r2r::tf2_msgs::msg::TFMessage;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::ros::robot_state_to_redis::tf_to_sp_tf;
+use crate::*;
+
+// The scenario files come in a few generations, the older ones name the frames
+// child_frame/parent_frame and keep the flags in extra_data or at the top.
+#[derive(Deserialize)]
+struct ScenarioTransform {
+    #[serde(alias = "child_frame")]
+    child_frame_id: String,
+    #[serde(alias = "parent_frame")]
+    parent_frame_id: String,
+    transform: ScenarioPose,
+    active: Option<bool>,
+    #[serde(alias = "extra_data")]
+    metadata: Option<ScenarioMetadata>,
+}
+
+#[derive(Deserialize)]
+struct ScenarioPose {
+    translation: ScenarioTranslation,
+    rotation: ScenarioRotation,
+}
+
+#[derive(Deserialize)]
+struct ScenarioTranslation {
+    x: f64,
+    y: f64,
+    z: f64,
+}
+
+#[derive(Deserialize)]
+struct ScenarioRotation {
+    x: f64,
+    y: f64,
+    z: f64,
+    w: f64,
+}
+
+#[derive(Deserialize)]
+struct ScenarioMetadata {
+    enable_transform: Option<bool>,
+    #[serde(alias = "active")]
+    active_transform: Option<bool>,
+}
+
+pub fn scenario_transform_from_json(json: &str) -> Result<SPTransformStamped, String> {
+    let scenario: ScenarioTransform = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    let metadata = scenario.metadata.as_ref();
+    Ok(SPTransformStamped {
+        active_transform: metadata
+            .and_then(|metadata| metadata.active_transform)
+            .or(scenario.active)
+            .unwrap_or(false),
+        enable_transform: metadata
+            .and_then(|metadata| metadata.enable_transform)
+            .unwrap_or(true),
+        time_stamp: SystemTime::now(),
+        parent_frame_id: scenario.parent_frame_id,
+        child_frame_id: scenario.child_frame_id,
+        transform: SPTransform {
+            translation: SPTranslation {
+                x: OrderedFloat(scenario.transform.translation.x),
+                y: OrderedFloat(scenario.transform.translation.y),
+                z: OrderedFloat(scenario.transform.translation.z),
+            },
+            rotation: SPRotation {
+                x: OrderedFloat(scenario.transform.rotation.x),
+                y: OrderedFloat(scenario.transform.rotation.y),
+                z: OrderedFloat(scenario.transform.rotation.z),
+                w: OrderedFloat(scenario.transform.rotation.w),
+            },
+        },
+        // The meshes and markers are for the visualization, it reads the files itself
+        metadata: MapOrUnknown::UNKNOWN,
+    })
+}
+
+/// Reads every .json transform in the directory, in the order of the file names.
+/// Subdirectories are other scenarios and are left alone.
+pub fn load_scenario_transforms(dir: &str) -> Result<Vec<SPTransformStamped>, String> {
+    let mut paths = std::fs::read_dir(dir)
+        .map_err(|e| format!("failed to read {} with {}", dir, e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "json"))
+        .collect::<Vec<std::path::PathBuf>>();
+    paths.sort();
+    paths
+        .iter()
+        .map(|path| {
+            std::fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|json| scenario_transform_from_json(&json))
+                .map_err(|e| format!("failed to load {} with {}", path.display(), e))
+        })
+        .collect()
+}
+
+fn same_transform(a: &SPTransform, b: &SPTransform) -> bool {
+    let (a, b) = (isometry_from_sp_transform(a), isometry_from_sp_transform(b));
+    (a.translation.vector - b.translation.vector).norm() < 1e-6 && a.rotation.angle_to(&b.rotation) < 1e-6
+}
+
+/// Keeps the last transform of every frame, with a message for every frame that was
+/// given more than one parent or pose.
+pub fn dedup_transforms(
+    transforms: Vec<SPTransformStamped>,
+) -> (Vec<SPTransformStamped>, Vec<String>) {
+    let mut deduped: Vec<SPTransformStamped> = vec![];
+    let mut conflicts = vec![];
+    for transform in transforms {
+        match deduped
+            .iter_mut()
+            .find(|existing| existing.child_frame_id == transform.child_frame_id)
+        {
+            Some(existing) => {
+                if existing.parent_frame_id != transform.parent_frame_id
+                    || !same_transform(&existing.transform, &transform.transform)
+                {
+                    conflicts.push(format!(
+                        "{} is given both in {} and in {}",
+                        transform.child_frame_id, existing.parent_frame_id, transform.parent_frame_id
+                    ));
+                }
+                *existing = transform;
+            }
+            None => deduped.push(transform),
+        }
+    }
+    (deduped, conflicts)
+}
+
+/// Inserts the transforms and reports the ones that move a frame which is already in
+/// the tree somewhere else. The new transforms win.
+pub async fn insert_transforms_reporting_conflicts(
+    log_target: &str,
+    connection_manager: &Arc<ConnectionManager>,
+    transforms: Vec<SPTransformStamped>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (transforms, mut conflicts) = dedup_transforms(transforms);
+    let mut con = connection_manager.get_connection().await;
+    for transform in &transforms {
+        if let Ok(existing) = TransformsManager::lookup_transform(
+            &mut con,
+            &transform.parent_frame_id,
+            &transform.child_frame_id,
+        )
+        .await
+        {
+            if !same_transform(&existing.transform, &transform.transform) {
+                conflicts.push(format!(
+                    "{} in {} is already in the tree at another pose",
+                    transform.child_frame_id, transform.parent_frame_id
+                ));
+            }
+        }
+    }
+    for conflict in conflicts {
+        r2r::log_warn!(log_target, "Transform conflict: {}.", conflict);
+    }
+    TransformsManager::insert_transforms(&mut con, &transforms).await?;
+    Ok(())
+}
+
+/// Loads the scenario, if there is one, and then mirrors tf_static. The static frames
+/// are only published once, so the subscriber needs transient local durability.
+pub async fn static_transforms_to_redis(
+    robot_name: &str,
+    urdf: &str,
+    scenario_dir: Option<String>,
+    mut subscriber: impl Stream<Item = TFMessage> + Unpin,
+    connection_manager: &Arc<ConnectionManager>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let log_target = &format!("{robot_name}_static_transforms");
+    // The links of the robot are already in the tree, attached where they should be
+    let robot_frames: HashSet<String> = link_tree_from_urdf(urdf)?
+        .into_iter()
+        .map(|link| link.child_frame_id)
+        .collect();
+    // After the link tree of the robot, so that the scenario can place its base
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    if let Some(scenario_dir) = scenario_dir {
+        let transforms = load_scenario_transforms(&scenario_dir)?;
+        r2r::log_info!(
+            log_target,
+            "Loaded {} scenario transforms from {}.",
+            transforms.len(),
+            scenario_dir
+        );
+        insert_transforms_reporting_conflicts(log_target, connection_manager, transforms).await?;
+    }
+
+    loop {
+        match subscriber.next().await {
+            Some(message) => {
+                if let Err(_) = connection_manager.check_redis_health(log_target).await {
+                    continue;
+                }
+                let transforms = message
+                    .transforms
+                    .into_iter()
+                    .filter(|tf| !robot_frames.contains(&tf.child_frame_id))
+                    .map(|tf| SPTransformStamped {
+                        active_transform: false,
+                        ..tf_to_sp_tf(tf)
+                    })
+                    .collect::<Vec<SPTransformStamped>>();
+                if transforms.is_empty() {
+                    continue;
+                }
+                insert_transforms_reporting_conflicts(log_target, connection_manager, transforms)
+                    .await?;
+            }
+            None => {
+                r2r::log_error!(log_target, "Static transform subscriber did not get the message?");
+            }
+        }
+    }
+}
+
+#[test]
+fn test_load_scenario_transforms() {
+    let dir = format!(
+        "{}/preparation/scenario/transforms/scenario_2",
+        env!("CARGO_MANIFEST_DIR")
+    );
+    let transforms = load_scenario_transforms(&dir).unwrap();
+    assert_eq!(transforms.len(), 16);
+    let box_1 = transforms.iter().find(|t| t.child_frame_id == "box_1").unwrap();
+    assert_eq!(box_1.parent_frame_id, "floor");
+    assert_eq!(box_1.transform.translation.x, OrderedFloat(0.5));
+    assert!(box_1.active_transform);
+
+    // The older generations of files
+    let base_link = scenario_transform_from_json(
+        r#"{"show": true, "active": false, "child_frame": "base_link", "parent_frame": "vagn",
+            "transform": {"translation": {"x": 0.0, "y": 0.0, "z": 0.0},
+                          "rotation": {"x": 0.0, "y": 0.0, "z": 1.0, "w": 0.0}}}"#,
+    )
+    .unwrap();
+    assert_eq!(base_link.parent_frame_id, "vagn");
+    assert!(!base_link.active_transform);
+    let place_pose = scenario_transform_from_json(
+        r#"{"parent_frame_id": "floor", "child_frame_id": "place_pose_1",
+            "transform": {"translation": {"x": 0.1, "y": 0.2, "z": 0.3},
+                          "rotation": {"x": 0.0, "y": 0.0, "z": 0.0, "w": 1.0}},
+            "extra_data": {"active": true}}"#,
+    )
+    .unwrap();
+    assert!(place_pose.active_transform);
+    assert!(place_pose.enable_transform);
+}
+
+#[test]
+fn test_dedup_transforms() {
+    let transform = |parent: &str, child: &str, x: f64| SPTransformStamped {
+        active_transform: false,
+        enable_transform: true,
+        time_stamp: SystemTime::now(),
+        parent_frame_id: parent.to_string(),
+        child_frame_id: child.to_string(),
+        transform: SPTransform {
+            translation: SPTranslation {
+                x: OrderedFloat(x),
+                y: OrderedFloat(0.0),
+                z: OrderedFloat(0.0),
+            },
+            rotation: SPRotation {
+                x: OrderedFloat(0.0),
+                y: OrderedFloat(0.0),
+                z: OrderedFloat(0.0),
+                w: OrderedFloat(1.0),
+            },
+        },
+        metadata: MapOrUnknown::UNKNOWN,
+    };
+    let (deduped, conflicts) = dedup_transforms(vec![
+        transform("world", "floor", 0.0),
+        transform("floor", "stand", 1.0),
+        transform("world", "floor", 0.0),
+        transform("world", "stand", 1.0),
+    ]);
+    assert_eq!(deduped.len(), 2);
+    assert_eq!(deduped[1].parent_frame_id, "world");
+    assert_eq!(conflicts, vec!["stand is given both in floor and in world"]);
+}
@@ -1,4 +1,9 @@
-use crate::URDFParameters;
+use crate::{
+    isometry_from_pose, FrameParameters, JointLimit, URDFParameters, DEFAULT_ROOT_FRAME_ID,
+    UR_JOINT_NAMES,
+};
+use k::nalgebra::{Isometry3, Quaternion, Translation3, UnitQuaternion};
+use std::collections::HashSet;
 use std::process::{Command, Stdio};
 
 
@@ -29,6 +34,257 @@ pub fn convert_xacro_to_urdf(args: URDFParameters) -> Option<String> {
 
 }
 
+// Joint limits of the arm, in the order of UR_JOINT_NAMES.
+pub fn joint_limits_from_urdf(urdf: &str, tf_prefix: &str) -> Result<Vec<JointLimit>, String> {
+    let robot = urdf_rs::read_from_string(urdf).map_err(|e| e.to_string())?;
+    UR_JOINT_NAMES
+        .iter()
+        .map(|name| {
+            let name = format!("{}{}", tf_prefix, name);
+            match robot.joints.iter().find(|j| j.name == name) {
+                Some(joint) => match joint.joint_type {
+                    urdf_rs::JointType::Continuous => Ok(JointLimit {
+                        name,
+                        lower: f64::NEG_INFINITY,
+                        upper: f64::INFINITY,
+                        velocity: joint.limit.velocity,
+                    }),
+                    _ => Ok(JointLimit {
+                        name,
+                        lower: joint.limit.lower,
+                        upper: joint.limit.upper,
+                        velocity: joint.limit.velocity,
+                    }),
+                },
+                None => Err(format!("joint {} is not in the urdf", name)),
+            }
+        })
+        .collect()
+}
+
+// The moving joints in the order that the joint states are mirrored in: the arm first,
+// in the order of UR_JOINT_NAMES, then any others in the order of the urdf.
+pub fn joint_names_from_urdf(urdf: &str, tf_prefix: &str) -> Result<Vec<String>, String> {
+    let robot = urdf_rs::read_from_string(urdf).map_err(|e| e.to_string())?;
+    let mut names = UR_JOINT_NAMES
+        .iter()
+        .map(|name| format!("{}{}", tf_prefix, name))
+        .collect::<Vec<String>>();
+    if let Some(name) = names.iter().find(|name| !robot.joints.iter().any(|j| j.name == **name)) {
+        return Err(format!("joint {} is not in the urdf", name));
+    }
+    for joint in &robot.joints {
+        if joint.joint_type != urdf_rs::JointType::Fixed && !names.contains(&joint.name) {
+            names.push(joint.name.clone());
+        }
+    }
+    Ok(names)
+}
+
+/// A joint of the robot as the transform from its parent link to its child link,
+/// with the joint in its zero position.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkTransform {
+    pub parent_frame_id: String,
+    pub child_frame_id: String,
+    pub translation: [f64; 3],
+    // x, y, z, w
+    pub rotation: [f64; 4],
+    // Moved by the joint states, the fixed ones only have to be inserted once
+    pub moving: bool,
+}
+
+impl LinkTransform {
+    pub fn isometry(&self) -> Isometry3<f64> {
+        Isometry3::from_parts(
+            Translation3::new(self.translation[0], self.translation[1], self.translation[2]),
+            UnitQuaternion::from_quaternion(Quaternion::new(
+                self.rotation[3],
+                self.rotation[0],
+                self.rotation[1],
+                self.rotation[2],
+            )),
+        )
+    }
+
+    pub fn set_isometry(&mut self, isometry: &Isometry3<f64>) {
+        let t = isometry.translation.vector;
+        let r = isometry.rotation;
+        self.translation = [t.x, t.y, t.z];
+        self.rotation = [r.i, r.j, r.k, r.w];
+    }
+}
+
+// The link tree of the robot, tf_prefix and all, in the order of the joints in the urdf.
+pub fn link_tree_from_urdf(urdf: &str) -> Result<Vec<LinkTransform>, String> {
+    let robot = urdf_rs::read_from_string(urdf).map_err(|e| e.to_string())?;
+    Ok(robot
+        .joints
+        .iter()
+        .map(|joint| {
+            let rpy = &joint.origin.rpy;
+            let rotation = UnitQuaternion::from_euler_angles(rpy[0], rpy[1], rpy[2]);
+            LinkTransform {
+                parent_frame_id: joint.parent.link.clone(),
+                child_frame_id: joint.child.link.clone(),
+                translation: [joint.origin.xyz[0], joint.origin.xyz[1], joint.origin.xyz[2]],
+                rotation: [rotation.i, rotation.j, rotation.k, rotation.w],
+                moving: joint.joint_type != urdf_rs::JointType::Fixed,
+            }
+        })
+        .collect())
+}
+
+/// Hangs the link tree under the parent frame of the robot. A world link of the urdf
+/// itself is replaced by the parent frame, any other root link is attached to it.
+pub fn attach_link_tree(
+    mut link_tree: Vec<LinkTransform>,
+    frames: &FrameParameters,
+) -> Vec<LinkTransform> {
+    let mount = isometry_from_pose(frames.base_in_parent);
+    let children: HashSet<String> = link_tree
+        .iter()
+        .map(|link| link.child_frame_id.clone())
+        .collect();
+    let mut roots: Vec<String> = vec![];
+    for link in &link_tree {
+        if !children.contains(&link.parent_frame_id) && !roots.contains(&link.parent_frame_id) {
+            roots.push(link.parent_frame_id.clone());
+        }
+    }
+
+    for root in roots {
+        if root == DEFAULT_ROOT_FRAME_ID {
+            for link in link_tree.iter_mut().filter(|link| link.parent_frame_id == root) {
+                let attached = mount * link.isometry();
+                link.parent_frame_id = frames.parent_frame_id.clone();
+                link.set_isometry(&attached);
+            }
+        } else if root != frames.parent_frame_id {
+            let mut link = LinkTransform {
+                parent_frame_id: frames.parent_frame_id.clone(),
+                child_frame_id: root,
+                translation: [0.0; 3],
+                rotation: [0.0, 0.0, 0.0, 1.0],
+                moving: false,
+            };
+            link.set_isometry(&mount);
+            link_tree.insert(0, link);
+        }
+    }
+    link_tree
+}
+
+/// The serial chain from the root of the urdf to the end link, e.g. the faceplate.
+pub fn arm_from_urdf(urdf: &str, end_link: &str) -> Result<k::SerialChain<f64>, String> {
+    let robot = urdf_rs::read_from_string(urdf).map_err(|e| e.to_string())?;
+    let chain = k::Chain::<f64>::from(&robot);
+    let end = chain
+        .find_link(end_link)
+        .ok_or(format!("there is no link {} in the urdf", end_link))?;
+    Ok(k::SerialChain::from_end(end))
+}
+
+// Where the moving joints of the arm are among the joint names, in the order of the arm.
+pub fn arm_joint_indices(arm: &k::SerialChain<f64>, joint_names: &[String]) -> Option<Vec<usize>> {
+    arm.iter_joints()
+        .filter(|joint| joint.is_movable())
+        .map(|joint| joint_names.iter().position(|name| *name == joint.name))
+        .collect()
+}
+
+#[test]
+fn test_joint_names_from_urdf() {
+    let mut links = vec!["<link name=\"r1_base_link\"/>".to_string()];
+    let mut joints = vec![];
+    let mut parent = "r1_base_link".to_string();
+    for (i, name) in ["finger_joint"].iter().chain(UR_JOINT_NAMES.iter().rev()).enumerate() {
+        let child = format!("r1_link_{}", i);
+        links.push(format!("<link name=\"{}\"/>", child));
+        joints.push(format!(
+            "<joint name=\"r1_{}\" type=\"revolute\"><parent link=\"{}\"/><child link=\"{}\"/>\
+             <limit lower=\"-1\" upper=\"1\" effort=\"1\" velocity=\"1\"/></joint>",
+            name, parent, child
+        ));
+        parent = child;
+    }
+    let urdf = format!("<robot name=\"r1\">{}{}</robot>", links.join(""), joints.join(""));
+
+    let names = joint_names_from_urdf(&urdf, "r1_").unwrap();
+    assert_eq!(names.len(), 7);
+    assert_eq!(names[0], "r1_shoulder_pan_joint");
+    assert_eq!(names[5], "r1_wrist_3_joint");
+    assert_eq!(names[6], "r1_finger_joint");
+    assert!(joint_names_from_urdf(&urdf, "").is_err());
+}
+
+#[test]
+fn test_attach_link_tree() {
+    let link = |parent: &str, child: &str| LinkTransform {
+        parent_frame_id: parent.to_string(),
+        child_frame_id: child.to_string(),
+        translation: [0.0, 0.0, 0.1],
+        rotation: [0.0, 0.0, 0.0, 1.0],
+        moving: false,
+    };
+    let frames = FrameParameters {
+        tf_prefix: "r1_".to_string(),
+        parent_frame_id: "stand".to_string(),
+        base_in_parent: [1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+    };
+
+    let tree = attach_link_tree(vec![link("r1_base_link", "r1_base_link_inertia")], &frames);
+    assert_eq!(tree.len(), 2);
+    assert_eq!(tree[0].parent_frame_id, "stand");
+    assert_eq!(tree[0].child_frame_id, "r1_base_link");
+    assert_eq!(tree[0].translation, [1.0, 0.0, 0.0]);
+
+    // The urdf brings its own world link
+    let tree = attach_link_tree(
+        vec![link("world", "r1_base_link"), link("r1_base_link", "r1_base_link_inertia")],
+        &frames,
+    );
+    assert_eq!(tree.len(), 2);
+    assert_eq!(tree[0].parent_frame_id, "stand");
+    assert_eq!(tree[0].translation, [1.0, 0.0, 0.1]);
+    assert_eq!(tree[1], link("r1_base_link", "r1_base_link_inertia"));
+
+    let tree = attach_link_tree(vec![link("world", "r1_base_link")], &FrameParameters::default());
+    assert_eq!(tree, vec![link("world", "r1_base_link")]);
+}
+
+#[test]
+fn test_link_tree_from_urdf() {
+    let urdf = r#"<?xml version="1.0"?>
+        <robot name="r1">
+          <link name="r1_base_link"/>
+          <link name="r1_shoulder_link"/>
+          <link name="r1_flange"/>
+          <joint name="r1_shoulder_pan_joint" type="revolute">
+            <parent link="r1_base_link"/>
+            <child link="r1_shoulder_link"/>
+            <origin xyz="0 0 0.181" rpy="0 0 3.141592653589793"/>
+            <axis xyz="0 0 1"/>
+            <limit lower="-6.28" upper="6.28" effort="330.0" velocity="2.09"/>
+          </joint>
+          <joint name="r1_wrist_3-flange" type="fixed">
+            <parent link="r1_shoulder_link"/>
+            <child link="r1_flange"/>
+            <origin xyz="0 0 0" rpy="0 -1.5707963267948966 -1.5707963267948966"/>
+          </joint>
+        </robot>"#;
+    let tree = link_tree_from_urdf(urdf).unwrap();
+    assert_eq!(tree.len(), 2);
+    assert_eq!(tree[0].parent_frame_id, "r1_base_link");
+    assert_eq!(tree[0].child_frame_id, "r1_shoulder_link");
+    assert_eq!(tree[0].translation, [0.0, 0.0, 0.181]);
+    assert!((tree[0].rotation[2].abs() - 1.0).abs() < 1e-9);
+    assert!(tree[0].moving);
+    assert_eq!(tree[1].child_frame_id, "r1_flange");
+    assert!((tree[1].rotation[0] - -0.5).abs() < 1e-9);
+    assert!(!tree[1].moving);
+}
+
 #[test]
 fn test_xacro() {
     use std::path::PathBuf;
@@ -48,4 +304,34 @@ fn test_xacro() {
     println!("{}", urdf.unwrap())
     // println!("urdf: {:?}", urdf.unwrap());
 
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_arm_from_urdf() {
+    let urdf = r#"<robot name="arm">
+        <link name="base_link"/>
+        <link name="link_a"/>
+        <link name="tool0"/>
+        <joint name="joint_a" type="revolute">
+            <parent link="base_link"/>
+            <child link="link_a"/>
+            <axis xyz="0 0 1"/>
+            <limit lower="-3.14" upper="3.14" effort="1.0" velocity="3.0"/>
+        </joint>
+        <joint name="flange" type="fixed">
+            <parent link="link_a"/>
+            <child link="tool0"/>
+            <origin xyz="1 0 0" rpy="0 0 0"/>
+        </joint>
+    </robot>"#;
+    let arm = arm_from_urdf(urdf, "tool0").unwrap();
+    assert_eq!(arm.dof(), 1);
+    let joint_names = vec!["finger_joint".to_string(), "joint_a".to_string()];
+    assert_eq!(arm_joint_indices(&arm, &joint_names), Some(vec![1]));
+    assert_eq!(arm_joint_indices(&arm, &joint_names[..1]), None);
+    assert!(arm_from_urdf(urdf, "tool1").is_err());
+
+    // 2 rad/s at 1 m from the axis
+    let speed = crate::tcp_speed(&arm, &[0.5], &[2.0]).unwrap();
+    assert!((speed - 2.0).abs() < 1e-9);
+}
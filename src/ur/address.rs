@@ -0,0 +1,51 @@
+use std::net::{IpAddr, SocketAddr};
+
+/// Splits the address of the robot into the host and the port. The address is a host
+/// name, an IPv4 or an IPv6 address, with an optional port: 'host:port' or '[v6]:port'.
+pub fn host_and_port(address: &str, default_port: u16) -> (String, u16) {
+    let address = address.trim();
+    if let Ok(address) = address.parse::<SocketAddr>() {
+        return (address.ip().to_string(), address.port());
+    }
+    if let Ok(ip) = address.parse::<IpAddr>() {
+        return (ip.to_string(), default_port);
+    }
+    if let Some(host) = address.strip_prefix('[').and_then(|a| a.strip_suffix(']')) {
+        return (host.to_string(), default_port);
+    }
+    match address.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => match port.parse::<u16>() {
+            Ok(port) => (host.to_string(), port),
+            Err(_) => (address.to_string(), default_port),
+        },
+        _ => (address.to_string(), default_port),
+    }
+}
+
+// What TcpStream::connect takes, with the IPv6 addresses in brackets.
+pub fn socket_address(address: &str, default_port: u16) -> String {
+    let (host, port) = host_and_port(address, default_port);
+    match host.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, port).to_string(),
+        Err(_) => format!("{}:{}", host, port),
+    }
+}
+
+#[test]
+fn test_socket_address() {
+    assert_eq!(socket_address("192.168.1.10", 29999), "192.168.1.10:29999");
+    assert_eq!(
+        socket_address("192.168.1.10:30002", 29999),
+        "192.168.1.10:30002"
+    );
+    assert_eq!(socket_address("ursim", 29999), "ursim:29999");
+    assert_eq!(socket_address("ursim:50001", 29999), "ursim:50001");
+    assert_eq!(socket_address("fe80::1", 29999), "[fe80::1]:29999");
+    assert_eq!(socket_address("[fe80::1]", 29999), "[fe80::1]:29999");
+    assert_eq!(socket_address("[fe80::1]:30002", 29999), "[fe80::1]:30002");
+    assert_eq!(
+        host_and_port("[::1]:30002", 29999),
+        ("::1".to_string(), 30002)
+    );
+    assert_eq!(host_and_port("ursim", 29999), ("ursim".to_string(), 29999));
+}
@@ -0,0 +1,310 @@
+use futures::FutureExt;
+use std::fmt;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+use crate::socket_address;
+
+pub const UR_DASHBOARD_PORT: u16 = 29999;
+pub const UR_DASHBOARD_TIMEOUT: u64 = 2000;
+
+#[derive(Debug)]
+pub enum DashboardError {
+    Io(std::io::Error),
+    Timeout,
+    Closed,
+}
+
+impl fmt::Display for DashboardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DashboardError::Io(e) => write!(f, "dashboard connection failed with: {}", e),
+            DashboardError::Timeout => write!(f, "dashboard server did not answer in time"),
+            DashboardError::Closed => write!(f, "dashboard server closed the connection"),
+        }
+    }
+}
+
+impl std::error::Error for DashboardError {}
+
+impl From<std::io::Error> for DashboardError {
+    fn from(e: std::io::Error) -> Self {
+        DashboardError::Io(e)
+    }
+}
+
+/// Client for the line based UR Dashboard Server protocol. Every request is one line
+/// and gets one line back. The connection is opened on the first request and is
+/// reopened when the server has hung up, but never after a request has gone out.
+pub struct DashboardClient {
+    address: String,
+    timeout: Duration,
+    reader: Option<BufReader<OwnedReadHalf>>,
+    writer: Option<OwnedWriteHalf>,
+    welcome: Option<String>,
+}
+
+impl DashboardClient {
+    // The address is either 'host' or 'host:port'.
+    pub fn new(address: &str) -> DashboardClient {
+        DashboardClient {
+            address: socket_address(address, UR_DASHBOARD_PORT),
+            timeout: Duration::from_millis(UR_DASHBOARD_TIMEOUT),
+            reader: None,
+            writer: None,
+            welcome: None,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> DashboardClient {
+        self.timeout = timeout;
+        self
+    }
+
+    // The banner the server greeted us with on the last connect.
+    pub fn welcome(&self) -> Option<&str> {
+        self.welcome.as_deref()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.writer.is_some()
+    }
+
+    // A server that hung up since the last answer has left the end of the stream behind.
+    fn is_closed_by_server(&mut self) -> bool {
+        match self.reader.as_mut() {
+            Some(reader) => matches!(
+                reader.fill_buf().now_or_never(),
+                Some(Ok([])) | Some(Err(_))
+            ),
+            None => true,
+        }
+    }
+
+    pub async fn connect(&mut self) -> Result<String, DashboardError> {
+        self.disconnect();
+        let stream = match tokio::time::timeout(self.timeout, TcpStream::connect(&self.address)).await {
+            Ok(stream) => stream?,
+            Err(_) => return Err(DashboardError::Timeout),
+        };
+        let (reader, writer) = stream.into_split();
+        self.reader = Some(BufReader::new(reader));
+        self.writer = Some(writer);
+        match self.read_line().await {
+            Ok(welcome) => {
+                self.welcome = Some(welcome.clone());
+                Ok(welcome)
+            }
+            Err(e) => {
+                self.disconnect();
+                Err(e)
+            }
+        }
+    }
+
+    pub fn disconnect(&mut self) {
+        self.reader = None;
+        self.writer = None;
+    }
+
+    /// Sends one request and returns the response line without the line ending. The
+    /// robot may have acted on a request that went out, so a missing answer is returned
+    /// as an error instead of sending the request again.
+    pub async fn send(&mut self, request: &str) -> Result<String, DashboardError> {
+        if self.is_closed_by_server() {
+            self.connect().await?;
+        }
+        if self.write_request(request).await.is_err() {
+            // The request never reached the robot, so it is safe to send it again.
+            self.connect().await?;
+            self.write_request(request).await?;
+        }
+        let response = self.read_line().await;
+        if response.is_err() {
+            self.disconnect();
+        }
+        response
+    }
+
+    async fn write_request(&mut self, request: &str) -> Result<(), DashboardError> {
+        let writer = match self.writer.as_mut() {
+            Some(writer) => writer,
+            None => return Err(DashboardError::Closed),
+        };
+        if let Err(e) = writer.write_all(format!("{}\n", request.trim()).as_bytes()).await {
+            self.disconnect();
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    async fn read_line(&mut self) -> Result<String, DashboardError> {
+        let reader = match self.reader.as_mut() {
+            Some(reader) => reader,
+            None => return Err(DashboardError::Closed),
+        };
+        let mut line = String::new();
+        match tokio::time::timeout(self.timeout, reader.read_line(&mut line)).await {
+            Ok(Ok(0)) => Err(DashboardError::Closed),
+            Ok(Ok(_)) => Ok(line.trim_end().to_string()),
+            Ok(Err(e)) => Err(e.into()),
+            Err(_) => Err(DashboardError::Timeout),
+        }
+    }
+
+    // "Robotmode: IDLE" -> "IDLE"
+    pub async fn robot_mode(&mut self) -> Result<String, DashboardError> {
+        let response = self.send("robotmode").await?;
+        Ok(strip_dashboard_label(&response, "Robotmode:"))
+    }
+
+    // "Safetystatus: PROTECTIVE_STOP" -> "PROTECTIVE_STOP"
+    pub async fn safety_status(&mut self) -> Result<String, DashboardError> {
+        let response = self.send("safetystatus").await?;
+        Ok(strip_dashboard_label(&response, "Safetystatus:"))
+    }
+
+    // "PLAYING <program>", "PAUSED <program>" or "STOPPED <program>" -> the first word
+    pub async fn program_state(&mut self) -> Result<String, DashboardError> {
+        let response = self.send("programState").await?;
+        Ok(response.split_whitespace().next().unwrap_or("").to_string())
+    }
+
+    pub async fn is_program_running(&mut self) -> Result<bool, DashboardError> {
+        let response = self.send("running").await?;
+        Ok(strip_dashboard_label(&response, "Program running:") == "true")
+    }
+
+    pub async fn is_in_remote_control(&mut self) -> Result<bool, DashboardError> {
+        let response = self.send("is in remote control").await?;
+        Ok(response.trim() == "true")
+    }
+}
+
+fn strip_dashboard_label(response: &str, label: &str) -> String {
+    response
+        .trim()
+        .strip_prefix(label)
+        .unwrap_or(response)
+        .trim()
+        .to_string()
+}
+
+/// The dashboard server answers in plain text, even when a request is refused.
+/// This tells the expected answer apart from the refusals.
+pub fn dashboard_request_accepted(request: &str, response: &str) -> bool {
+    let expected = match request.trim() {
+        "power on" => "Powering on",
+        "power off" => "Powering off",
+        "brake release" => "Brake releasing",
+        "unlock protective stop" => "Protective stop releasing",
+        "close safety popup" => "closing safety popup",
+        "play" => "Starting program",
+        "pause" => "Pausing program",
+        "stop" => "Stopped",
+        request if request.starts_with("load ") => "Loading program",
+        _ => return !response.to_lowercase().starts_with("failed"),
+    };
+    response.trim().to_lowercase().starts_with(&expected.to_lowercase())
+}
+
+// How often the fake server was asked to hang up without an answer
+#[cfg(test)]
+static HANG_UPS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+#[cfg(test)]
+pub(crate) async fn fake_dashboard_server(close_after: usize) -> std::net::SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            writer
+                .write_all(b"Connected: Universal Robots Dashboard Server\n")
+                .await
+                .unwrap();
+            let mut answered = 0;
+            while let Ok(Some(line)) = lines.next_line().await {
+                let response = match line.as_str() {
+                    "robotmode" => "Robotmode: IDLE".to_string(),
+                    "safetystatus" => "Safetystatus: PROTECTIVE_STOP".to_string(),
+                    "programState" => "STOPPED pick.urp".to_string(),
+                    "running" => "Program running: false".to_string(),
+                    "is in remote control" => "true".to_string(),
+                    "power on" => "Powering on".to_string(),
+                    "play" => "Failed to execute: play".to_string(),
+                    "hang up" => {
+                        HANG_UPS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                        break;
+                    }
+                    "sleep" => {
+                        tokio::time::sleep(Duration::from_millis(500)).await;
+                        "awake".to_string()
+                    }
+                    other => format!("could not understand: '{}'", other),
+                };
+                writer.write_all(format!("{}\n", response).as_bytes()).await.unwrap();
+                answered += 1;
+                if answered == close_after {
+                    break;
+                }
+            }
+        }
+    });
+    address
+}
+
+#[tokio::test]
+async fn test_dashboard_client() {
+    let address = fake_dashboard_server(usize::MAX).await;
+    let mut client = DashboardClient::new(&address.to_string());
+    assert_eq!(
+        client.connect().await.unwrap(),
+        "Connected: Universal Robots Dashboard Server"
+    );
+    assert_eq!(client.robot_mode().await.unwrap(), "IDLE");
+    assert_eq!(client.safety_status().await.unwrap(), "PROTECTIVE_STOP");
+    assert_eq!(client.program_state().await.unwrap(), "STOPPED");
+    assert!(!client.is_program_running().await.unwrap());
+    assert!(client.is_in_remote_control().await.unwrap());
+
+    let response = client.send("power on").await.unwrap();
+    assert!(dashboard_request_accepted("power on", &response));
+    let response = client.send("play").await.unwrap();
+    assert!(!dashboard_request_accepted("play", &response));
+}
+
+#[tokio::test]
+async fn test_dashboard_client_reconnects() {
+    // The server hangs up after every answer
+    let address = fake_dashboard_server(1).await;
+    let mut client = DashboardClient::new(&address.to_string());
+    assert_eq!(client.robot_mode().await.unwrap(), "IDLE");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(client.safety_status().await.unwrap(), "PROTECTIVE_STOP");
+    assert!(client.welcome().is_some());
+}
+
+#[tokio::test]
+async fn test_dashboard_client_sends_once() {
+    // The server hangs up after reading the request, the robot may have acted on it
+    let address = fake_dashboard_server(usize::MAX).await;
+    let mut client = DashboardClient::new(&address.to_string());
+    assert!(matches!(client.send("hang up").await, Err(DashboardError::Closed)));
+    assert_eq!(HANG_UPS.load(std::sync::atomic::Ordering::SeqCst), 1);
+    assert_eq!(client.robot_mode().await.unwrap(), "IDLE");
+}
+
+#[tokio::test]
+async fn test_dashboard_client_timeout() {
+    let address = fake_dashboard_server(usize::MAX).await;
+    let mut client =
+        DashboardClient::new(&address.to_string()).with_timeout(Duration::from_millis(100));
+    assert!(matches!(client.send("sleep").await, Err(DashboardError::Timeout)));
+    assert!(!client.is_connected());
+}
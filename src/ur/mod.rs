@@ -0,0 +1,7 @@
+pub mod address;
+pub mod dashboard;
+pub mod primary_interface;
+pub mod protective_stop;
+pub mod robot_status;
+pub mod rtde;
+pub mod script_sender;
\ No newline at end of file
@@ -0,0 +1,493 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+use crate::socket_address;
+
+pub const UR_PRIMARY_PORT: u16 = 30001;
+// How many robot messages we remember for the diagnosis of a failing request
+pub const UR_DIAGNOSTICS_CAPACITY: usize = 50;
+// How long a failed request waits for the messages that explain it [ms]
+pub const UR_DIAGNOSTICS_GRACE_PERIOD: u64 = 250;
+
+const ROBOT_STATE: u8 = 16;
+const ROBOT_MESSAGE: u8 = 20;
+
+const ROBOT_MODE_DATA: u8 = 0;
+
+const ROBOT_MESSAGE_TEXT: u8 = 0;
+const ROBOT_MESSAGE_VERSION: u8 = 3;
+const ROBOT_MESSAGE_SAFETY_MODE: u8 = 5;
+const ROBOT_MESSAGE_ERROR_CODE: u8 = 6;
+const ROBOT_MESSAGE_KEY: u8 = 7;
+const ROBOT_MESSAGE_RUNTIME_EXCEPTION: u8 = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrimaryMessage {
+    RobotModeData {
+        timestamp: u64,
+        is_robot_power_on: bool,
+        is_emergency_stopped: bool,
+        is_protective_stopped: bool,
+        is_program_running: bool,
+        is_program_paused: bool,
+        robot_mode: u8,
+        control_mode: u8,
+        speed_scaling: f64,
+    },
+    Text {
+        timestamp: u64,
+        text: String,
+    },
+    Version {
+        project_name: String,
+        major: u8,
+        minor: u8,
+        bugfix: i32,
+        build: i32,
+        build_date: String,
+    },
+    SafetyModeChange {
+        timestamp: u64,
+        code: i32,
+        argument: i32,
+        safety_mode: u8,
+    },
+    ErrorCode {
+        timestamp: u64,
+        code: i32,
+        argument: i32,
+        report_level: i32,
+        text: String,
+    },
+    Key {
+        timestamp: u64,
+        code: i32,
+        argument: i32,
+        title: String,
+        text: String,
+    },
+    RuntimeException {
+        timestamp: u64,
+        line: i32,
+        column: i32,
+        text: String,
+    },
+}
+
+pub fn safety_mode_name(safety_mode: u8) -> &'static str {
+    match safety_mode {
+        1 => "NORMAL",
+        2 => "REDUCED",
+        3 => "PROTECTIVE_STOP",
+        4 => "RECOVERY",
+        5 => "SAFEGUARD_STOP",
+        6 => "SYSTEM_EMERGENCY_STOP",
+        7 => "ROBOT_EMERGENCY_STOP",
+        8 => "VIOLATION",
+        9 => "FAULT",
+        10 => "VALIDATE_JOINT_ID",
+        12 => "AUTOMATIC_MODE_SAFEGUARD_STOP",
+        13 => "SYSTEM_THREE_POSITION_ENABLING_STOP",
+        _ => "UNDEFINED_SAFETY_MODE",
+    }
+}
+
+impl PrimaryMessage {
+    /// What an operator would want to read when a request fails, if anything.
+    pub fn diagnostic(&self) -> Option<String> {
+        match self {
+            PrimaryMessage::RuntimeException { line, column, text, .. } => Some(format!(
+                "Runtime exception at line {}, column {}: {}",
+                line, column, text
+            )),
+            PrimaryMessage::SafetyModeChange { code, argument, safety_mode, .. } => Some(format!(
+                "Safety mode changed to {} (C{}A{})",
+                safety_mode_name(*safety_mode),
+                code,
+                argument
+            )),
+            PrimaryMessage::ErrorCode { code, argument, text, .. } => {
+                Some(format!("Error C{}A{}: {}", code, argument, text))
+            }
+            PrimaryMessage::Key { title, text, .. } => Some(match text.is_empty() {
+                true => title.clone(),
+                false => format!("{}: {}", title, text),
+            }),
+            _ => None,
+        }
+    }
+}
+
+// Big endian reads that fail instead of panicking on a short package
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(bytes: &'a [u8]) -> Cursor<'a> {
+        Cursor { bytes, offset: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        match self.bytes.get(self.offset..self.offset + n) {
+            Some(bytes) => {
+                self.offset += n;
+                Ok(bytes)
+            }
+            None => Err(format!(
+                "package ended at {} bytes, needed {} more after {}",
+                self.bytes.len(),
+                n,
+                self.offset
+            )),
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, String> {
+        Ok(self.u8()? != 0)
+    }
+
+    fn i32(&mut self) -> Result<i32, String> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> Result<f64, String> {
+        Ok(f64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self, n: usize) -> Result<String, String> {
+        Ok(String::from_utf8_lossy(self.take(n)?).to_string())
+    }
+
+    fn rest(&mut self) -> String {
+        let rest = String::from_utf8_lossy(&self.bytes[self.offset.min(self.bytes.len())..]);
+        self.offset = self.bytes.len();
+        rest.to_string()
+    }
+}
+
+/// Parses one packet as sent on the primary and secondary interfaces, starting with the
+/// 4 byte length and the message type. Unknown packages are skipped, so a packet can
+/// give no messages at all.
+pub fn parse_primary_packet(packet: &[u8]) -> Result<Vec<PrimaryMessage>, String> {
+    let mut cursor = Cursor::new(packet);
+    let length = cursor.u32()? as usize;
+    if length != packet.len() {
+        return Err(format!("packet says {} bytes, got {}", length, packet.len()));
+    }
+    match cursor.u8()? {
+        ROBOT_STATE => {
+            let mut messages = vec![];
+            while cursor.offset < packet.len() {
+                let length = cursor.u32()? as usize;
+                if length < 5 {
+                    return Err(format!("sub package of {} bytes", length));
+                }
+                let package_type = cursor.u8()?;
+                let package = cursor.take(length - 5)?;
+                if package_type == ROBOT_MODE_DATA {
+                    messages.push(parse_robot_mode_data(package)?);
+                }
+            }
+            Ok(messages)
+        }
+        ROBOT_MESSAGE => Ok(parse_robot_message(&packet[5..])?.into_iter().collect()),
+        _ => Ok(vec![]),
+    }
+}
+
+fn parse_robot_mode_data(package: &[u8]) -> Result<PrimaryMessage, String> {
+    let mut cursor = Cursor::new(package);
+    let timestamp = cursor.u64()?;
+    let _is_real_robot_connected = cursor.bool()?;
+    let _is_real_robot_enabled = cursor.bool()?;
+    Ok(PrimaryMessage::RobotModeData {
+        timestamp,
+        is_robot_power_on: cursor.bool()?,
+        is_emergency_stopped: cursor.bool()?,
+        is_protective_stopped: cursor.bool()?,
+        is_program_running: cursor.bool()?,
+        is_program_paused: cursor.bool()?,
+        robot_mode: cursor.u8()?,
+        control_mode: cursor.u8()?,
+        speed_scaling: {
+            let _target_speed_fraction = cursor.f64()?;
+            cursor.f64()?
+        },
+    })
+}
+
+fn parse_robot_message(message: &[u8]) -> Result<Option<PrimaryMessage>, String> {
+    let mut cursor = Cursor::new(message);
+    let timestamp = cursor.u64()?;
+    let _source = cursor.u8()?;
+    let message = match cursor.u8()? {
+        ROBOT_MESSAGE_TEXT => PrimaryMessage::Text {
+            timestamp,
+            text: cursor.rest(),
+        },
+        ROBOT_MESSAGE_VERSION => {
+            let project_name_length = cursor.u8()? as usize;
+            PrimaryMessage::Version {
+                project_name: cursor.string(project_name_length)?,
+                major: cursor.u8()?,
+                minor: cursor.u8()?,
+                bugfix: cursor.i32()?,
+                build: cursor.i32()?,
+                build_date: cursor.rest(),
+            }
+        }
+        ROBOT_MESSAGE_SAFETY_MODE => PrimaryMessage::SafetyModeChange {
+            timestamp,
+            code: cursor.i32()?,
+            argument: cursor.i32()?,
+            safety_mode: cursor.u8()?,
+        },
+        ROBOT_MESSAGE_ERROR_CODE => {
+            let code = cursor.i32()?;
+            let argument = cursor.i32()?;
+            let report_level = cursor.i32()?;
+            let _data_type = cursor.u8()?;
+            let _data = cursor.u32()?;
+            PrimaryMessage::ErrorCode {
+                timestamp,
+                code,
+                argument,
+                report_level,
+                text: cursor.rest(),
+            }
+        }
+        ROBOT_MESSAGE_KEY => {
+            let code = cursor.i32()?;
+            let argument = cursor.i32()?;
+            let title_length = cursor.u8()? as usize;
+            PrimaryMessage::Key {
+                timestamp,
+                code,
+                argument,
+                title: cursor.string(title_length)?,
+                text: cursor.rest(),
+            }
+        }
+        ROBOT_MESSAGE_RUNTIME_EXCEPTION => PrimaryMessage::RuntimeException {
+            timestamp,
+            line: cursor.i32()?,
+            column: cursor.i32()?,
+            text: cursor.rest(),
+        },
+        _ => return Ok(None),
+    };
+    Ok(Some(message))
+}
+
+/// The last diagnostic messages of the robot, shared between the task that listens
+/// to the primary interface and the action client that attaches them to a failed request.
+#[derive(Debug, Clone, Default)]
+pub struct RobotDiagnostics {
+    messages: Arc<Mutex<VecDeque<(SystemTime, String)>>>,
+}
+
+impl RobotDiagnostics {
+    pub fn record(&self, message: String) {
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() == UR_DIAGNOSTICS_CAPACITY {
+            messages.pop_front();
+        }
+        messages.push_back((SystemTime::now(), message));
+    }
+
+    pub fn since(&self, time: SystemTime) -> Vec<String> {
+        self.messages
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(received, _)| *received >= time)
+            .map(|(_, message)| message.clone())
+            .collect()
+    }
+}
+
+async fn read_primary_packet(stream: &mut TcpStream) -> Result<Vec<u8>, std::io::Error> {
+    let mut length = [0u8; 4];
+    stream.read_exact(&mut length).await?;
+    let mut packet = length.to_vec();
+    packet.resize(u32::from_be_bytes(length).max(4) as usize, 0);
+    stream.read_exact(&mut packet[4..]).await?;
+    Ok(packet)
+}
+
+pub async fn primary_interface_monitor(
+    robot_name: &str,
+    ur_address: &str,
+    diagnostics: &RobotDiagnostics,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let log_target = &format!("{robot_name}_primary_interface");
+    let ur_address = socket_address(ur_address, UR_PRIMARY_PORT);
+    loop {
+        let mut stream = match TcpStream::connect(&ur_address).await {
+            Ok(stream) => stream,
+            Err(_) => {
+                tokio::time::sleep(Duration::from_millis(1000)).await;
+                continue;
+            }
+        };
+        while let Ok(packet) = read_primary_packet(&mut stream).await {
+            let messages = match parse_primary_packet(&packet) {
+                Ok(messages) => messages,
+                Err(e) => {
+                    r2r::log_warn!(log_target, "Failed to parse a primary interface packet, {}.", e);
+                    continue;
+                }
+            };
+            for message in messages {
+                if let PrimaryMessage::Version { major, minor, bugfix, build, .. } = &message {
+                    r2r::log_info!(
+                        log_target,
+                        "Connected to controller version {}.{}.{}.{}.",
+                        major,
+                        minor,
+                        bugfix,
+                        build
+                    );
+                }
+                if let Some(diagnostic) = message.diagnostic() {
+                    r2r::log_warn!(log_target, "{}", diagnostic);
+                    diagnostics.record(diagnostic);
+                }
+            }
+        }
+        r2r::log_warn!(log_target, "Lost the primary interface, reconnecting.");
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+    }
+}
+
+// Runtime exception for 'movej(undefined_pose)' on line 14, column 5 of a program
+#[cfg(test)]
+static RUNTIME_EXCEPTION_CAPTURE: [u8; 66] = [
+    0x00, 0x00, 0x00, 0x42, 0x14, 0x00, 0x00, 0x01, 0x8d, 0x2a, 0x1b, 0x3c, 0x10, 0xfe, 0x0a, 0x00,
+    0x00, 0x00, 0x0e, 0x00, 0x00, 0x00, 0x05, 0x63, 0x6f, 0x6d, 0x70, 0x69, 0x6c, 0x65, 0x5f, 0x65,
+    0x72, 0x72, 0x6f, 0x72, 0x5f, 0x6e, 0x61, 0x6d, 0x65, 0x5f, 0x6e, 0x6f, 0x74, 0x5f, 0x66, 0x6f,
+    0x75, 0x6e, 0x64, 0x3a, 0x75, 0x6e, 0x64, 0x65, 0x66, 0x69, 0x6e, 0x65, 0x64, 0x5f, 0x70, 0x6f,
+    0x73, 0x65,
+];
+
+// Safety mode change to PROTECTIVE_STOP, C153A1
+#[cfg(test)]
+static SAFETY_MODE_CAPTURE: [u8; 32] = [
+    0x00, 0x00, 0x00, 0x20, 0x14, 0x00, 0x00, 0x01, 0x8d, 0x2a, 0x1b, 0x3c, 0x10, 0xfe, 0x05, 0x00,
+    0x00, 0x00, 0x99, 0x00, 0x00, 0x00, 0x01, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+// Controller version 5.15.2.117 "URControl"
+#[cfg(test)]
+static VERSION_CAPTURE: [u8; 55] = [
+    0x00, 0x00, 0x00, 0x37, 0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xfd, 0x03, 0x09,
+    0x55, 0x52, 0x43, 0x6f, 0x6e, 0x74, 0x72, 0x6f, 0x6c, 0x05, 0x0f, 0x00, 0x00, 0x00, 0x02, 0x00,
+    0x00, 0x00, 0x75, 0x30, 0x36, 0x2d, 0x31, 0x32, 0x2d, 0x32, 0x30, 0x32, 0x33, 0x2c, 0x20, 0x31,
+    0x32, 0x3a, 0x30, 0x30, 0x3a, 0x30, 0x30,
+];
+
+#[test]
+fn test_parse_robot_messages() {
+    assert_eq!(
+        parse_primary_packet(&RUNTIME_EXCEPTION_CAPTURE).unwrap(),
+        vec![PrimaryMessage::RuntimeException {
+            timestamp: 0x018d2a1b3c10,
+            line: 14,
+            column: 5,
+            text: "compile_error_name_not_found:undefined_pose".to_string(),
+        }]
+    );
+
+    let messages = parse_primary_packet(&SAFETY_MODE_CAPTURE).unwrap();
+    assert_eq!(
+        messages,
+        vec![PrimaryMessage::SafetyModeChange {
+            timestamp: 0x018d2a1b3c10,
+            code: 153,
+            argument: 1,
+            safety_mode: 3,
+        }]
+    );
+    assert_eq!(
+        messages[0].diagnostic().unwrap(),
+        "Safety mode changed to PROTECTIVE_STOP (C153A1)"
+    );
+
+    let messages = parse_primary_packet(&VERSION_CAPTURE).unwrap();
+    assert!(matches!(
+        &messages[0],
+        PrimaryMessage::Version { project_name, major: 5, minor: 15, bugfix: 2, build: 117, .. }
+            if project_name == "URControl"
+    ));
+    assert_eq!(messages[0].diagnostic(), None);
+
+    // Cut short in the middle of the line number
+    assert!(parse_primary_packet(&RUNTIME_EXCEPTION_CAPTURE[..18]).is_err());
+}
+
+#[test]
+fn test_parse_robot_state() {
+    let mut robot_mode_data = vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0xe8];
+    robot_mode_data.extend([1, 1, 1, 0, 1, 0, 0, 7, 0]);
+    robot_mode_data.extend(1.0f64.to_be_bytes());
+    robot_mode_data.extend(0.5f64.to_be_bytes());
+    robot_mode_data.extend(1.0f64.to_be_bytes());
+    robot_mode_data.push(0);
+    // Joint data, which we skip
+    let joint_data = vec![0u8; 41 * 6];
+
+    let mut packet = vec![0, 0, 0, 0, ROBOT_STATE];
+    for (package_type, package) in [(ROBOT_MODE_DATA, &robot_mode_data), (1, &joint_data)] {
+        packet.extend(((package.len() + 5) as u32).to_be_bytes());
+        packet.push(package_type);
+        packet.extend(package);
+    }
+    let length = (packet.len() as u32).to_be_bytes();
+    packet[..4].copy_from_slice(&length);
+
+    assert_eq!(
+        parse_primary_packet(&packet).unwrap(),
+        vec![PrimaryMessage::RobotModeData {
+            timestamp: 1000,
+            is_robot_power_on: true,
+            is_emergency_stopped: false,
+            is_protective_stopped: true,
+            is_program_running: false,
+            is_program_paused: false,
+            robot_mode: 7,
+            control_mode: 0,
+            speed_scaling: 0.5,
+        }]
+    );
+}
+
+#[test]
+fn test_robot_diagnostics() {
+    let diagnostics = RobotDiagnostics::default();
+    diagnostics.record("old".to_string());
+    let start = SystemTime::now();
+    std::thread::sleep(Duration::from_millis(5));
+    diagnostics.record("new".to_string());
+    assert_eq!(diagnostics.since(start), vec!["new".to_string()]);
+    for i in 0..UR_DIAGNOSTICS_CAPACITY {
+        diagnostics.record(i.to_string());
+    }
+    assert_eq!(diagnostics.since(start).len(), UR_DIAGNOSTICS_CAPACITY);
+}
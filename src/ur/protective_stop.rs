@@ -0,0 +1,113 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::*;
+
+// The request state of a request that was aborted by a protective stop
+pub static FAILED_PROTECTIVE_STOP: &str = "failed_protective_stop";
+
+// The controller refuses to unlock a protective stop earlier than 5 seconds after it happened.
+pub const PROTECTIVE_STOP_UNLOCK_DELAY: u64 = 5000;
+// The longest wait between two recovery attempts [ms]
+pub const PROTECTIVE_STOP_MAX_BACKOFF: u64 = 60000;
+
+/// Whether the controller unlocks protective stops by itself. After max_recoveries_per_hour
+/// automatic recoveries within the last hour, an operator has to take over.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtectiveStopRecovery {
+    pub enabled: bool,
+    pub max_recoveries_per_hour: usize,
+}
+
+impl Default for ProtectiveStopRecovery {
+    fn default() -> Self {
+        ProtectiveStopRecovery {
+            enabled: false,
+            max_recoveries_per_hour: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RecoveryHistory {
+    recoveries: VecDeque<Instant>,
+    // Failed recoveries in a row, each one doubles the wait before the next attempt
+    failures: u32,
+}
+
+impl RecoveryHistory {
+    /// Records a recovery at now if the hourly budget allows it.
+    pub fn try_record(&mut self, now: Instant, max_recoveries_per_hour: usize) -> bool {
+        while let Some(oldest) = self.recoveries.front() {
+            match now.duration_since(*oldest) >= Duration::from_secs(3600) {
+                true => self.recoveries.pop_front(),
+                false => break,
+            };
+        }
+        if self.recoveries.len() >= max_recoveries_per_hour {
+            return false;
+        }
+        self.recoveries.push_back(now);
+        true
+    }
+
+    pub fn record_result(&mut self, recovered: bool) {
+        self.failures = match recovered {
+            true => 0,
+            false => self.failures.saturating_add(1),
+        };
+    }
+
+    /// How long to wait after an attempt before the next one, if the arm is still stopped.
+    pub fn backoff(&self) -> Duration {
+        let backoff = PROTECTIVE_STOP_UNLOCK_DELAY.saturating_mul(1 << self.failures.min(16));
+        Duration::from_millis(backoff.min(PROTECTIVE_STOP_MAX_BACKOFF))
+    }
+}
+
+/// Waits out the unlock delay, unlocks the protective stop and starts the
+/// loaded program again if it was running when the robot stopped.
+pub async fn recover_from_protective_stop(
+    client: &mut DashboardClient,
+    restart_program: bool,
+) -> Result<(), String> {
+    tokio::time::sleep(Duration::from_millis(PROTECTIVE_STOP_UNLOCK_DELAY)).await;
+
+    let response = client
+        .send("unlock protective stop")
+        .await
+        .map_err(|e| e.to_string())?;
+    if !dashboard_request_accepted("unlock protective stop", &response) {
+        return Err(response);
+    }
+
+    if restart_program && !client.is_program_running().await.map_err(|e| e.to_string())? {
+        let response = client.send("play").await.map_err(|e| e.to_string())?;
+        if !dashboard_request_accepted("play", &response) {
+            return Err(response);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_recovery_history() {
+    let mut history = RecoveryHistory::default();
+    let start = Instant::now();
+    assert!(history.try_record(start, 2));
+    assert!(history.try_record(start + Duration::from_secs(60), 2));
+    assert!(!history.try_record(start + Duration::from_secs(120), 2));
+    // The first one is out of the window after an hour
+    assert!(history.try_record(start + Duration::from_secs(3600), 2));
+    assert!(!history.try_record(start + Duration::from_secs(3601), 2));
+    assert!(!history.try_record(start, 0));
+
+    assert_eq!(history.backoff(), Duration::from_millis(PROTECTIVE_STOP_UNLOCK_DELAY));
+    history.record_result(false);
+    history.record_result(false);
+    assert_eq!(history.backoff(), Duration::from_millis(4 * PROTECTIVE_STOP_UNLOCK_DELAY));
+    (0..20).for_each(|_| history.record_result(false));
+    assert_eq!(history.backoff(), Duration::from_millis(PROTECTIVE_STOP_MAX_BACKOFF));
+    history.record_result(true);
+    assert_eq!(history.backoff(), Duration::from_millis(PROTECTIVE_STOP_UNLOCK_DELAY));
+}
@@ -0,0 +1,242 @@
+use micro_sp::ToSPValue;
+use micro_sp::*;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::*;
+
+pub const UR_ROBOT_STATUS_TICKER_RATE: u64 = 500;
+
+/// What the dashboard server tells us about the arm, independent of any request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RobotStatus {
+    // NO_CONTROLLER, DISCONNECTED, CONFIRM_SAFETY, BOOTING, POWER_OFF, POWER_ON, IDLE, BACKDRIVE or RUNNING
+    pub robot_mode: String,
+    // NORMAL, REDUCED, PROTECTIVE_STOP, RECOVERY, SAFEGUARD_STOP, SYSTEM_EMERGENCY_STOP, ...
+    pub safety_status: String,
+    pub program_running: bool,
+    pub remote_control: bool,
+}
+
+impl RobotStatus {
+    pub async fn read(client: &mut DashboardClient) -> Result<RobotStatus, DashboardError> {
+        Ok(RobotStatus {
+            robot_mode: client.robot_mode().await?,
+            safety_status: client.safety_status().await?,
+            program_running: client.is_program_running().await?,
+            remote_control: client.is_in_remote_control().await?,
+        })
+    }
+
+    pub fn is_protective_stop(&self) -> bool {
+        self.safety_status == "PROTECTIVE_STOP"
+    }
+}
+
+pub async fn robot_status_to_redis(
+    robot_name: &str,
+    ur_address: &str,
+    connection_manager: &Arc<ConnectionManager>,
+    protective_stop_recovery: &ProtectiveStopRecovery,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let log_target = &format!("{robot_name}_robot_status");
+    let mut client = DashboardClient::new(ur_address);
+    let mut interval =
+        tokio::time::interval(std::time::Duration::from_millis(UR_ROBOT_STATUS_TICKER_RATE));
+    // A slow dashboard server shouldn't make the missed ticks burst afterwards
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut available = true;
+    let mut previous_status: Option<RobotStatus> = None;
+    let mut recovery_history = RecoveryHistory::default();
+    let mut protective_stop_count: i64 = 0;
+    // The recovery runs next to the polling, and is retried with a backoff until
+    // the arm is out of the protective stop or the hourly budget is used up
+    let mut recovery: Option<tokio::task::JoinHandle<Result<(), String>>> = None;
+    let mut next_attempt: Option<Instant> = None;
+    let mut restart_program = false;
+
+    let mut con = connection_manager.get_connection().await;
+    loop {
+        interval.tick().await;
+        if let Err(_) = connection_manager.check_redis_health(&log_target).await {
+            continue;
+        }
+
+        // Rather unknown than stale, the planners should not act on an old status
+        let status = RobotStatus::read(&mut client).await;
+        let values = match &status {
+            Ok(status) => {
+                if !available {
+                    r2r::log_info!(log_target, "Robot {robot_name} status available again.");
+                    available = true;
+                }
+                vec![
+                    ("robot_mode", status.robot_mode.to_spvalue()),
+                    ("safety_status", status.safety_status.to_spvalue()),
+                    ("program_running", status.program_running.to_spvalue()),
+                    ("remote_control", status.remote_control.to_spvalue()),
+                ]
+            }
+            Err(e) => {
+                if available {
+                    r2r::log_warn!(log_target, "Failed to read the {robot_name} status, {}.", e);
+                    available = false;
+                }
+                vec![
+                    ("robot_mode", SPValue::String(StringOrUnknown::UNKNOWN)),
+                    ("safety_status", SPValue::String(StringOrUnknown::UNKNOWN)),
+                    ("program_running", SPValue::Bool(BoolOrUnknown::UNKNOWN)),
+                    ("remote_control", SPValue::Bool(BoolOrUnknown::UNKNOWN)),
+                ]
+            }
+        };
+
+        for (key, value) in values {
+            StateManager::set_sp_value(&mut con, &format!("{robot_name}_{key}"), &value).await;
+        }
+
+        if let Some(finished) = recovery.take_if(|recovery| recovery.is_finished()) {
+            let result = finished
+                .await
+                .unwrap_or_else(|e| Err(format!("the recovery task panicked, {}", e)));
+            let recovery_state = match result {
+                Ok(()) => {
+                    r2r::log_info!(log_target, "Robot {robot_name} recovered from the protective stop.");
+                    "recovered"
+                }
+                Err(e) => {
+                    r2r::log_error!(
+                        log_target,
+                        "Robot {robot_name} failed to recover from the protective stop: {}.",
+                        e
+                    );
+                    "recovery_failed"
+                }
+            };
+            recovery_history.record_result(result.is_ok());
+            set_recovery_state(robot_name, connection_manager, recovery_state).await;
+            // Only tried again if the arm is still stopped by then
+            next_attempt = Some(Instant::now() + recovery_history.backoff());
+        }
+
+        let status = match status {
+            Ok(status) => status,
+            Err(_) => continue,
+        };
+
+        let was_protective_stop = previous_status
+            .as_ref()
+            .is_some_and(|previous| previous.is_protective_stop());
+        if status.is_protective_stop() && !was_protective_stop {
+            protective_stop_count += 1;
+            StateManager::set_sp_value(
+                &mut con,
+                &format!("{robot_name}_protective_stop_count"),
+                &protective_stop_count.to_spvalue(),
+            )
+            .await;
+            restart_program = previous_status
+                .as_ref()
+                .is_some_and(|previous| previous.program_running);
+            match protective_stop_recovery.enabled {
+                true => next_attempt = Some(Instant::now()),
+                false => {
+                    r2r::log_warn!(log_target, "Robot {robot_name} is in a protective stop.");
+                    set_recovery_state(robot_name, connection_manager, "operator_required").await;
+                }
+            }
+        }
+        if !status.is_protective_stop() && recovery.is_none() {
+            next_attempt = None;
+        }
+
+        let due = next_attempt.is_some_and(|next_attempt| Instant::now() >= next_attempt);
+        if status.is_protective_stop() && recovery.is_none() && due {
+            match recovery_history.try_record(
+                Instant::now(),
+                protective_stop_recovery.max_recoveries_per_hour,
+            ) {
+                true => {
+                    r2r::log_warn!(
+                        log_target,
+                        "Robot {robot_name} is in a protective stop, recovering in {} ms.",
+                        PROTECTIVE_STOP_UNLOCK_DELAY
+                    );
+                    set_recovery_state(robot_name, connection_manager, "recovering").await;
+                    // On its own connection, so that the status keeps coming while it waits
+                    let mut recovery_client = DashboardClient::new(ur_address);
+                    recovery = Some(tokio::task::spawn(async move {
+                        recover_from_protective_stop(&mut recovery_client, restart_program).await
+                    }));
+                }
+                false => {
+                    r2r::log_error!(
+                        log_target,
+                        "Robot {robot_name} is in a protective stop, but it was already recovered {} times in the last hour.",
+                        protective_stop_recovery.max_recoveries_per_hour
+                    );
+                    set_recovery_state(robot_name, connection_manager, "operator_required").await;
+                    next_attempt = None;
+                }
+            }
+        }
+        previous_status = Some(status);
+    }
+}
+
+/// Whether the status poller reports a protective stop within the timeout. The
+/// poller publishes {robot}_safety_status every UR_ROBOT_STATUS_TICKER_RATE ms, so a
+/// stop that just happened can take up to a tick to show.
+pub async fn wait_for_protective_stop(
+    robot_name: &str,
+    connection_manager: &Arc<ConnectionManager>,
+    timeout: u64,
+) -> bool {
+    let log_target = &format!("{robot_name}_robot_status");
+    let keys = vec![format!("{robot_name}_safety_status")];
+    let mut con = connection_manager.get_connection().await;
+    let started = Instant::now();
+    let mut interval = tokio::time::interval(std::time::Duration::from_millis(SETTLE_TICKER_RATE));
+    loop {
+        interval.tick().await;
+        if let Some(state) = StateManager::get_state_for_keys(&mut con, &keys).await {
+            if state.get_string_or_default_to_unknown(&keys[0], log_target) == "PROTECTIVE_STOP" {
+                return true;
+            }
+        }
+        if started.elapsed() >= std::time::Duration::from_millis(timeout) {
+            return false;
+        }
+    }
+}
+
+async fn set_recovery_state(
+    robot_name: &str,
+    connection_manager: &Arc<ConnectionManager>,
+    recovery_state: &str,
+) {
+    let mut con = connection_manager.get_connection().await;
+    StateManager::set_sp_value(
+        &mut con,
+        &format!("{robot_name}_protective_stop_recovery"),
+        &recovery_state.to_spvalue(),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_read_robot_status() {
+    let address = fake_dashboard_server(usize::MAX).await;
+    let mut client = DashboardClient::new(&address.to_string());
+    let status = RobotStatus::read(&mut client).await.unwrap();
+    assert_eq!(
+        status,
+        RobotStatus {
+            robot_mode: "IDLE".to_string(),
+            safety_status: "PROTECTIVE_STOP".to_string(),
+            program_running: false,
+            remote_control: true,
+        }
+    );
+    assert!(status.is_protective_stop());
+}
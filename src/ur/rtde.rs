@@ -0,0 +1,844 @@
+use micro_sp::ToSPValue;
+use micro_sp::*;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::socket_address;
+
+pub const UR_RTDE_PORT: u16 = 30004;
+pub const UR_RTDE_PROTOCOL_VERSION: u16 = 2;
+pub const UR_RTDE_TIMEOUT: u64 = 2000;
+
+// What we mirror to Redis, as {robot_name}_rtde_{lowercase variable name}
+pub static RTDE_OUTPUTS: [&str; 10] = [
+    "timestamp",
+    "actual_q",
+    "actual_qd",
+    "actual_TCP_pose",
+    "actual_TCP_force",
+    "actual_digital_input_bits",
+    "actual_digital_output_bits",
+    "robot_mode",
+    "safety_mode",
+    "speed_scaling",
+];
+
+const RTDE_REQUEST_PROTOCOL_VERSION: u8 = b'V';
+const RTDE_GET_URCONTROL_VERSION: u8 = b'v';
+const RTDE_TEXT_MESSAGE: u8 = b'M';
+const RTDE_DATA_PACKAGE: u8 = b'U';
+const RTDE_CONTROL_PACKAGE_SETUP_OUTPUTS: u8 = b'O';
+const RTDE_CONTROL_PACKAGE_SETUP_INPUTS: u8 = b'I';
+const RTDE_CONTROL_PACKAGE_START: u8 = b'S';
+const RTDE_CONTROL_PACKAGE_PAUSE: u8 = b'P';
+
+#[derive(Debug)]
+pub enum RtdeError {
+    Io(std::io::Error),
+    Timeout,
+    Protocol(String),
+}
+
+impl fmt::Display for RtdeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RtdeError::Io(e) => write!(f, "RTDE connection failed with: {}", e),
+            RtdeError::Timeout => write!(f, "RTDE server did not answer in time"),
+            RtdeError::Protocol(e) => write!(f, "RTDE protocol error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RtdeError {}
+
+impl From<std::io::Error> for RtdeError {
+    fn from(e: std::io::Error) -> Self {
+        RtdeError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RtdeType {
+    Bool,
+    UInt8,
+    UInt32,
+    UInt64,
+    Int32,
+    Double,
+    Vector3D,
+    Vector6D,
+    Vector6Int32,
+    Vector6UInt32,
+}
+
+impl RtdeType {
+    // The controller answers a recipe setup with the type of every variable.
+    pub fn from_name(variable: &str, type_name: &str) -> Result<RtdeType, RtdeError> {
+        match type_name {
+            "BOOL" => Ok(RtdeType::Bool),
+            "UINT8" => Ok(RtdeType::UInt8),
+            "UINT32" => Ok(RtdeType::UInt32),
+            "UINT64" => Ok(RtdeType::UInt64),
+            "INT32" => Ok(RtdeType::Int32),
+            "DOUBLE" => Ok(RtdeType::Double),
+            "VECTOR3D" => Ok(RtdeType::Vector3D),
+            "VECTOR6D" => Ok(RtdeType::Vector6D),
+            "VECTOR6INT32" => Ok(RtdeType::Vector6Int32),
+            "VECTOR6UINT32" => Ok(RtdeType::Vector6UInt32),
+            "NOT_FOUND" => Err(RtdeError::Protocol(format!("'{}' does not exist", variable))),
+            "IN_USE" => Err(RtdeError::Protocol(format!("'{}' is used by another client", variable))),
+            other => Err(RtdeError::Protocol(format!("'{}' has unknown type '{}'", variable, other))),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            RtdeType::Bool | RtdeType::UInt8 => 1,
+            RtdeType::UInt32 | RtdeType::Int32 => 4,
+            RtdeType::UInt64 | RtdeType::Double => 8,
+            RtdeType::Vector3D => 24,
+            RtdeType::Vector6D => 48,
+            RtdeType::Vector6Int32 | RtdeType::Vector6UInt32 => 24,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RtdeValue {
+    Bool(bool),
+    UInt8(u8),
+    UInt32(u32),
+    UInt64(u64),
+    Int32(i32),
+    Double(f64),
+    Vector3D([f64; 3]),
+    Vector6D([f64; 6]),
+    Vector6Int32([i32; 6]),
+    Vector6UInt32([u32; 6]),
+}
+
+impl RtdeValue {
+    // Everything is big endian on the wire, bytes has to be exactly rtde_type.size() long.
+    pub fn decode(rtde_type: RtdeType, bytes: &[u8]) -> RtdeValue {
+        let f64_at = |i: usize| f64::from_be_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+        let u32_at = |i: usize| u32::from_be_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap());
+        match rtde_type {
+            RtdeType::Bool => RtdeValue::Bool(bytes[0] != 0),
+            RtdeType::UInt8 => RtdeValue::UInt8(bytes[0]),
+            RtdeType::UInt32 => RtdeValue::UInt32(u32_at(0)),
+            RtdeType::UInt64 => RtdeValue::UInt64(u64::from_be_bytes(bytes[..8].try_into().unwrap())),
+            RtdeType::Int32 => RtdeValue::Int32(u32_at(0) as i32),
+            RtdeType::Double => RtdeValue::Double(f64_at(0)),
+            RtdeType::Vector3D => RtdeValue::Vector3D(std::array::from_fn(f64_at)),
+            RtdeType::Vector6D => RtdeValue::Vector6D(std::array::from_fn(f64_at)),
+            RtdeType::Vector6Int32 => RtdeValue::Vector6Int32(std::array::from_fn(|i| u32_at(i) as i32)),
+            RtdeType::Vector6UInt32 => RtdeValue::Vector6UInt32(std::array::from_fn(u32_at)),
+        }
+    }
+
+    pub fn encode(&self, bytes: &mut Vec<u8>) {
+        match self {
+            RtdeValue::Bool(value) => bytes.push(*value as u8),
+            RtdeValue::UInt8(value) => bytes.push(*value),
+            RtdeValue::UInt32(value) => bytes.extend(value.to_be_bytes()),
+            RtdeValue::UInt64(value) => bytes.extend(value.to_be_bytes()),
+            RtdeValue::Int32(value) => bytes.extend(value.to_be_bytes()),
+            RtdeValue::Double(value) => bytes.extend(value.to_be_bytes()),
+            RtdeValue::Vector3D(values) => values.iter().for_each(|v| bytes.extend(v.to_be_bytes())),
+            RtdeValue::Vector6D(values) => values.iter().for_each(|v| bytes.extend(v.to_be_bytes())),
+            RtdeValue::Vector6Int32(values) => values.iter().for_each(|v| bytes.extend(v.to_be_bytes())),
+            RtdeValue::Vector6UInt32(values) => values.iter().for_each(|v| bytes.extend(v.to_be_bytes())),
+        }
+    }
+
+    // Only the types of the input registers can be written from Redis.
+    pub fn from_spvalue(rtde_type: RtdeType, value: &SPValue) -> Option<RtdeValue> {
+        match (rtde_type, value) {
+            (RtdeType::Double, SPValue::Float64(FloatOrUnknown::Float64(value))) => {
+                Some(RtdeValue::Double(value.into_inner()))
+            }
+            (RtdeType::Double, SPValue::Int64(IntOrUnknown::Int64(value))) => {
+                Some(RtdeValue::Double(*value as f64))
+            }
+            (RtdeType::Int32, SPValue::Int64(IntOrUnknown::Int64(value))) => {
+                i32::try_from(*value).ok().map(RtdeValue::Int32)
+            }
+            (RtdeType::Bool, SPValue::Bool(BoolOrUnknown::Bool(value))) => Some(RtdeValue::Bool(*value)),
+            _ => None,
+        }
+    }
+
+    pub fn rtde_type(&self) -> RtdeType {
+        match self {
+            RtdeValue::Bool(_) => RtdeType::Bool,
+            RtdeValue::UInt8(_) => RtdeType::UInt8,
+            RtdeValue::UInt32(_) => RtdeType::UInt32,
+            RtdeValue::UInt64(_) => RtdeType::UInt64,
+            RtdeValue::Int32(_) => RtdeType::Int32,
+            RtdeValue::Double(_) => RtdeType::Double,
+            RtdeValue::Vector3D(_) => RtdeType::Vector3D,
+            RtdeValue::Vector6D(_) => RtdeType::Vector6D,
+            RtdeValue::Vector6Int32(_) => RtdeType::Vector6Int32,
+            RtdeValue::Vector6UInt32(_) => RtdeType::Vector6UInt32,
+        }
+    }
+
+    pub fn to_spvalue(&self) -> SPValue {
+        match self {
+            RtdeValue::Bool(value) => value.to_spvalue(),
+            RtdeValue::UInt8(value) => (*value as i64).to_spvalue(),
+            RtdeValue::UInt32(value) => (*value as i64).to_spvalue(),
+            // Digital IO bits, only the lower 64 are ever used
+            RtdeValue::UInt64(value) => (*value as i64).to_spvalue(),
+            RtdeValue::Int32(value) => (*value as i64).to_spvalue(),
+            RtdeValue::Double(value) => value.to_spvalue(),
+            RtdeValue::Vector3D(values) => values.to_vec().to_spvalue(),
+            RtdeValue::Vector6D(values) => values.to_vec().to_spvalue(),
+            RtdeValue::Vector6Int32(values) => SPValue::Array(ArrayOrUnknown::Array(
+                values.iter().map(|v| (*v as i64).to_spvalue()).collect(),
+            )),
+            RtdeValue::Vector6UInt32(values) => SPValue::Array(ArrayOrUnknown::Array(
+                values.iter().map(|v| (*v as i64).to_spvalue()).collect(),
+            )),
+        }
+    }
+}
+
+/// A set of variables the controller agreed to exchange with us, in the agreed order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RtdeRecipe {
+    pub id: u8,
+    pub names: Vec<String>,
+    pub types: Vec<RtdeType>,
+}
+
+impl RtdeRecipe {
+    // The answer to a setup request is the recipe id followed by the comma separated types.
+    fn from_setup_response(names: &[&str], response: &[u8]) -> Result<RtdeRecipe, RtdeError> {
+        if response.is_empty() {
+            return Err(RtdeError::Protocol("empty recipe setup response".to_string()));
+        }
+        let type_names = String::from_utf8_lossy(&response[1..]).to_string();
+        let type_names = type_names.split(',').collect::<Vec<&str>>();
+        if type_names.len() != names.len() {
+            return Err(RtdeError::Protocol(format!(
+                "asked for {} variables, got {} types",
+                names.len(),
+                type_names.len()
+            )));
+        }
+        let types = names
+            .iter()
+            .zip(type_names)
+            .map(|(name, type_name)| RtdeType::from_name(name, type_name))
+            .collect::<Result<Vec<RtdeType>, RtdeError>>()?;
+        Ok(RtdeRecipe {
+            id: response[0],
+            names: names.iter().map(|name| name.to_string()).collect(),
+            types,
+        })
+    }
+
+    pub fn decode(&self, payload: &[u8]) -> Result<Vec<(String, RtdeValue)>, RtdeError> {
+        let expected = 1 + self.types.iter().map(|t| t.size()).sum::<usize>();
+        if payload.len() != expected {
+            return Err(RtdeError::Protocol(format!(
+                "data package of recipe {} is {} bytes, expected {}",
+                self.id,
+                payload.len(),
+                expected
+            )));
+        }
+        let mut offset = 1;
+        let mut values = vec![];
+        for (name, rtde_type) in self.names.iter().zip(&self.types) {
+            values.push((
+                name.clone(),
+                RtdeValue::decode(*rtde_type, &payload[offset..offset + rtde_type.size()]),
+            ));
+            offset += rtde_type.size();
+        }
+        Ok(values)
+    }
+}
+
+/// Client for the Real-Time Data Exchange interface, protocol version 2.
+pub struct RtdeClient {
+    stream: TcpStream,
+    timeout: Duration,
+    outputs: Option<RtdeRecipe>,
+}
+
+impl RtdeClient {
+    // The address is either 'host' or 'host:port'.
+    pub async fn connect(address: &str) -> Result<RtdeClient, RtdeError> {
+        let address = socket_address(address, UR_RTDE_PORT);
+        let timeout = Duration::from_millis(UR_RTDE_TIMEOUT);
+        let stream = match tokio::time::timeout(timeout, TcpStream::connect(&address)).await {
+            Ok(stream) => stream?,
+            Err(_) => return Err(RtdeError::Timeout),
+        };
+        stream.set_nodelay(true)?;
+        Ok(RtdeClient {
+            stream,
+            timeout,
+            outputs: None,
+        })
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> RtdeClient {
+        self.timeout = timeout;
+        self
+    }
+
+    async fn send_package(&mut self, package_type: u8, payload: &[u8]) -> Result<(), RtdeError> {
+        let mut package = ((payload.len() + 3) as u16).to_be_bytes().to_vec();
+        package.push(package_type);
+        package.extend(payload);
+        self.stream.write_all(&package).await?;
+        Ok(())
+    }
+
+    async fn receive_package(&mut self) -> Result<(u8, Vec<u8>), RtdeError> {
+        match tokio::time::timeout(self.timeout, read_package(&mut self.stream)).await {
+            Ok(package) => package,
+            Err(_) => Err(RtdeError::Timeout),
+        }
+    }
+
+    // Sends a control package and waits for the answer of the same type.
+    async fn request(&mut self, package_type: u8, payload: &[u8]) -> Result<Vec<u8>, RtdeError> {
+        self.send_package(package_type, payload).await?;
+        loop {
+            let (response_type, response) = self.receive_package().await?;
+            match response_type {
+                t if t == package_type => return Ok(response),
+                RTDE_TEXT_MESSAGE => log_text_message(&response),
+                // Data packages that were already on the way, e.g. before a pause
+                _ => (),
+            }
+        }
+    }
+
+    pub async fn negotiate_protocol_version(&mut self) -> Result<(), RtdeError> {
+        let response = self
+            .request(RTDE_REQUEST_PROTOCOL_VERSION, &UR_RTDE_PROTOCOL_VERSION.to_be_bytes())
+            .await?;
+        match response.first() {
+            Some(1) => Ok(()),
+            _ => Err(RtdeError::Protocol(format!(
+                "controller does not speak protocol version {}",
+                UR_RTDE_PROTOCOL_VERSION
+            ))),
+        }
+    }
+
+    // major, minor, bugfix and build
+    pub async fn controller_version(&mut self) -> Result<[u32; 4], RtdeError> {
+        let response = self.request(RTDE_GET_URCONTROL_VERSION, &[]).await?;
+        if response.len() != 16 {
+            return Err(RtdeError::Protocol("malformed controller version".to_string()));
+        }
+        Ok(std::array::from_fn(|i| {
+            u32::from_be_bytes(response[i * 4..i * 4 + 4].try_into().unwrap())
+        }))
+    }
+
+    /// Asks the controller to send the variables at frequency [Hz], at most 500 Hz on e-Series and 125 Hz on CB3.
+    pub async fn setup_outputs(&mut self, frequency: f64, names: &[&str]) -> Result<RtdeRecipe, RtdeError> {
+        let mut payload = frequency.to_be_bytes().to_vec();
+        payload.extend(names.join(",").as_bytes());
+        let response = self.request(RTDE_CONTROL_PACKAGE_SETUP_OUTPUTS, &payload).await?;
+        let recipe = RtdeRecipe::from_setup_response(names, &response)?;
+        self.outputs = Some(recipe.clone());
+        Ok(recipe)
+    }
+
+    /// Sets up the registers we write, this has to happen before start.
+    pub async fn setup_inputs(&mut self, names: &[&str]) -> Result<RtdeRecipe, RtdeError> {
+        let response = self
+            .request(RTDE_CONTROL_PACKAGE_SETUP_INPUTS, names.join(",").as_bytes())
+            .await?;
+        RtdeRecipe::from_setup_response(names, &response)
+    }
+
+    // The controller doesn't answer input data packages.
+    pub async fn send_inputs(&mut self, recipe: &RtdeRecipe, values: &[RtdeValue]) -> Result<(), RtdeError> {
+        if values.len() != recipe.types.len()
+            || values.iter().zip(&recipe.types).any(|(value, t)| value.rtde_type() != *t)
+        {
+            return Err(RtdeError::Protocol(format!(
+                "{:?} doesn't match the types {:?} of input recipe {}",
+                values, recipe.types, recipe.id
+            )));
+        }
+        let mut payload = vec![recipe.id];
+        values.iter().for_each(|value| value.encode(&mut payload));
+        self.send_package(RTDE_DATA_PACKAGE, &payload).await
+    }
+
+    pub async fn start(&mut self) -> Result<(), RtdeError> {
+        match self.request(RTDE_CONTROL_PACKAGE_START, &[]).await?.first() {
+            Some(1) => Ok(()),
+            _ => Err(RtdeError::Protocol("controller refused to start".to_string())),
+        }
+    }
+
+    pub async fn pause(&mut self) -> Result<(), RtdeError> {
+        match self.request(RTDE_CONTROL_PACKAGE_PAUSE, &[]).await?.first() {
+            Some(1) => Ok(()),
+            _ => Err(RtdeError::Protocol("controller refused to pause".to_string())),
+        }
+    }
+
+    /// Waits for the next data package of the output recipe.
+    pub async fn receive_outputs(&mut self) -> Result<Vec<(String, RtdeValue)>, RtdeError> {
+        loop {
+            let (package_type, payload) = self.receive_package().await?;
+            match package_type {
+                RTDE_DATA_PACKAGE => match &self.outputs {
+                    Some(recipe) if payload.first() == Some(&recipe.id) => {
+                        return recipe.decode(&payload)
+                    }
+                    _ => (),
+                },
+                RTDE_TEXT_MESSAGE => log_text_message(&payload),
+                _ => (),
+            }
+        }
+    }
+}
+
+async fn read_package(stream: &mut TcpStream) -> Result<(u8, Vec<u8>), RtdeError> {
+    let mut header = [0u8; 3];
+    stream.read_exact(&mut header).await?;
+    let size = u16::from_be_bytes([header[0], header[1]]) as usize;
+    if size < 3 {
+        return Err(RtdeError::Protocol(format!("package size {} is too small", size)));
+    }
+    let mut payload = vec![0u8; size - 3];
+    stream.read_exact(&mut payload).await?;
+    Ok((header[2], payload))
+}
+
+// message length, message, source length, source, warning level
+fn log_text_message(payload: &[u8]) {
+    let message_length = *payload.first().unwrap_or(&0) as usize;
+    let message = payload.get(1..1 + message_length).unwrap_or(&[]);
+    r2r::log_warn!("rtde_client", "{}", String::from_utf8_lossy(message));
+}
+
+/// A Redis key whose value is written to an input register, so that a running
+/// script can pick it up with read_input_float_register(24) and the like.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RtdeInputRegister {
+    pub key: String,
+    // input_double_register_X, input_int_register_X or input_bit_register_X
+    pub register: String,
+}
+
+impl RtdeInputRegister {
+    // "r1_force_threshold=input_double_register_24,r1_mode=input_int_register_24"
+    pub fn parse_mapping(mapping: &str) -> Result<Vec<RtdeInputRegister>, String> {
+        mapping
+            .split(',')
+            .map(|entry| entry.trim())
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (key, register) = entry
+                    .split_once('=')
+                    .ok_or(format!("'{}' is not a key=register pair", entry))?;
+                let index = ["input_double_register_", "input_int_register_", "input_bit_register_"]
+                    .iter()
+                    .find_map(|prefix| register.trim().strip_prefix(prefix));
+                match index.map(|index| index.parse::<u8>()) {
+                    Some(Ok(_)) if !key.trim().is_empty() => Ok(RtdeInputRegister {
+                        key: key.trim().to_string(),
+                        register: register.trim().to_string(),
+                    }),
+                    _ => Err(format!("'{}' is not an input register mapping", entry)),
+                }
+            })
+            .collect()
+    }
+}
+
+/// How often the controller sends data, how many packages we skip between two Redis
+/// round trips, which Redis keys are written to the input registers and how often
+/// they are read [Hz].
+#[derive(Debug, Clone, PartialEq)]
+pub struct RtdeConfig {
+    pub frequency: f64,
+    pub decimation: usize,
+    pub input_registers: Vec<RtdeInputRegister>,
+    pub input_rate: f64,
+}
+
+impl Default for RtdeConfig {
+    fn default() -> Self {
+        RtdeConfig {
+            frequency: 125.0,
+            decimation: 5,
+            input_registers: vec![],
+            input_rate: 25.0,
+        }
+    }
+}
+
+pub async fn rtde_to_redis(
+    robot_name: &str,
+    ur_address: &str,
+    connection_manager: &Arc<ConnectionManager>,
+    rtde_config: &RtdeConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let log_target = &format!("{robot_name}_rtde_client");
+    loop {
+        if let Err(e) = stream_rtde_outputs(robot_name, ur_address, connection_manager, rtde_config).await {
+            r2r::log_warn!(log_target, "RTDE stream stopped, {}, reconnecting.", e);
+        }
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+    }
+}
+
+async fn stream_rtde_outputs(
+    robot_name: &str,
+    ur_address: &str,
+    connection_manager: &Arc<ConnectionManager>,
+    rtde_config: &RtdeConfig,
+) -> Result<(), RtdeError> {
+    let log_target = &format!("{robot_name}_rtde_client");
+    let mut client = RtdeClient::connect(ur_address).await?;
+    client.negotiate_protocol_version().await?;
+    let version = client.controller_version().await?;
+    client.setup_outputs(rtde_config.frequency, &RTDE_OUTPUTS).await?;
+    client.start().await?;
+    r2r::log_info!(
+        log_target,
+        "Streaming RTDE data from controller version {}.{}.{}.{}.",
+        version[0],
+        version[1],
+        version[2],
+        version[3]
+    );
+
+    let mut con = connection_manager.get_connection().await;
+    let mut received: usize = 0;
+    loop {
+        let outputs = client.receive_outputs().await?;
+        received += 1;
+        if received % rtde_config.decimation.max(1) != 0 {
+            continue;
+        }
+        if let Err(_) = connection_manager.check_redis_health(&log_target).await {
+            continue;
+        }
+        let values = outputs
+            .into_iter()
+            .map(|(name, value)| {
+                (
+                    format!("{robot_name}_rtde_{}", name.to_lowercase()),
+                    value.to_spvalue(),
+                )
+            })
+            .collect::<Vec<(String, SPValue)>>();
+        StateManager::set_state(&mut con, &state_from_values(values)).await;
+    }
+}
+
+/// Writes the mapped Redis keys to the input registers at the input rate, on a
+/// connection of its own so that it doesn't wait for the output packages.
+pub async fn redis_to_rtde_inputs(
+    robot_name: &str,
+    ur_address: &str,
+    connection_manager: &Arc<ConnectionManager>,
+    rtde_config: &RtdeConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let log_target = &format!("{robot_name}_rtde_client");
+    if rtde_config.input_registers.is_empty() {
+        return Ok(());
+    }
+    loop {
+        if let Err(e) = stream_rtde_inputs(robot_name, ur_address, connection_manager, rtde_config).await {
+            r2r::log_warn!(log_target, "RTDE inputs stopped, {}, reconnecting.", e);
+        }
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+    }
+}
+
+async fn stream_rtde_inputs(
+    robot_name: &str,
+    ur_address: &str,
+    connection_manager: &Arc<ConnectionManager>,
+    rtde_config: &RtdeConfig,
+) -> Result<(), RtdeError> {
+    let log_target = &format!("{robot_name}_rtde_client");
+    let mut client = RtdeClient::connect(ur_address).await?;
+    client.negotiate_protocol_version().await?;
+    // A recipe per register, so that a key without a usable value doesn't hold back the others
+    let mut recipes = vec![];
+    for input in &rtde_config.input_registers {
+        recipes.push(client.setup_inputs(&[input.register.as_str()]).await?);
+    }
+    client.start().await?;
+
+    let keys = rtde_config
+        .input_registers
+        .iter()
+        .map(|input| input.key.clone())
+        .collect::<Vec<String>>();
+    // The registers keep their value, so only changes are sent
+    let mut sent: Vec<Option<RtdeValue>> = vec![None; keys.len()];
+    let mut warned = vec![false; keys.len()];
+    let mut con = connection_manager.get_connection().await;
+    let mut interval = tokio::time::interval(mirror_period(rtde_config.input_rate));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        if let Err(_) = connection_manager.check_redis_health(&log_target).await {
+            continue;
+        }
+        let state = match StateManager::get_state_for_keys(&mut con, &keys).await {
+            Some(state) => state,
+            None => continue,
+        };
+        for (i, (input, recipe)) in rtde_config.input_registers.iter().zip(&recipes).enumerate() {
+            let value = state
+                .get_value(&input.key, &log_target)
+                .and_then(|value| RtdeValue::from_spvalue(recipe.types[0], &value));
+            match value {
+                Some(value) => {
+                    warned[i] = false;
+                    if sent[i].as_ref() != Some(&value) {
+                        client.send_inputs(recipe, &[value.clone()]).await?;
+                        sent[i] = Some(value);
+                    }
+                }
+                None if !warned[i] => {
+                    r2r::log_warn!(
+                        log_target,
+                        "{} has no value for {}, not writing it.",
+                        input.key,
+                        input.register
+                    );
+                    warned[i] = true;
+                }
+                None => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn fake_rtde_value(name: &str) -> Option<RtdeValue> {
+    match name {
+        "timestamp" => Some(RtdeValue::Double(12.5)),
+        "actual_q" => Some(RtdeValue::Vector6D([0.1, 0.2, 0.3, 0.4, 0.5, 0.6])),
+        "actual_qd" | "actual_TCP_pose" | "actual_TCP_force" => Some(RtdeValue::Vector6D([0.0; 6])),
+        "actual_digital_input_bits" | "actual_digital_output_bits" => Some(RtdeValue::UInt64(5)),
+        "robot_mode" => Some(RtdeValue::Int32(7)),
+        "safety_mode" => Some(RtdeValue::Int32(1)),
+        "speed_scaling" => Some(RtdeValue::Double(0.5)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+fn fake_rtde_type_name(value: &RtdeValue) -> &'static str {
+    match value {
+        RtdeValue::Int32(_) => "INT32",
+        RtdeValue::UInt64(_) => "UINT64",
+        RtdeValue::Double(_) => "DOUBLE",
+        _ => "VECTOR6D",
+    }
+}
+
+/// Answers like a controller with version 5.15 and streams three data packages after start.
+/// The input data packages it receives come out of the channel.
+#[cfg(test)]
+pub(crate) async fn fake_rtde_server() -> (
+    std::net::SocketAddr,
+    tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>,
+) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (inputs_sender, inputs_receiver) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut outputs: Vec<RtdeValue> = vec![];
+        while let Ok((package_type, payload)) = read_package(&mut stream).await {
+            let mut packages = vec![];
+            match package_type {
+                RTDE_REQUEST_PROTOCOL_VERSION => {
+                    let accepted = payload == UR_RTDE_PROTOCOL_VERSION.to_be_bytes();
+                    packages.push((package_type, vec![accepted as u8]));
+                }
+                RTDE_GET_URCONTROL_VERSION => {
+                    let version = [5u32, 15, 0, 0].iter().flat_map(|v| v.to_be_bytes()).collect();
+                    packages.push((package_type, version));
+                }
+                RTDE_CONTROL_PACKAGE_SETUP_OUTPUTS => {
+                    let names = String::from_utf8_lossy(&payload[8..]).to_string();
+                    let values = names.split(',').map(fake_rtde_value).collect::<Vec<_>>();
+                    let types = values
+                        .iter()
+                        .map(|value| value.as_ref().map(fake_rtde_type_name).unwrap_or("NOT_FOUND"))
+                        .collect::<Vec<&str>>()
+                        .join(",");
+                    outputs = values.into_iter().flatten().collect();
+                    let mut response = vec![1];
+                    response.extend(types.as_bytes());
+                    packages.push((package_type, response));
+                }
+                RTDE_CONTROL_PACKAGE_SETUP_INPUTS => {
+                    let names = String::from_utf8_lossy(&payload).to_string();
+                    let types = names
+                        .split(',')
+                        .map(|name| match name {
+                            name if name.starts_with("input_double_register_") => "DOUBLE",
+                            name if name.starts_with("input_int_register_") => "INT32",
+                            name if name.starts_with("input_bit_register_") => "BOOL",
+                            _ => "NOT_FOUND",
+                        })
+                        .collect::<Vec<&str>>()
+                        .join(",");
+                    let mut response = vec![2];
+                    response.extend(types.as_bytes());
+                    packages.push((package_type, response));
+                }
+                RTDE_DATA_PACKAGE => {
+                    let _ = inputs_sender.send(payload);
+                }
+                RTDE_CONTROL_PACKAGE_START => {
+                    packages.push((package_type, vec![1]));
+                    let mut message = vec![5];
+                    message.extend(b"hello");
+                    message.extend([4]);
+                    message.extend(b"test");
+                    message.push(0);
+                    packages.push((RTDE_TEXT_MESSAGE, message));
+                    for _ in 0..3 {
+                        let mut data = vec![1];
+                        outputs.iter().for_each(|value| value.encode(&mut data));
+                        packages.push((RTDE_DATA_PACKAGE, data));
+                    }
+                }
+                _ => packages.push((package_type, vec![1])),
+            }
+            for (package_type, payload) in packages {
+                let mut package = ((payload.len() + 3) as u16).to_be_bytes().to_vec();
+                package.push(package_type);
+                package.extend(payload);
+                stream.write_all(&package).await.unwrap();
+            }
+        }
+    });
+    (address, inputs_receiver)
+}
+
+#[tokio::test]
+async fn test_rtde_client() {
+    let (address, _) = fake_rtde_server().await;
+    let mut client = RtdeClient::connect(&address.to_string()).await.unwrap();
+    client.negotiate_protocol_version().await.unwrap();
+    assert_eq!(client.controller_version().await.unwrap(), [5, 15, 0, 0]);
+    let recipe = client.setup_outputs(125.0, &RTDE_OUTPUTS).await.unwrap();
+    assert_eq!(recipe.types.len(), RTDE_OUTPUTS.len());
+    client.start().await.unwrap();
+
+    for _ in 0..3 {
+        let outputs = client.receive_outputs().await.unwrap();
+        assert_eq!(outputs.len(), RTDE_OUTPUTS.len());
+        assert_eq!(outputs[0], ("timestamp".to_string(), RtdeValue::Double(12.5)));
+        assert_eq!(
+            outputs[1],
+            ("actual_q".to_string(), RtdeValue::Vector6D([0.1, 0.2, 0.3, 0.4, 0.5, 0.6]))
+        );
+        assert_eq!(outputs[7], ("robot_mode".to_string(), RtdeValue::Int32(7)));
+        assert_eq!(outputs[9], ("speed_scaling".to_string(), RtdeValue::Double(0.5)));
+    }
+    let mut client = client.with_timeout(Duration::from_millis(100));
+    assert!(matches!(client.receive_outputs().await, Err(RtdeError::Timeout)));
+}
+
+#[tokio::test]
+async fn test_rtde_unknown_output() {
+    let (address, _) = fake_rtde_server().await;
+    let mut client = RtdeClient::connect(&address.to_string()).await.unwrap();
+    client.negotiate_protocol_version().await.unwrap();
+    assert!(matches!(
+        client.setup_outputs(125.0, &["actual_q", "not_a_variable"]).await,
+        Err(RtdeError::Protocol(_))
+    ));
+}
+
+#[test]
+fn test_rtde_value_round_trip() {
+    for (rtde_type, value) in [
+        (RtdeType::Bool, RtdeValue::Bool(true)),
+        (RtdeType::UInt8, RtdeValue::UInt8(3)),
+        (RtdeType::UInt32, RtdeValue::UInt32(70000)),
+        (RtdeType::UInt64, RtdeValue::UInt64(1 << 40)),
+        (RtdeType::Int32, RtdeValue::Int32(-2)),
+        (RtdeType::Double, RtdeValue::Double(-0.25)),
+        (RtdeType::Vector3D, RtdeValue::Vector3D([1.0, -2.0, 3.0])),
+        (RtdeType::Vector6Int32, RtdeValue::Vector6Int32([1, -2, 3, -4, 5, -6])),
+        (RtdeType::Vector6UInt32, RtdeValue::Vector6UInt32([1, 2, 3, 4, 5, 6])),
+    ] {
+        let mut bytes = vec![];
+        value.encode(&mut bytes);
+        assert_eq!(bytes.len(), rtde_type.size());
+        assert_eq!(RtdeValue::decode(rtde_type, &bytes), value);
+    }
+}
+
+#[tokio::test]
+async fn test_rtde_inputs() {
+    let (address, mut inputs) = fake_rtde_server().await;
+    let mut client = RtdeClient::connect(&address.to_string()).await.unwrap();
+    client.negotiate_protocol_version().await.unwrap();
+    let recipe = client
+        .setup_inputs(&["input_double_register_24", "input_int_register_24", "input_bit_register_64"])
+        .await
+        .unwrap();
+    assert_eq!(recipe.types, vec![RtdeType::Double, RtdeType::Int32, RtdeType::Bool]);
+    client.start().await.unwrap();
+
+    let values = vec![RtdeValue::Double(0.5), RtdeValue::Int32(3), RtdeValue::Bool(true)];
+    client.send_inputs(&recipe, &values).await.unwrap();
+    let payload = inputs.recv().await.unwrap();
+    let received = recipe.decode(&payload).unwrap();
+    assert_eq!(received.into_iter().map(|(_, v)| v).collect::<Vec<_>>(), values);
+
+    let wrong = vec![RtdeValue::Int32(1), RtdeValue::Int32(3), RtdeValue::Bool(true)];
+    assert!(client.send_inputs(&recipe, &wrong).await.is_err());
+    assert!(client.setup_inputs(&["actual_q"]).await.is_err());
+}
+
+#[test]
+fn test_rtde_input_register_mapping() {
+    let mapping = RtdeInputRegister::parse_mapping(
+        "r1_force_threshold=input_double_register_24, r1_mode=input_int_register_25,",
+    )
+    .unwrap();
+    assert_eq!(
+        mapping,
+        vec![
+            RtdeInputRegister {
+                key: "r1_force_threshold".to_string(),
+                register: "input_double_register_24".to_string(),
+            },
+            RtdeInputRegister {
+                key: "r1_mode".to_string(),
+                register: "input_int_register_25".to_string(),
+            },
+        ]
+    );
+    assert!(RtdeInputRegister::parse_mapping("").unwrap().is_empty());
+    assert!(RtdeInputRegister::parse_mapping("r1_mode").is_err());
+    assert!(RtdeInputRegister::parse_mapping("r1_mode=output_int_register_25").is_err());
+    assert!(RtdeInputRegister::parse_mapping("r1_mode=input_int_register_x").is_err());
+    assert!(RtdeInputRegister::parse_mapping("=input_int_register_1").is_err());
+}
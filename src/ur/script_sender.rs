@@ -0,0 +1,284 @@
+use futures::channel::mpsc::{unbounded, UnboundedSender};
+use futures::StreamExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::*;
+
+pub const UR_SECONDARY_PORT: u16 = 30002;
+pub const UR_SECONDARY_TIMEOUT: u64 = 2000;
+// How long the robot gets to compile the script and connect back to us
+pub const UR_SCRIPT_START_TIMEOUT: u64 = 10000;
+// How long a script may run once it started [ms], 0 waits forever
+pub const DEFAULT_SCRIPT_TIMEOUT: u64 = 300000;
+pub static UR_SCRIPT_RESULT_PREFIX: &str = "UR_CONTROLLER_RESULT: ";
+
+static PROGRAM_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Where the robot connects back to while a script runs. Port 0 takes any free port,
+/// the robot is told which one in the script itself.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirectScriptConfig {
+    pub host_address: String,
+    pub port: u16,
+}
+
+/// How the rendered scripts reach the robot.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum ScriptBackend {
+    // The ur_script action of the external ur_script_driver
+    #[default]
+    Ros,
+    // Straight to the secondary interface of the robot
+    Direct(DirectScriptConfig),
+}
+
+// The address of the interface we use to reach the robot, for when there is no override.
+pub fn local_address_towards(ur_address: &str) -> Option<String> {
+    let (host, port) = host_and_port(ur_address, UR_SECONDARY_PORT);
+    let bind = match host.parse::<std::net::IpAddr>() {
+        Ok(std::net::IpAddr::V6(_)) => "[::]:0",
+        _ => "0.0.0.0:0",
+    };
+    let socket = std::net::UdpSocket::bind(bind).ok()?;
+    socket.connect((host.as_str(), port)).ok()?;
+    Some(socket.local_addr().ok()?.ip().to_string())
+}
+
+/// Wraps a rendered template into a program of its own. The templates define script() and
+/// talk to us through "ur_driver_socket", the wrapper opens that socket, calls script()
+/// and reports what it returned.
+pub fn wrap_script(script: &str, host_address: &str, port: u16) -> String {
+    let name = format!(
+        "ur_controller_{}",
+        PROGRAM_COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let body = script
+        .lines()
+        .map(|line| match line.trim().is_empty() {
+            true => String::new(),
+            false => format!("  {}", line),
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    format!(
+        "def {name}():\n\
+         {body}\n\
+         \x20 socket_open(\"{host_address}\", {port}, \"ur_driver_socket\")\n\
+         \x20 if script():\n\
+         \x20   socket_send_line(\"{prefix}true\", \"ur_driver_socket\")\n\
+         \x20 else:\n\
+         \x20   socket_send_line(\"{prefix}false\", \"ur_driver_socket\")\n\
+         \x20 end\n\
+         \x20 socket_close(\"ur_driver_socket\")\n\
+         end\n",
+        prefix = UR_SCRIPT_RESULT_PREFIX
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct ScriptSender {
+    ur_address: String,
+    config: DirectScriptConfig,
+    run_timeout: u64,
+}
+
+impl ScriptSender {
+    // The address is either 'host' or 'host:port'.
+    pub fn new(ur_address: &str, config: &DirectScriptConfig, run_timeout: u64) -> ScriptSender {
+        let ur_address = socket_address(ur_address, UR_SECONDARY_PORT);
+        ScriptSender {
+            ur_address,
+            config: config.clone(),
+            run_timeout,
+        }
+    }
+
+    /// Runs the script and waits until it returns. Every line the script sends on the way
+    /// is passed on as feedback. A script that stops without returning, because of a
+    /// protective stop or a runtime exception, is an error. So is one that runs longer
+    /// than the run timeout, it is stopped then.
+    pub async fn execute(
+        &self,
+        script: &str,
+        feedback: UnboundedSender<String>,
+    ) -> Result<bool, String> {
+        let listener = TcpListener::bind(("0.0.0.0", self.config.port))
+            .await
+            .map_err(|e| format!("failed to listen for the robot with {}", e))?;
+        let port = listener
+            .local_addr()
+            .map_err(|e| format!("failed to listen for the robot with {}", e))?
+            .port();
+
+        self.send_program(&wrap_script(script, &self.config.host_address, port))
+            .await?;
+
+        let stream = match tokio::time::timeout(
+            Duration::from_millis(UR_SCRIPT_START_TIMEOUT),
+            listener.accept(),
+        )
+        .await
+        {
+            Ok(Ok((stream, _))) => stream,
+            Ok(Err(e)) => return Err(format!("robot failed to connect back with {}", e)),
+            Err(_) => return Err("robot did not start the script, is it valid URScript?".to_string()),
+        };
+
+        let run = async {
+            let mut lines = BufReader::new(stream).lines();
+            while let Some(line) = lines
+                .next_line()
+                .await
+                .map_err(|e| format!("lost the robot with {}", e))?
+            {
+                match line.strip_prefix(UR_SCRIPT_RESULT_PREFIX) {
+                    Some(result) => return Ok(result.trim() == "true"),
+                    None => {
+                        let _ = feedback.unbounded_send(line);
+                    }
+                }
+            }
+            Err("script stopped before it returned".to_string())
+        };
+        if self.run_timeout == 0 {
+            return run.await;
+        }
+        match tokio::time::timeout(Duration::from_millis(self.run_timeout), run).await {
+            Ok(result) => result,
+            Err(_) => {
+                let _ = self.stop().await;
+                Err(format!("script did not return within {} ms", self.run_timeout))
+            }
+        }
+    }
+
+    pub async fn send_program(&self, program: &str) -> Result<(), String> {
+        let mut stream = match tokio::time::timeout(
+            Duration::from_millis(UR_SECONDARY_TIMEOUT),
+            TcpStream::connect(&self.ur_address),
+        )
+        .await
+        {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => return Err(format!("failed to connect to {} with {}", self.ur_address, e)),
+            Err(_) => return Err(format!("failed to connect to {} in time", self.ur_address)),
+        };
+        stream
+            .write_all(program.as_bytes())
+            .await
+            .map_err(|e| format!("failed to send the script with {}", e))?;
+        let _ = stream.shutdown().await;
+        Ok(())
+    }
+
+    // A new program replaces the running one, this one only stops the arm.
+    pub async fn stop(&self) -> Result<(), String> {
+        self.send_program("def ur_controller_stop():\n  stopj(2.0)\nend\n")
+            .await
+    }
+}
+
+// The program goes out as soon as the result is awaited, the robot doesn't acknowledge it before.
+impl RobotBackend for ScriptSender {
+    fn send_script(&self, script: String) -> BackendFuture<'_, Result<ScriptExecution, String>> {
+        let sender = self.clone();
+        Box::pin(async move {
+            let (feedback_sender, feedback) = unbounded();
+            let result: BackendFuture<'static, Result<String, String>> =
+                Box::pin(async move {
+                    match sender.execute(&script, feedback_sender).await {
+                        Ok(returned) => script_returned(returned),
+                        Err(e) => Err(format!("Script failed, {}.", e)),
+                    }
+                });
+            Ok(ScriptExecution {
+                feedback: feedback.boxed(),
+                result,
+            })
+        })
+    }
+
+    fn cancel(&self) -> BackendFuture<'_, Result<(), String>> {
+        Box::pin(self.stop())
+    }
+}
+
+// Plays the robot: takes the program from the secondary interface, connects back to
+// where it says and sends the given lines. With keep_running the script only ends
+// when the next program comes in, like the stop program.
+#[cfg(test)]
+async fn fake_secondary_interface(lines: Vec<&'static str>, keep_running: bool) -> std::net::SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut program = String::new();
+        let mut reader = BufReader::new(stream);
+        while reader.read_line(&mut program).await.unwrap() > 0 {}
+        let socket_open = program
+            .lines()
+            .find(|line| line.contains("socket_open("))
+            .unwrap()
+            .to_string();
+        let port = socket_open.split(',').nth(1).unwrap().trim();
+        let mut robot = TcpStream::connect(format!("127.0.0.1:{}", port)).await.unwrap();
+        for line in lines {
+            robot.write_all(format!("{}\n", line).as_bytes()).await.unwrap();
+        }
+        if keep_running {
+            let _ = listener.accept().await;
+        }
+    });
+    address
+}
+
+#[test]
+fn test_wrap_script() {
+    let script = "global move_done = False\ndef script():\n  return True\nend\n";
+    let program = wrap_script(script, "192.168.1.15", 50002);
+    assert!(program.starts_with("def ur_controller_"));
+    assert!(program.contains("\n  global move_done = False\n  def script():\n    return True\n  end\n"));
+    assert!(program.contains("  socket_open(\"192.168.1.15\", 50002, \"ur_driver_socket\")\n"));
+    assert!(program.contains(&format!("socket_send_line(\"{}true\"", UR_SCRIPT_RESULT_PREFIX)));
+    assert!(program.ends_with("\nend\n"));
+}
+
+#[tokio::test]
+async fn test_script_sender() {
+    let config = DirectScriptConfig {
+        host_address: "127.0.0.1".to_string(),
+        port: 0,
+    };
+    let address = fake_secondary_interface(vec![
+        "FORCE: 1.5",
+        "UR_CONTROLLER_RESULT: true",
+    ], false)
+    .await;
+    let sender = ScriptSender::new(&address.to_string(), &config, DEFAULT_SCRIPT_TIMEOUT);
+    let (feedback_sender, mut feedback) = futures::channel::mpsc::unbounded();
+    assert_eq!(sender.execute("def script():\n  return True\nend", feedback_sender).await, Ok(true));
+    assert_eq!(feedback.try_recv().ok(), Some("FORCE: 1.5".to_string()));
+
+    let address = fake_secondary_interface(vec!["UR_CONTROLLER_RESULT: false"], false).await;
+    let sender = ScriptSender::new(&address.to_string(), &config, DEFAULT_SCRIPT_TIMEOUT);
+    let (feedback_sender, _) = futures::channel::mpsc::unbounded();
+    assert_eq!(sender.execute("", feedback_sender).await, Ok(false));
+
+    // Stopped by the robot before script() returned
+    let address = fake_secondary_interface(vec!["Solution found, start movej."], false).await;
+    let sender = ScriptSender::new(&address.to_string(), &config, DEFAULT_SCRIPT_TIMEOUT);
+    let (feedback_sender, _) = futures::channel::mpsc::unbounded();
+    assert!(sender.execute("", feedback_sender).await.is_err());
+
+    // Never returns, so it is stopped after the run timeout
+    let address = fake_secondary_interface(vec!["FORCE: 1.5"], true).await;
+    let sender = ScriptSender::new(&address.to_string(), &config, 100);
+    let (feedback_sender, _) = futures::channel::mpsc::unbounded();
+    assert_eq!(
+        sender.execute("", feedback_sender).await,
+        Err("script did not return within 100 ms".to_string())
+    );
+}